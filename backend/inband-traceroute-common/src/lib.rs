@@ -8,11 +8,18 @@ pub struct TraceEvent {
     pub arrival: u64,
     pub trace_id: u32,
     pub ack_seq: u32,
+    /// Sequence number of the client's segment, or of our probe as quoted in an ICMP error
     pub seq: u32,
     pub event_type: TraceEventType,
     pub ip_version: IPVersion,
     pub ttl: u8,
     pub addr: IPAddr,
+    /// Next-hop MTU reported by ICMP Fragmentation Needed / ICMPv6 Packet Too Big
+    pub mtu: u32,
+    /// TOS / traffic class of the probe as quoted in an ICMP error
+    pub quoted_tos: u8,
+    /// IP protocol of the probe the event answers
+    pub probe_protocol: u8,
 }
 
 #[repr(u8)]
@@ -21,6 +28,14 @@ pub enum TraceEventType {
     TcpAck,
     TcpRst,
     IcmpTimeExceeded,
+    IcmpPacketTooBig,
+    /// Time Exceeded for a classic traceroute probe
+    ClassicTimeExceeded,
+    /// Destination Unreachable for a classic traceroute probe (port unreachable when it comes
+    /// from the client itself)
+    ClassicUnreachable,
+    /// Echo Reply from the client to a classic ICMP probe
+    ClassicEchoReply,
 }
 
 #[repr(u8)]
@@ -68,6 +83,109 @@ pub struct SocketAddr {
     pub addr: IPAddr,
 }
 
+/// Probes are sent with this `SO_MARK` so the egress classifier can tell them apart from the
+/// kernel's own packets on the traced connection
+pub const PROBE_MARK: u32 = 0x1b7a_ce00;
+
+/// Classic UDP probes are sent to this port plus the TTL. Classic probes carry their trace's
+/// identifier as the ICMP echo identifier or UDP source port, and are registered in
+/// CLASSIC_TRACES under the client's address with the identifier as port.
+pub const CLASSIC_UDP_BASE_PORT: u16 = 33434;
+
+/// IP header fields the kernel uses on a traced connection, as seen on egress
+#[repr(C, packed)]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct ConnectionHeaders {
+    /// Non-zero once a packet of the connection has been seen on egress
+    pub observed: u8,
+    /// IPv4 TOS or IPv6 traffic class (DSCP and ECN)
+    pub tos: u8,
+    /// IPv6 flow label, zero for IPv4
+    pub flow_label: u32,
+}
+
+/// Value type of the TRACES map
+#[repr(C, packed)]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct TraceEntry {
+    pub trace_id: u32,
+    pub headers: ConnectionHeaders,
+}
+
+impl TraceEntry {
+    pub fn new(trace_id: u32) -> Self {
+        Self {
+            trace_id,
+            headers: ConnectionHeaders::default(),
+        }
+    }
+}
+
+/// A TCP connection to or from one of our addresses, and key type of the TRACES map
+#[repr(C, packed)]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct ConnectionKey {
+    pub remote: SocketAddr,
+    /// Our port of the connection: the listening port for clients, an ephemeral one for outbound
+    /// connections
+    pub local_port: u16,
+}
+
+/// Value type of the SEQUENCES map: where a traced connection's sequence numbers are, as seen on
+/// its packets, so probes can fit in without touching the socket. Userspace adds the entry when
+/// the trace starts.
+#[repr(C, packed)]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct ConnectionSequence {
+    /// Non-zero once an ACK from the peer has been seen on ingress
+    pub acked: u8,
+    /// Highest acknowledgment number from the peer, i.e. the oldest byte we sent that isn't
+    /// acknowledged yet
+    pub snd_una: u32,
+    /// Non-zero once an ACK of ours has been seen on egress
+    pub ack_sent: u8,
+    /// Acknowledgment number of our last segment, i.e. the next byte expected from the peer
+    pub rcv_nxt: u32,
+}
+
+/// Value type of the HANDSHAKES map: the initial sequence numbers from a connection's SYNs
+#[repr(C, packed)]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct InitialSequence {
+    /// Non-zero once our SYN has been seen on egress
+    pub local_seen: u8,
+    /// Non-zero if our SYN had no ACK, i.e. we opened the connection
+    pub opened: u8,
+    pub local: u32,
+    /// Non-zero once the peer's SYN has been seen on ingress
+    pub remote_seen: u8,
+    pub remote: u32,
+}
+
+/// Number of TCP options recorded from a SYN, later ones are ignored
+pub const SYN_MAX_OPTIONS: usize = 16;
+
+/// Window scale of a SYN without the option
+pub const SYN_NO_WSCALE: u8 = 0xff;
+
+/// Value type of the SYNS map: the client's SYN as it arrived, keyed by its connection
+#[repr(C, packed)]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct SynFingerprint {
+    /// IPv4 TTL or IPv6 hop limit
+    pub ttl: u8,
+    /// Non-zero if the IPv4 don't fragment bit was set
+    pub df: u8,
+    pub window: u16,
+    /// Zero if the SYN had no MSS option
+    pub mss: u16,
+    /// `SYN_NO_WSCALE` if the SYN had no window scale option
+    pub wscale: u8,
+    pub option_count: u8,
+    /// Option kinds in the order they appeared
+    pub options: [u8; SYN_MAX_OPTIONS],
+}
+
 #[repr(C, packed)]
 #[derive(Debug, Copy, Clone, Default)]
 pub struct EbpfConfig {
@@ -110,6 +228,16 @@ mod userspace {
 
     unsafe impl aya::Pod for crate::SocketAddr {}
 
+    unsafe impl aya::Pod for crate::TraceEntry {}
+
+    unsafe impl aya::Pod for crate::ConnectionKey {}
+
+    unsafe impl aya::Pod for crate::ConnectionSequence {}
+
+    unsafe impl aya::Pod for crate::InitialSequence {}
+
+    unsafe impl aya::Pod for crate::SynFingerprint {}
+
     unsafe impl aya::Pod for crate::EbpfConfig {}
 }
 
@@ -121,7 +249,7 @@ mod tests {
 
     #[test]
     fn test_trace_event_size() {
-        assert_eq!(mem::size_of::<TraceEvent>(), 40);
+        assert_eq!(mem::size_of::<TraceEvent>(), 46);
     }
 
     #[test]
@@ -134,6 +262,31 @@ mod tests {
         assert_eq!(mem::size_of::<SocketAddr>(), 19);
     }
 
+    #[test]
+    fn test_trace_entry_size() {
+        assert_eq!(mem::size_of::<TraceEntry>(), 10);
+    }
+
+    #[test]
+    fn test_connection_key_size() {
+        assert_eq!(mem::size_of::<ConnectionKey>(), 21);
+    }
+
+    #[test]
+    fn test_connection_sequence_size() {
+        assert_eq!(mem::size_of::<ConnectionSequence>(), 10);
+    }
+
+    #[test]
+    fn test_initial_sequence_size() {
+        assert_eq!(mem::size_of::<InitialSequence>(), 11);
+    }
+
+    #[test]
+    fn test_syn_fingerprint_size() {
+        assert_eq!(mem::size_of::<SynFingerprint>(), 24);
+    }
+
     #[test]
     fn test_ebpf_config_size() {
         assert_eq!(mem::size_of::<EbpfConfig>(), 36);
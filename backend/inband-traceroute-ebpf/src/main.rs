@@ -4,25 +4,72 @@
 use core::mem;
 
 use aya_ebpf::{
-    bindings::xdp_action,
+    bindings::{xdp_action, TC_ACT_PIPE},
     helpers::r#gen::bpf_ktime_get_ns,
-    macros::{map, xdp},
-    maps::{Array, HashMap, PerfEventArray},
-    programs::XdpContext,
+    macros::{classifier, map, xdp},
+    maps::{Array, HashMap, LruHashMap, PerfEventArray},
+    programs::{TcContext, XdpContext},
 };
 use aya_log_ebpf::debug;
-use inband_traceroute_common::{EbpfConfig, IPAddr, IPVersion, SocketAddr, TraceEvent};
+use inband_traceroute_common::{
+    ConnectionHeaders, ConnectionKey, ConnectionSequence, EbpfConfig, IPAddr, IPVersion,
+    InitialSequence, SocketAddr, SynFingerprint, TraceEntry, TraceEvent, TraceEventType,
+    CLASSIC_UDP_BASE_PORT, PROBE_MARK, SYN_MAX_OPTIONS, SYN_NO_WSCALE,
+};
 use network_types::{
     eth::{EthHdr, EtherType},
     ip::{IpProto, Ipv4Hdr, Ipv6Hdr},
     tcp::TcpHdr,
 };
 
+const IPV4_DF: u16 = 0x4000;
+
+const ICMP_TYPE_ECHO_REPLY: u8 = 0;
 const ICMP_TYPE_TTL_EXCEEDED: u8 = 11;
+const ICMP_TYPE_DEST_UNREACHABLE: u8 = 3;
+const ICMP_CODE_FRAG_NEEDED: u8 = 4;
+const ICMPV6_TYPE_DEST_UNREACHABLE: u8 = 1;
 const ICMPV6_TYPE_TTL_EXCEEDED: u8 = 3;
+const ICMPV6_TYPE_PACKET_TOO_BIG: u8 = 2;
+const ICMPV6_TYPE_ECHO_REPLY: u8 = 129;
 
 const MAX_TRACES: u32 = 1024;
 
+// Connections whose SYN is remembered, the oldest are evicted first
+const MAX_SYNS: u32 = 16384;
+
+// Connections whose initial sequence numbers are remembered, the oldest are evicted first
+const MAX_CONNECTIONS: u32 = 16384;
+
+const TCPOPT_EOL: u8 = 0;
+const TCPOPT_NOP: u8 = 1;
+const TCPOPT_MSS: u8 = 2;
+const TCPOPT_WSCALE: u8 = 3;
+
+// Options can take up to 40 bytes after the fixed TCP header
+const TCP_MAX_OPTIONS_LEN: usize = 40;
+
+// Probes carry no TCP options
+const TCP_HDR_LEN: u16 = 20;
+
+// Common layout of ICMP and ICMPv6 error message headers
+#[repr(C)]
+struct IcmpErrorHdr {
+    pub type_: u8,
+    pub code: u8,
+    pub checksum: u16,
+    // Unused, except for the next-hop MTU in Fragmentation Needed / Packet Too Big
+    pub data: u32,
+}
+
+#[repr(C)]
+struct UdpHdr {
+    pub source: u16,
+    pub dest: u16,
+    pub len: u16,
+    pub check: u16,
+}
+
 #[repr(C)]
 struct TCPHeaderFirst8Bytes {
     pub source: u16,
@@ -34,7 +81,23 @@ struct TCPHeaderFirst8Bytes {
 static EVENTS: PerfEventArray<TraceEvent> = PerfEventArray::new(0);
 
 #[map]
-static TRACES: HashMap<SocketAddr, u32> = HashMap::with_max_entries(MAX_TRACES, 0);
+static TRACES: HashMap<ConnectionKey, TraceEntry> = HashMap::with_max_entries(MAX_TRACES, 0);
+
+// Trace ids of classic traces by client address and identifier
+#[map]
+static CLASSIC_TRACES: HashMap<SocketAddr, u32> = HashMap::with_max_entries(MAX_TRACES, 0);
+
+#[map]
+static SYNS: LruHashMap<ConnectionKey, SynFingerprint> = LruHashMap::with_max_entries(MAX_SYNS, 0);
+
+// Only of traced connections, added by userspace when the trace starts
+#[map]
+static SEQUENCES: HashMap<ConnectionKey, ConnectionSequence> =
+    HashMap::with_max_entries(MAX_TRACES, 0);
+
+#[map]
+static HANDSHAKES: LruHashMap<ConnectionKey, InitialSequence> =
+    LruHashMap::with_max_entries(MAX_CONNECTIONS, 0);
 
 #[map]
 static CONFIG: Array<EbpfConfig> = Array::with_max_entries(1, 0);
@@ -48,8 +111,8 @@ pub fn inband_traceroute(ctx: XdpContext) -> u32 {
     }
 }
 
-// Basically we ignore all packets that are not destined for our server (protocol, address, port)
-// Then, ignore all packets that are not associated with an active trace
+// Basically we ignore all packets that are not destined for our server (protocol, address)
+// Then, ignore all packets that are not associated with an active trace on the same local port
 fn try_inband_traceroute(ctx: XdpContext, arrival: u64) -> Result<(), ()> {
     let config: &EbpfConfig = CONFIG.get(0).ok_or(())?;
     let ethhdr: &EthHdr = ptr_at(&ctx, 0)?;
@@ -60,6 +123,8 @@ fn try_inband_traceroute(ctx: XdpContext, arrival: u64) -> Result<(), ()> {
     let ip_version: IPVersion;
     let layer4_protocol: IpProto;
     let layer4_offset: usize;
+    let ttl: u8;
+    let df: u8;
 
     match ether_type {
         EtherType::Ipv4 => {
@@ -73,6 +138,8 @@ fn try_inband_traceroute(ctx: XdpContext, arrival: u64) -> Result<(), ()> {
             ip_version = IPVersion::IPV4;
             layer4_protocol = ipv4hdr.proto;
             layer4_offset = EthHdr::LEN + Ipv4Hdr::LEN;
+            ttl = ipv4hdr.ttl;
+            df = (u16::from_be(ipv4hdr.frag_off) & IPV4_DF != 0) as u8;
 
             src_addr.addr = IPAddr::new_v4(ipv4hdr.src_addr.to_le_bytes());
         }
@@ -85,6 +152,8 @@ fn try_inband_traceroute(ctx: XdpContext, arrival: u64) -> Result<(), ()> {
             ip_version = IPVersion::IPV6;
             layer4_protocol = ipv6hdr.next_hdr;
             layer4_offset = EthHdr::LEN + Ipv6Hdr::LEN;
+            ttl = ipv6hdr.hop_limit;
+            df = 0;
 
             src_addr.addr = IPAddr::new_v6(unsafe { ipv6hdr.src_addr.in6_u.u6_addr8 });
         }
@@ -98,36 +167,57 @@ fn try_inband_traceroute(ctx: XdpContext, arrival: u64) -> Result<(), ()> {
             let tcp_hdr: &TcpHdr = ptr_at(&ctx, layer4_offset)?;
             let dst_port = u16::from_be(tcp_hdr.dest);
 
-            if dst_port != config.port {
+            src_addr.port = u16::from_be(tcp_hdr.source);
+            let key = ConnectionKey {
+                remote: src_addr,
+                local_port: dst_port,
+            };
+
+            // A client's SYN or a server's SYN-ACK
+            if tcp_hdr.syn() != 0 {
+                record_syn_received(&key, u32::from_be(tcp_hdr.seq));
+
+                // Remember how clients' SYNs look when they arrive, for fingerprinting. Any of
+                // our ports, since the raw TCP service listens on its own.
+                if tcp_hdr.ack() == 0 {
+                    let fingerprint = syn_fingerprint(&ctx, layer4_offset, tcp_hdr, ttl, df);
+                    let _ = SYNS.insert(&key, &fingerprint, 0);
+                }
                 return Ok(());
             }
 
-            // Ignore packets that are not TCP SYN or RST now to avoid map lookups
+            // Ignore packets that are not TCP ACK or RST now to avoid map lookups
             if tcp_hdr.ack() == 0 && tcp_hdr.rst() == 0 {
                 return Ok(());
             }
 
-            src_addr.port = u16::from_be(tcp_hdr.source);
-            let trace_id = unsafe { TRACES.get(&src_addr) };
-            match trace_id {
+            let trace = unsafe { TRACES.get(&key) };
+            match trace {
                 None => {
                     return Ok(());
                 }
-                Some(trace_id) => {
+                Some(trace) => {
+                    if tcp_hdr.ack() != 0 {
+                        record_ack_received(&key, u32::from_be(tcp_hdr.ack_seq));
+                    }
+
                     // Found a trace, send event
                     let event = TraceEvent {
                         arrival,
-                        trace_id: *trace_id,
+                        trace_id: trace.trace_id,
                         event_type: if tcp_hdr.ack() != 0 {
-                            inband_traceroute_common::TraceEventType::TcpAck
+                            TraceEventType::TcpAck
                         } else {
-                            inband_traceroute_common::TraceEventType::TcpRst
+                            TraceEventType::TcpRst
                         },
                         ack_seq: u32::from_be(tcp_hdr.ack_seq),
                         seq: u32::from_be(tcp_hdr.seq),
                         ip_version,
                         ttl: 0,
                         addr: src_addr.addr,
+                        mtu: 0,
+                        quoted_tos: 0,
+                        probe_protocol: IpProto::Tcp as u8,
                     };
 
                     EVENTS.output(&ctx, &event, 0)
@@ -137,54 +227,87 @@ fn try_inband_traceroute(ctx: XdpContext, arrival: u64) -> Result<(), ()> {
             return Ok(());
         }
         IpProto::Icmp => {
-            let icmp_hdr: &network_types::icmp::IcmpHdr = ptr_at(&ctx, layer4_offset)?;
-            if icmp_hdr.type_ != ICMP_TYPE_TTL_EXCEEDED {
-                return Ok(());
-            }
+            let icmp_hdr: &IcmpErrorHdr = ptr_at(&ctx, layer4_offset)?;
+            let classic_type = match icmp_hdr.type_ {
+                ICMP_TYPE_ECHO_REPLY => {
+                    return classic_echo_reply(
+                        &ctx,
+                        arrival,
+                        src_addr.addr,
+                        icmp_hdr,
+                        IpProto::Icmp,
+                    );
+                }
+                ICMP_TYPE_TTL_EXCEEDED => TraceEventType::ClassicTimeExceeded,
+                ICMP_TYPE_DEST_UNREACHABLE => TraceEventType::ClassicUnreachable,
+                _ => return Ok(()),
+            };
 
             let original_ip_hdr: &Ipv4Hdr = ptr_at(&ctx, layer4_offset + 8)?;
 
-            if original_ip_hdr.proto != IpProto::Tcp
-                || Some(original_ip_hdr.src_addr) != config.get_ipv4()
-            {
-                debug!(&ctx, "Not TCP packet or not from us");
+            if Some(original_ip_hdr.src_addr) != config.get_ipv4() {
+                debug!(&ctx, "Not from us");
                 return Ok(());
             }
 
+            if original_ip_hdr.proto != IpProto::Tcp {
+                return classic_icmp_error(
+                    &ctx,
+                    arrival,
+                    src_addr.addr,
+                    classic_type,
+                    original_ip_hdr.proto,
+                    IPAddr::new_v4(original_ip_hdr.dst_addr.to_le_bytes()),
+                    layer4_offset + 8 + Ipv4Hdr::LEN,
+                );
+            }
+
+            let (event_type, mtu) = match (icmp_hdr.type_, icmp_hdr.code) {
+                (ICMP_TYPE_TTL_EXCEEDED, _) => (TraceEventType::IcmpTimeExceeded, 0),
+                (ICMP_TYPE_DEST_UNREACHABLE, ICMP_CODE_FRAG_NEEDED) => (
+                    TraceEventType::IcmpPacketTooBig,
+                    u32::from_be(icmp_hdr.data) & 0xffff,
+                ),
+                _ => return Ok(()),
+            };
+
             let original_tcp_hdr: &TCPHeaderFirst8Bytes =
                 ptr_at(&ctx, layer4_offset + 8 + Ipv4Hdr::LEN)?;
 
-            // packet didn't come from us
-            if u16::from_be(original_tcp_hdr.source) != config.port {
-                debug!(&ctx, "Not TCP port match");
-                return Ok(());
-            }
-
             let original_dest_addr = SocketAddr {
                 addr: IPAddr::new_v4(original_ip_hdr.dst_addr.to_le_bytes()),
                 port: u16::from_be(original_tcp_hdr.dest),
             };
 
-            let trace_id = unsafe { TRACES.get(&original_dest_addr) };
-            match trace_id {
+            let key = ConnectionKey {
+                remote: original_dest_addr,
+                local_port: u16::from_be(original_tcp_hdr.source),
+            };
+
+            let trace = unsafe { TRACES.get(&key) };
+            match trace {
                 None => {
                     debug!(&ctx, "No trace found for original destination address");
                     return Ok(());
                 }
-                Some(trace_id) => {
+                Some(trace) => {
                     // Found a trace, send event
                     let event = TraceEvent {
                         arrival,
-                        trace_id: *trace_id,
-                        event_type: inband_traceroute_common::TraceEventType::IcmpTimeExceeded,
+                        trace_id: trace.trace_id,
+                        event_type,
                         ack_seq: 0,
-                        seq: 0,
+                        // Tells which probe a Packet Too Big answers
+                        seq: u32::from_be(original_tcp_hdr.seq),
                         ip_version: IPVersion::IPV4,
                         ttl: u16::from_be(original_ip_hdr.id) as u8,
                         addr: src_addr.addr,
+                        mtu,
+                        quoted_tos: original_ip_hdr.tos,
+                        probe_protocol: IpProto::Tcp as u8,
                     };
 
-                    debug!(&ctx, "Sending ICMP TTL Exceeded event: {}", event.trace_id);
+                    debug!(&ctx, "Sending ICMP event: {}", event.trace_id);
 
                     EVENTS.output(&ctx, &event, 0);
 
@@ -193,55 +316,91 @@ fn try_inband_traceroute(ctx: XdpContext, arrival: u64) -> Result<(), ()> {
             }
         }
         IpProto::Ipv6Icmp => {
-            // Note: first 4 bytes of ICMPv6 header are the same as IPv4
-            let icmp_hdr: &network_types::icmp::IcmpHdr = ptr_at(&ctx, layer4_offset)?;
-            if icmp_hdr.type_ != ICMPV6_TYPE_TTL_EXCEEDED {
-                return Ok(());
+            // Note: first 8 bytes of ICMPv6 error messages are the same as IPv4
+            let icmp_hdr: &IcmpErrorHdr = ptr_at(&ctx, layer4_offset)?;
+            if icmp_hdr.type_ == ICMPV6_TYPE_ECHO_REPLY {
+                return classic_echo_reply(
+                    &ctx,
+                    arrival,
+                    src_addr.addr,
+                    icmp_hdr,
+                    IpProto::Ipv6Icmp,
+                );
             }
 
             let original_ip_hdr: &Ipv6Hdr = ptr_at(&ctx, layer4_offset + 8)?;
+            let original_first_word: &[u8; 4] = ptr_at(&ctx, layer4_offset + 8)?;
 
-            if original_ip_hdr.next_hdr != IpProto::Tcp
-                || Some(unsafe { original_ip_hdr.src_addr.in6_u.u6_addr8 }) != config.get_ipv6()
-            {
-                debug!(&ctx, "Not TCP packet or not from us");
+            if Some(unsafe { original_ip_hdr.src_addr.in6_u.u6_addr8 }) != config.get_ipv6() {
+                debug!(&ctx, "Not from us");
                 return Ok(());
             }
 
+            if original_ip_hdr.next_hdr != IpProto::Tcp {
+                let classic_type = match icmp_hdr.type_ {
+                    ICMPV6_TYPE_TTL_EXCEEDED => TraceEventType::ClassicTimeExceeded,
+                    ICMPV6_TYPE_DEST_UNREACHABLE => TraceEventType::ClassicUnreachable,
+                    _ => return Ok(()),
+                };
+                return classic_icmp_error(
+                    &ctx,
+                    arrival,
+                    src_addr.addr,
+                    classic_type,
+                    original_ip_hdr.next_hdr,
+                    IPAddr::new_v6(unsafe { original_ip_hdr.dst_addr.in6_u.u6_addr8 }),
+                    layer4_offset + 8 + Ipv6Hdr::LEN,
+                );
+            }
+
+            let (event_type, mtu) = match icmp_hdr.type_ {
+                ICMPV6_TYPE_TTL_EXCEEDED => (TraceEventType::IcmpTimeExceeded, 0),
+                ICMPV6_TYPE_PACKET_TOO_BIG => (
+                    TraceEventType::IcmpPacketTooBig,
+                    u32::from_be(icmp_hdr.data),
+                ),
+                _ => return Ok(()),
+            };
+
             let original_tcp_hdr: &TCPHeaderFirst8Bytes =
                 ptr_at(&ctx, layer4_offset + 8 + Ipv6Hdr::LEN)?;
 
-            // packet didn't come from us
-            if u16::from_be(original_tcp_hdr.source) != config.port {
-                debug!(&ctx, "Not TCP port match");
-                return Ok(());
-            }
-
             let original_dest_addr = SocketAddr {
                 addr: IPAddr::new_v6(unsafe { original_ip_hdr.dst_addr.in6_u.u6_addr8 }),
                 port: u16::from_be(original_tcp_hdr.dest),
             };
 
-            let trace_id = unsafe { TRACES.get(&original_dest_addr) };
-            match trace_id {
+            let key = ConnectionKey {
+                remote: original_dest_addr,
+                local_port: u16::from_be(original_tcp_hdr.source),
+            };
+
+            let trace = unsafe { TRACES.get(&key) };
+            match trace {
                 None => {
                     debug!(&ctx, "No trace found for original destination address");
                     return Ok(());
                 }
-                Some(trace_id) => {
+                Some(trace) => {
                     // Found a trace, send event
                     let event = TraceEvent {
                         arrival,
-                        trace_id: *trace_id,
-                        event_type: inband_traceroute_common::TraceEventType::IcmpTimeExceeded,
+                        trace_id: trace.trace_id,
+                        event_type,
                         ack_seq: 0,
-                        seq: 0,
+                        seq: u32::from_be(original_tcp_hdr.seq),
                         ip_version: IPVersion::IPV6,
-                        ttl: original_ip_hdr.flow_label[2],
+                        // The flow label is copied from the connection, so the TTL is
+                        // encoded in the probe's payload length instead
+                        ttl: u16::from_be(original_ip_hdr.payload_len).saturating_sub(TCP_HDR_LEN)
+                            as u8,
                         addr: src_addr.addr,
+                        mtu,
+                        quoted_tos: ipv6_traffic_class(original_first_word),
+                        probe_protocol: IpProto::Tcp as u8,
                     };
 
-                    debug!(&ctx, "Sending ICMP TTL Exceeded event: {}", event.trace_id);
+                    debug!(&ctx, "Sending ICMP event: {}", event.trace_id);
 
                     EVENTS.output(&ctx, &event, 0);
 
@@ -255,10 +414,352 @@ fn try_inband_traceroute(ctx: XdpContext, arrival: u64) -> Result<(), ()> {
     }
 }
 
+// The SYN's header fields and the kinds of its TCP options in order
+#[inline(always)]
+fn syn_fingerprint(
+    ctx: &XdpContext,
+    tcp_offset: usize,
+    tcp_hdr: &TcpHdr,
+    ttl: u8,
+    df: u8,
+) -> SynFingerprint {
+    let mut fingerprint = SynFingerprint {
+        ttl,
+        df,
+        window: u16::from_be(tcp_hdr.window),
+        mss: 0,
+        wscale: SYN_NO_WSCALE,
+        option_count: 0,
+        options: [0; SYN_MAX_OPTIONS],
+    };
+
+    let options_len = (tcp_hdr.doff() as usize * 4)
+        .saturating_sub(TcpHdr::LEN)
+        .min(TCP_MAX_OPTIONS_LEN);
+    let options_end = tcp_offset + TcpHdr::LEN + options_len;
+    let mut offset = tcp_offset + TcpHdr::LEN;
+
+    // Bounded for the verifier
+    for i in 0..SYN_MAX_OPTIONS {
+        if offset >= options_end {
+            break;
+        }
+        let Ok(kind) = ptr_at::<u8>(ctx, offset) else {
+            break;
+        };
+        let kind = *kind;
+        fingerprint.options[i] = kind;
+        fingerprint.option_count += 1;
+
+        match kind {
+            TCPOPT_EOL => break,
+            TCPOPT_NOP => {
+                offset += 1;
+                continue;
+            }
+            _ => {}
+        }
+
+        let Ok(len) = ptr_at::<u8>(ctx, offset + 1) else {
+            break;
+        };
+        let len = *len as usize;
+        if len < 2 {
+            break;
+        }
+
+        match (kind, len) {
+            (TCPOPT_MSS, 4) => {
+                if let Ok(mss) = ptr_at::<[u8; 2]>(ctx, offset + 2) {
+                    fingerprint.mss = u16::from_be_bytes(*mss);
+                }
+            }
+            (TCPOPT_WSCALE, 3) => {
+                if let Ok(wscale) = ptr_at::<u8>(ctx, offset + 2) {
+                    fingerprint.wscale = *wscale;
+                }
+            }
+            _ => {}
+        }
+        offset += len;
+    }
+
+    fingerprint
+}
+
+// Keep the peer's initial sequence number, so userspace can tell where the connection is later
+#[inline(always)]
+fn record_syn_received(key: &ConnectionKey, seq: u32) {
+    match HANDSHAKES.get_ptr_mut(key) {
+        Some(handshake) => unsafe {
+            (*handshake).remote = seq;
+            (*handshake).remote_seen = 1;
+        },
+        None => {
+            let handshake = InitialSequence {
+                remote_seen: 1,
+                remote: seq,
+                ..Default::default()
+            };
+            let _ = HANDSHAKES.insert(key, &handshake, 0);
+        }
+    }
+}
+
+// Keep our initial sequence number on a connection
+#[inline(always)]
+fn record_syn_sent(key: &ConnectionKey, seq: u32, opened: u8) {
+    match HANDSHAKES.get_ptr_mut(key) {
+        Some(handshake) => unsafe {
+            (*handshake).local = seq;
+            (*handshake).opened = opened;
+            (*handshake).local_seen = 1;
+        },
+        None => {
+            let handshake = InitialSequence {
+                local_seen: 1,
+                opened,
+                local: seq,
+                ..Default::default()
+            };
+            let _ = HANDSHAKES.insert(key, &handshake, 0);
+        }
+    }
+}
+
+// Keep the highest acknowledgment number the peer sent on a traced connection. They wrap around,
+// and a reordered ACK must not move it back.
+#[inline(always)]
+fn record_ack_received(key: &ConnectionKey, ack_seq: u32) {
+    if let Some(sequence) = SEQUENCES.get_ptr_mut(key) {
+        unsafe {
+            if (*sequence).acked == 0 || ack_seq.wrapping_sub((*sequence).snd_una) as i32 > 0 {
+                (*sequence).snd_una = ack_seq;
+                (*sequence).acked = 1;
+            }
+        }
+    }
+}
+
+// Keep the acknowledgment number of our last segment on a traced connection
+#[inline(always)]
+fn record_ack_sent(key: &ConnectionKey, ack_seq: u32) {
+    if let Some(sequence) = SEQUENCES.get_ptr_mut(key) {
+        unsafe {
+            (*sequence).rcv_nxt = ack_seq;
+            (*sequence).ack_sent = 1;
+        }
+    }
+}
+
+// Echo replies from a client that is being traced classically. The identifier is the trace's,
+// the sequence number is the TTL.
+#[inline(always)]
+fn classic_echo_reply(
+    ctx: &XdpContext,
+    arrival: u64,
+    from: IPAddr,
+    echo_hdr: &IcmpErrorHdr,
+    protocol: IpProto,
+) -> Result<(), ()> {
+    let ident_seq = u32::from_be(echo_hdr.data);
+    let key = SocketAddr {
+        port: (ident_seq >> 16) as u16,
+        addr: from,
+    };
+    send_classic_event(
+        ctx,
+        &key,
+        arrival,
+        TraceEventType::ClassicEchoReply,
+        protocol,
+        ident_seq as u8,
+        from,
+    );
+    Ok(())
+}
+
+// ICMP errors quoting a classic probe, which carries the trace's identifier in the ICMP echo
+// identifier or UDP source port, and the TTL in the ICMP echo sequence number or UDP destination
+// port
+#[inline(always)]
+fn classic_icmp_error(
+    ctx: &XdpContext,
+    arrival: u64,
+    from: IPAddr,
+    event_type: TraceEventType,
+    protocol: IpProto,
+    original_dst: IPAddr,
+    quoted_offset: usize,
+) -> Result<(), ()> {
+    let (ident, ttl) = match protocol {
+        IpProto::Icmp | IpProto::Ipv6Icmp => {
+            let echo_hdr: &IcmpErrorHdr = ptr_at(ctx, quoted_offset)?;
+            let ident_seq = u32::from_be(echo_hdr.data);
+            ((ident_seq >> 16) as u16, ident_seq as u8)
+        }
+        IpProto::Udp => {
+            let udp_hdr: &UdpHdr = ptr_at(ctx, quoted_offset)?;
+            let dest = u16::from_be(udp_hdr.dest);
+            if dest <= CLASSIC_UDP_BASE_PORT || dest > CLASSIC_UDP_BASE_PORT + u8::MAX as u16 {
+                return Ok(());
+            }
+            (
+                u16::from_be(udp_hdr.source),
+                (dest - CLASSIC_UDP_BASE_PORT) as u8,
+            )
+        }
+        _ => return Ok(()),
+    };
+
+    let key = SocketAddr {
+        port: ident,
+        addr: original_dst,
+    };
+    send_classic_event(ctx, &key, arrival, event_type, protocol, ttl, from);
+    Ok(())
+}
+
+#[inline(always)]
+fn send_classic_event(
+    ctx: &XdpContext,
+    key: &SocketAddr,
+    arrival: u64,
+    event_type: TraceEventType,
+    protocol: IpProto,
+    ttl: u8,
+    from: IPAddr,
+) {
+    let Some(trace_id) = (unsafe { CLASSIC_TRACES.get(key) }) else {
+        return;
+    };
+
+    let event = TraceEvent {
+        arrival,
+        trace_id: *trace_id,
+        event_type,
+        ack_seq: 0,
+        seq: 0,
+        ip_version: from.ip_version,
+        ttl,
+        addr: from,
+        mtu: 0,
+        quoted_tos: 0,
+        probe_protocol: protocol as u8,
+    };
+
+    EVENTS.output(ctx, &event, 0);
+}
+
+#[classifier]
+pub fn inband_traceroute_egress(ctx: TcContext) -> i32 {
+    let _ = try_inband_traceroute_egress(&ctx);
+    TC_ACT_PIPE
+}
+
+// Record the TOS/traffic class and flow label the kernel puts on outgoing packets of traced
+// connections so that probes can copy them and hash onto the same ECMP path, and their
+// acknowledgment number so probes can carry it. Our SYNs are recorded for every connection.
+fn try_inband_traceroute_egress(ctx: &TcContext) -> Result<(), ()> {
+    // Our own probes must not overwrite what the kernel uses
+    if unsafe { (*ctx.skb.skb).mark } == PROBE_MARK {
+        return Ok(());
+    }
+
+    let config: &EbpfConfig = CONFIG.get(0).ok_or(())?;
+    let ethhdr: &EthHdr = skb_ptr_at(ctx, 0)?;
+
+    let dst_addr: IPAddr;
+    let headers: ConnectionHeaders;
+    let layer4_offset: usize;
+
+    match ethhdr.ether_type {
+        EtherType::Ipv4 => {
+            let ipv4hdr: &Ipv4Hdr = skb_ptr_at(ctx, EthHdr::LEN)?;
+            if ipv4hdr.proto != IpProto::Tcp || Some(ipv4hdr.src_addr) != config.get_ipv4() {
+                return Ok(());
+            }
+
+            dst_addr = IPAddr::new_v4(ipv4hdr.dst_addr.to_le_bytes());
+            headers = ConnectionHeaders {
+                observed: 1,
+                tos: ipv4hdr.tos,
+                flow_label: 0,
+            };
+            layer4_offset = EthHdr::LEN + Ipv4Hdr::LEN;
+        }
+        EtherType::Ipv6 => {
+            let ipv6hdr: &Ipv6Hdr = skb_ptr_at(ctx, EthHdr::LEN)?;
+            if ipv6hdr.next_hdr != IpProto::Tcp
+                || Some(unsafe { ipv6hdr.src_addr.in6_u.u6_addr8 }) != config.get_ipv6()
+            {
+                return Ok(());
+            }
+
+            let first_word: &[u8; 4] = skb_ptr_at(ctx, EthHdr::LEN)?;
+
+            dst_addr = IPAddr::new_v6(unsafe { ipv6hdr.dst_addr.in6_u.u6_addr8 });
+            headers = ConnectionHeaders {
+                observed: 1,
+                tos: ipv6_traffic_class(first_word),
+                flow_label: u32::from_be_bytes(*first_word) & 0xfffff,
+            };
+            layer4_offset = EthHdr::LEN + Ipv6Hdr::LEN;
+        }
+        _ => {
+            return Ok(());
+        }
+    }
+
+    let tcp_hdr: &TcpHdr = skb_ptr_at(ctx, layer4_offset)?;
+    let local_port = u16::from_be(tcp_hdr.source);
+
+    let key = ConnectionKey {
+        remote: SocketAddr {
+            port: u16::from_be(tcp_hdr.dest),
+            addr: dst_addr,
+        },
+        local_port,
+    };
+
+    // Our SYN or SYN-ACK
+    if tcp_hdr.syn() != 0 {
+        let opened = (tcp_hdr.ack() == 0) as u8;
+        record_syn_sent(&key, u32::from_be(tcp_hdr.seq), opened);
+        return Ok(());
+    }
+
+    if let Some(trace) = TRACES.get_ptr_mut(&key) {
+        unsafe {
+            (*trace).headers = headers;
+        }
+        if tcp_hdr.ack() != 0 {
+            record_ack_sent(&key, u32::from_be(tcp_hdr.ack_seq));
+        }
+    }
+
+    Ok(())
+}
+
+// The first word of the IPv6 header is version (4 bits), traffic class (8 bits) and flow label
+// (20 bits)
+#[inline(always)]
+fn ipv6_traffic_class(first_word: &[u8; 4]) -> u8 {
+    (u32::from_be_bytes(*first_word) >> 20) as u8
+}
+
 #[inline(always)]
 fn ptr_at<T>(ctx: &XdpContext, offset: usize) -> Result<&T, ()> {
-    let start = ctx.data();
-    let end = ctx.data_end();
+    bounded_ptr_at(ctx.data(), ctx.data_end(), offset)
+}
+
+#[inline(always)]
+fn skb_ptr_at<T>(ctx: &TcContext, offset: usize) -> Result<&T, ()> {
+    bounded_ptr_at(ctx.data(), ctx.data_end(), offset)
+}
+
+#[inline(always)]
+fn bounded_ptr_at<'a, T>(start: usize, end: usize, offset: usize) -> Result<&'a T, ()> {
     let len = mem::size_of::<T>();
 
     if start + offset + len > end {
@@ -2,6 +2,11 @@ use anyhow::{anyhow, Context as _};
 use aya_build::cargo_metadata;
 
 fn main() -> anyhow::Result<()> {
+    tonic_build::configure()
+        .build_client(false)
+        .compile_protos(&["proto/inband_traceroute.proto"], &["proto"])
+        .context("Failed to compile protobuf definitions")?;
+
     let cargo_metadata::Metadata { packages, .. } = cargo_metadata::MetadataCommand::new()
         .no_deps()
         .exec()
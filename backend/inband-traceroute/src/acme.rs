@@ -0,0 +1,491 @@
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Context};
+use async_trait::async_trait;
+use aws_lc_rs::{
+    hmac,
+    rand::SystemRandom,
+    signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING},
+};
+use base64::prelude::*;
+use bytes::Bytes;
+use http::{header, uri::Scheme, Request, Uri};
+use http_body_util::{BodyExt, Full};
+use hyper_util::rt::TokioIo;
+use rustls_acme::{
+    acme::{Account, Directory},
+    caches::DirCache,
+    futures_rustls::rustls::{pki_types::ServerName, ClientConfig, RootCertStore},
+    AccountCache, CertCache,
+};
+use serde::Serialize;
+use serde_json::{json, Value};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+
+// Number of ACME events kept for the status endpoint
+const STATUS_EVENTS: usize = 32;
+
+/// Client TLS config for talking to the ACME directory, trusting the webpki roots plus the CA
+/// certificates in `ca_cert` (for an internal CA or a local Pebble instance)
+pub(crate) fn client_config(ca_cert: Option<&Path>) -> anyhow::Result<Arc<ClientConfig>> {
+    let mut roots = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+
+    if let Some(path) = ca_cert {
+        let file =
+            File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+        for cert in rustls_pemfile::certs(&mut BufReader::new(file)) {
+            let cert = cert
+                .with_context(|| format!("Failed to parse certificates in {}", path.display()))?;
+            roots
+                .add(cert)
+                .with_context(|| format!("Invalid CA certificate in {}", path.display()))?;
+        }
+    }
+
+    Ok(Arc::new(
+        ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth(),
+    ))
+}
+
+/// External Account Binding credentials issued by the CA
+#[derive(Debug, Clone)]
+pub(crate) struct ExternalAccountBinding {
+    kid: String,
+    hmac_key: Vec<u8>,
+}
+
+impl ExternalAccountBinding {
+    /// `hmac_key` is base64url encoded, as CAs hand it out
+    pub(crate) fn new(kid: &str, hmac_key: &str) -> anyhow::Result<Self> {
+        let hmac_key = BASE64_URL_SAFE_NO_PAD
+            .decode(hmac_key.trim_end_matches('='))
+            .context("EAB HMAC key is not valid base64url")?;
+        Ok(Self {
+            kid: kid.to_owned(),
+            hmac_key,
+        })
+    }
+}
+
+/// Certificate and account cache for rustls-acme, optionally backed by a directory.
+///
+/// rustls-acme can't register accounts with External Account Binding. When EAB is configured
+/// and there is no cached account, a new account key is registered with EAB here and handed to
+/// rustls-acme, whose own registration then just returns the existing account.
+pub(crate) struct AcmeCache {
+    dir: Option<DirCache<PathBuf>>,
+    // Also kept without a directory, so renewals reuse the account instead of registering a new
+    // one each time
+    account: Mutex<Option<Vec<u8>>>,
+    eab: Option<ExternalAccountBinding>,
+    client_config: Arc<ClientConfig>,
+}
+
+impl AcmeCache {
+    pub(crate) fn new(
+        dir: Option<PathBuf>,
+        eab: Option<ExternalAccountBinding>,
+        client_config: Arc<ClientConfig>,
+    ) -> Self {
+        Self {
+            dir: dir.map(DirCache::new),
+            account: Mutex::new(None),
+            eab,
+            client_config,
+        }
+    }
+}
+
+#[async_trait]
+impl CertCache for AcmeCache {
+    type EC = anyhow::Error;
+
+    async fn load_cert(
+        &self,
+        domains: &[String],
+        directory_url: &str,
+    ) -> Result<Option<Vec<u8>>, Self::EC> {
+        match &self.dir {
+            Some(dir) => Ok(dir.load_cert(domains, directory_url).await?),
+            None => Ok(None),
+        }
+    }
+
+    async fn store_cert(
+        &self,
+        domains: &[String],
+        directory_url: &str,
+        cert: &[u8],
+    ) -> Result<(), Self::EC> {
+        match &self.dir {
+            Some(dir) => Ok(dir.store_cert(domains, directory_url, cert).await?),
+            None => Ok(()),
+        }
+    }
+}
+
+#[async_trait]
+impl AccountCache for AcmeCache {
+    type EA = anyhow::Error;
+
+    async fn load_account(
+        &self,
+        contact: &[String],
+        directory_url: &str,
+    ) -> Result<Option<Vec<u8>>, Self::EA> {
+        if let Some(account) = self.account.lock().unwrap().clone() {
+            return Ok(Some(account));
+        }
+        if let Some(dir) = &self.dir {
+            if let Some(account) = dir.load_account(contact, directory_url).await? {
+                *self.account.lock().unwrap() = Some(account.clone());
+                return Ok(Some(account));
+            }
+        }
+
+        let Some(eab) = &self.eab else {
+            return Ok(None);
+        };
+
+        let key_pair = Account::generate_key_pair();
+        register_account(&self.client_config, directory_url, contact, &key_pair, eab)
+            .await
+            .context("Failed to register ACME account with External Account Binding")?;
+        self.store_account(contact, directory_url, &key_pair)
+            .await?;
+
+        Ok(Some(key_pair))
+    }
+
+    async fn store_account(
+        &self,
+        contact: &[String],
+        directory_url: &str,
+        account: &[u8],
+    ) -> Result<(), Self::EA> {
+        *self.account.lock().unwrap() = Some(account.to_vec());
+        match &self.dir {
+            Some(dir) => Ok(dir.store_account(contact, directory_url, account).await?),
+            None => Ok(()),
+        }
+    }
+}
+
+// RFC 8555 section 7.3.4
+async fn register_account(
+    client_config: &Arc<ClientConfig>,
+    directory_url: &str,
+    contact: &[String],
+    key_pair: &[u8],
+    eab: &ExternalAccountBinding,
+) -> anyhow::Result<()> {
+    let directory = Directory::discover(client_config, directory_url)
+        .await
+        .map_err(|err| anyhow!("Failed to discover ACME directory: {err}"))?;
+    let nonce = directory
+        .nonce(client_config)
+        .await
+        .map_err(|err| anyhow!("Failed to get ACME nonce: {err}"))?;
+
+    let body = new_account_request(&directory.new_account, &nonce, contact, key_pair, eab)?;
+    post_jose(client_config, &directory.new_account, body.to_string()).await
+}
+
+// Body of a newAccount request with the External Account Binding
+fn new_account_request(
+    new_account: &str,
+    nonce: &str,
+    contact: &[String],
+    key_pair: &[u8],
+    eab: &ExternalAccountBinding,
+) -> anyhow::Result<Value> {
+    let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, key_pair)
+        .map_err(|err| anyhow!("Invalid account key: {err}"))?;
+    let (x, y) = key.public_key().as_ref()[1..].split_at(32);
+    let jwk = json!({
+        "crv": "P-256",
+        "kty": "EC",
+        "x": BASE64_URL_SAFE_NO_PAD.encode(x),
+        "y": BASE64_URL_SAFE_NO_PAD.encode(y),
+    });
+
+    let hmac_key = hmac::Key::new(hmac::HMAC_SHA256, &eab.hmac_key);
+    let binding = jws(
+        &json!({ "alg": "HS256", "kid": eab.kid, "url": new_account }),
+        &jwk,
+        |message| Ok(hmac::sign(&hmac_key, message).as_ref().to_vec()),
+    )?;
+
+    let rng = SystemRandom::new();
+    jws(
+        &json!({ "alg": "ES256", "jwk": jwk, "nonce": nonce, "url": new_account }),
+        &json!({
+            "termsOfServiceAgreed": true,
+            "contact": contact,
+            "externalAccountBinding": binding,
+        }),
+        |message| {
+            let signature = key
+                .sign(&rng, message)
+                .map_err(|_| anyhow!("Failed to sign ACME request"))?;
+            Ok(signature.as_ref().to_vec())
+        },
+    )
+}
+
+// Flattened JWS JSON serialization
+fn jws(
+    protected: &Value,
+    payload: &Value,
+    sign: impl FnOnce(&[u8]) -> anyhow::Result<Vec<u8>>,
+) -> anyhow::Result<Value> {
+    let protected = BASE64_URL_SAFE_NO_PAD.encode(serde_json::to_vec(protected)?);
+    let payload = BASE64_URL_SAFE_NO_PAD.encode(serde_json::to_vec(payload)?);
+    let signature = sign(format!("{protected}.{payload}").as_bytes())?;
+
+    Ok(json!({
+        "protected": protected,
+        "payload": payload,
+        "signature": BASE64_URL_SAFE_NO_PAD.encode(signature),
+    }))
+}
+
+async fn post_jose(
+    client_config: &Arc<ClientConfig>,
+    url: &str,
+    body: String,
+) -> anyhow::Result<()> {
+    let uri: Uri = url.parse().context("Invalid ACME URL")?;
+    let host = uri.host().context("ACME URL has no host")?.to_owned();
+    // ACME is only served over HTTPS (RFC 8555 section 6.1)
+    anyhow::ensure!(
+        uri.scheme() == Some(&Scheme::HTTPS),
+        "ACME URL {url} is not https"
+    );
+    let port = port_or_known_default(&uri).context("ACME URL has no port")?;
+
+    let stream = TcpStream::connect((host.as_str(), port))
+        .await
+        .with_context(|| format!("Failed to connect to {host}:{port}"))?;
+    let server_name = ServerName::try_from(host.clone()).context("Invalid ACME host")?;
+    let stream = TlsConnector::from(client_config.clone())
+        .connect(server_name, stream)
+        .await
+        .context("TLS handshake with ACME server failed")?;
+
+    let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+        .await
+        .context("HTTP handshake with ACME server failed")?;
+    tokio::spawn(connection);
+
+    let request = Request::post(uri.path_and_query().map_or("/", |path| path.as_str()))
+        .header(header::HOST, host)
+        .header(header::CONTENT_TYPE, "application/jose+json")
+        .body(Full::new(Bytes::from(body)))?;
+    let response = sender.send_request(request).await?;
+
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.into_body().collect().await?.to_bytes();
+        anyhow::bail!("{status}: {}", String::from_utf8_lossy(&body));
+    }
+
+    Ok(())
+}
+
+// The URI's port, or the default one of its scheme
+fn port_or_known_default(uri: &Uri) -> Option<u16> {
+    uri.port_u16().or_else(|| match uri.scheme_str()? {
+        "https" => Some(443),
+        "http" => Some(80),
+        _ => None,
+    })
+}
+
+/// Recent ACME events, served by the status endpoint
+#[derive(Debug, Default)]
+pub(crate) struct AcmeStatus {
+    inner: Mutex<AcmeStatusInner>,
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub(crate) struct AcmeStatusInner {
+    /// `None` unless certificates come from ACME
+    directory: Option<String>,
+    events: VecDeque<AcmeStatusEvent>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct AcmeStatusEvent {
+    /// Unix time
+    time: u64,
+    ok: bool,
+    message: String,
+}
+
+impl AcmeStatus {
+    pub(crate) fn set_directory(&self, directory: String) {
+        self.inner.lock().unwrap().directory = Some(directory);
+    }
+
+    pub(crate) fn record(&self, ok: bool, message: String) {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        let mut inner = self.inner.lock().unwrap();
+        if inner.events.len() == STATUS_EVENTS {
+            inner.events.pop_front();
+        }
+        inner
+            .events
+            .push_back(AcmeStatusEvent { time, ok, message });
+    }
+
+    pub(crate) fn snapshot(&self) -> AcmeStatusInner {
+        self.inner.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use aws_lc_rs::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_FIXED};
+
+    use super::*;
+
+    const NEW_ACCOUNT: &str = "https://acme.example/acme/new-account";
+
+    fn decode(value: &Value) -> Vec<u8> {
+        BASE64_URL_SAFE_NO_PAD
+            .decode(value.as_str().unwrap())
+            .unwrap()
+    }
+
+    fn decode_json(value: &Value) -> Value {
+        serde_json::from_slice(&decode(value)).unwrap()
+    }
+
+    // The signing input of a flattened JWS
+    fn signing_input(jws: &Value) -> String {
+        format!(
+            "{}.{}",
+            jws["protected"].as_str().unwrap(),
+            jws["payload"].as_str().unwrap()
+        )
+    }
+
+    #[test]
+    fn jws_hs256() {
+        // Key of RFC 7515, appendix A.1
+        let eab = ExternalAccountBinding::new(
+            "kid",
+            "AyM1SysPpbyDfgZld3umj1qzKObwVMkoqQ-EstJQLr_T-1qS0gZH75aKtMN3Yj0iPS4hcgUuTwjAzZr1Z9CAow",
+        )
+        .unwrap();
+        let key = hmac::Key::new(hmac::HMAC_SHA256, &eab.hmac_key);
+
+        let jws = jws(
+            &json!({ "alg": "HS256" }),
+            &json!({ "iss": "joe" }),
+            |message| Ok(hmac::sign(&key, message).as_ref().to_vec()),
+        )
+        .unwrap();
+        assert_eq!(
+            jws,
+            json!({
+                "protected": "eyJhbGciOiJIUzI1NiJ9",
+                "payload": "eyJpc3MiOiJqb2UifQ",
+                "signature": "drNzgXhtBsgXhRXUJuUzfelbO_zNvlOmBQORTQiNiG8",
+            })
+        );
+    }
+
+    #[test]
+    fn new_account_with_eab() {
+        let eab = ExternalAccountBinding::new("kid-1", "c2VjcmV0LWhtYWMta2V5").unwrap();
+        let key_pair = Account::generate_key_pair();
+        let contact = vec!["mailto:admin@example.com".to_owned()];
+
+        let body = new_account_request(NEW_ACCOUNT, "nonce-1", &contact, &key_pair, &eab).unwrap();
+
+        let protected = decode_json(&body["protected"]);
+        assert_eq!(protected["alg"], "ES256");
+        assert_eq!(protected["nonce"], "nonce-1");
+        assert_eq!(protected["url"], NEW_ACCOUNT);
+        let jwk = &protected["jwk"];
+        assert_eq!(jwk["kty"], "EC");
+        assert_eq!(jwk["crv"], "P-256");
+
+        // The JWK is the account key, and it signed the request
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &key_pair).unwrap();
+        let public_key = [vec![4], decode(&jwk["x"]), decode(&jwk["y"])].concat();
+        assert_eq!(public_key, key.public_key().as_ref());
+        UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, &public_key)
+            .verify(signing_input(&body).as_bytes(), &decode(&body["signature"]))
+            .unwrap();
+
+        let payload = decode_json(&body["payload"]);
+        assert_eq!(payload["termsOfServiceAgreed"], true);
+        assert_eq!(payload["contact"], json!(contact));
+
+        // The binding is the JWK, signed with the EAB key (RFC 8555 section 7.3.4)
+        let binding = &payload["externalAccountBinding"];
+        assert_eq!(
+            decode_json(&binding["protected"]),
+            json!({ "alg": "HS256", "kid": "kid-1", "url": NEW_ACCOUNT })
+        );
+        assert_eq!(&decode_json(&binding["payload"]), jwk);
+        let hmac_key = hmac::Key::new(hmac::HMAC_SHA256, b"secret-hmac-key");
+        hmac::verify(
+            &hmac_key,
+            signing_input(binding).as_bytes(),
+            &decode(&binding["signature"]),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn eab_key_is_base64url() {
+        let eab = ExternalAccountBinding::new("kid", "c2VjcmV0LWhtYWMta2V5").unwrap();
+        assert_eq!(eab.hmac_key, b"secret-hmac-key");
+        // Some CAs hand out padded keys
+        let padded = ExternalAccountBinding::new("kid", "a2V5IQ==").unwrap();
+        assert_eq!(padded.hmac_key, b"key!");
+        assert!(ExternalAccountBinding::new("kid", "not base64!").is_err());
+    }
+
+    #[test]
+    fn known_default_ports() {
+        let port = |url: &str| port_or_known_default(&url.parse().unwrap());
+        assert_eq!(port("https://acme.example/dir"), Some(443));
+        assert_eq!(port("http://acme.example/dir"), Some(80));
+        assert_eq!(port("https://localhost:14000/dir"), Some(14000));
+        assert_eq!(port("ftp://acme.example/dir"), None);
+    }
+
+    #[tokio::test]
+    async fn post_jose_needs_https() {
+        let client_config = client_config(None).unwrap();
+        let err = post_jose(
+            &client_config,
+            "http://acme.example/new-acct",
+            "{}".to_owned(),
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("not https"), "{err}");
+    }
+}
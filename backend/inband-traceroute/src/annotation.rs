@@ -0,0 +1,271 @@
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use http::HeaderMap;
+use ipnet::Ipv6Net;
+use serde::Serialize;
+
+// Headers added by proxies between the client and us
+const PROXY_HEADERS: [&str; 3] = ["via", "forwarded", "x-forwarded-for"];
+
+// Well-known NAT64 prefix (RFC 6052), checked in addition to the configured ones
+const WELL_KNOWN_NAT64_PREFIX: (Ipv6Addr, u8) = (Ipv6Addr::new(0x64, 0xff9b, 0, 0, 0, 0, 0, 0), 96);
+
+/// Something about the traced connection that changes what the trace describes
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub(crate) enum Annotation {
+    /// The request came through an HTTP proxy, so the trace ends at the proxy rather than the
+    /// client
+    Proxy {
+        header: String,
+        value: String,
+        /// Client addresses the proxies reported, from `Forwarded` and `X-Forwarded-For`
+        clients: Vec<IpAddr>,
+    },
+    /// The address is in a NAT64 prefix, so the trace ends at the translator and the other side
+    /// is on IPv4
+    Nat64 { prefix: Ipv6Net, ipv4: Ipv4Addr },
+}
+
+impl Annotation {
+    /// Annotations for a traced connection to `remote`, with the request's headers if it was
+    /// traced through HTTP. `nat64_prefixes` are checked after the well-known prefix.
+    pub(crate) fn detect(
+        remote: SocketAddr,
+        headers: &HeaderMap,
+        nat64_prefixes: &[Ipv6Net],
+    ) -> Vec<Self> {
+        let mut annotations = Vec::new();
+
+        for header in PROXY_HEADERS {
+            for value in headers.get_all(header) {
+                let value = String::from_utf8_lossy(value.as_bytes()).into_owned();
+                let clients = match header {
+                    "forwarded" => forwarded_for(&value),
+                    "x-forwarded-for" => value
+                        .split(',')
+                        .filter_map(|addr| addr.trim().parse().ok())
+                        .collect(),
+                    _ => Vec::new(),
+                };
+                annotations.push(Self::Proxy {
+                    header: header.to_owned(),
+                    value,
+                    clients,
+                });
+            }
+        }
+
+        if let IpAddr::V6(addr) = remote.ip() {
+            let (well_known, len) = WELL_KNOWN_NAT64_PREFIX;
+            let well_known = Ipv6Net::new(well_known, len).expect("valid prefix length");
+            if let Some((prefix, ipv4)) = std::iter::once(&well_known)
+                .chain(nat64_prefixes)
+                .find_map(|prefix| Some((*prefix, embedded_ipv4(prefix, addr)?)))
+            {
+                annotations.push(Self::Nat64 { prefix, ipv4 });
+            }
+        }
+
+        annotations
+    }
+}
+
+impl fmt::Display for Annotation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Proxy { header, value, .. } => write!(
+                f,
+                "request came through a proxy ({header}: {value}), the trace ends at the proxy"
+            ),
+            Self::Nat64 { prefix, ipv4 } => write!(
+                f,
+                "address is in the NAT64 prefix {prefix}, the trace ends at the translator in \
+                 front of {ipv4}"
+            ),
+        }
+    }
+}
+
+/// Parse a NAT64 prefix, which RFC 6052 allows to be 32, 40, 48, 56, 64 or 96 bits long
+pub(crate) fn parse_nat64_prefix(prefix: &str) -> Result<Ipv6Net, String> {
+    let prefix: Ipv6Net = prefix.parse().map_err(|err| format!("{err}"))?;
+    if ![32, 40, 48, 56, 64, 96].contains(&prefix.prefix_len()) {
+        return Err("NAT64 prefixes must be 32, 40, 48, 56, 64 or 96 bits long".to_owned());
+    }
+    Ok(prefix.trunc())
+}
+
+// Addresses of the `for` parameters of a Forwarded header (RFC 7239), e.g.
+// `for=192.0.2.43, for="[2001:db8:cafe::17]:4711"`. Obfuscated and unknown ones are skipped.
+fn forwarded_for(value: &str) -> Vec<IpAddr> {
+    value
+        .split(',')
+        .flat_map(|element| element.split(';'))
+        .filter_map(|pair| {
+            let (key, node) = pair.split_once('=')?;
+            if !key.trim().eq_ignore_ascii_case("for") {
+                return None;
+            }
+            let node = node.trim().trim_matches('"');
+            if let Some(ipv6) = node.strip_prefix('[') {
+                return ipv6.split(']').next()?.parse().ok();
+            }
+            node.split(':').next()?.parse().ok()
+        })
+        .collect()
+}
+
+// The IPv4 address embedded in an IPv6 address of a NAT64 prefix (RFC 6052, section 2.2). Bits
+// 64 to 71 are always zero, so the address skips them.
+fn embedded_ipv4(prefix: &Ipv6Net, addr: Ipv6Addr) -> Option<Ipv4Addr> {
+    if !prefix.contains(&addr) {
+        return None;
+    }
+
+    let octets = addr.octets();
+    let start = prefix.prefix_len() as usize / 8;
+    let ipv4: Vec<u8> = (start..16)
+        .filter(|index| *index != 8)
+        .take(4)
+        .map(|index| octets[index])
+        .collect();
+    Some(Ipv4Addr::new(ipv4[0], ipv4[1], ipv4[2], ipv4[3]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ips(addrs: &[&str]) -> Vec<IpAddr> {
+        addrs.iter().map(|addr| addr.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn forwarded_for_forms() {
+        // Examples of RFC 7239, sections 4 and 7
+        for (value, expected) in [
+            ("for=192.0.2.43", ips(&["192.0.2.43"])),
+            (
+                "For=\"[2001:db8:cafe::17]:4711\"",
+                ips(&["2001:db8:cafe::17"]),
+            ),
+            ("for=\"[2001:db8:cafe::17]\"", ips(&["2001:db8:cafe::17"])),
+            ("for=\"192.0.2.43:47011\"", ips(&["192.0.2.43"])),
+            (
+                "for=192.0.2.60;proto=http;by=203.0.113.43",
+                ips(&["192.0.2.60"]),
+            ),
+            (
+                "for=192.0.2.43, for=198.51.100.17",
+                ips(&["192.0.2.43", "198.51.100.17"]),
+            ),
+            (
+                "proto=https;for=\"[2001:db8::1]:443\", for=192.0.2.1",
+                ips(&["2001:db8::1", "192.0.2.1"]),
+            ),
+        ] {
+            assert_eq!(forwarded_for(value), expected, "{value}");
+        }
+    }
+
+    #[test]
+    fn forwarded_for_skips_obfuscated_and_unknown() {
+        for value in [
+            "for=unknown",
+            "for=\"unknown:4711\"",
+            "for=_hidden",
+            "for=\"_gazonk\"",
+            "for=\"[_hidden]:1\"",
+            "by=203.0.113.43",
+            "",
+        ] {
+            assert!(forwarded_for(value).is_empty(), "{value}");
+        }
+        assert_eq!(
+            forwarded_for("for=_hidden, for=198.51.100.17"),
+            ips(&["198.51.100.17"])
+        );
+    }
+
+    #[test]
+    fn embedded_ipv4_of_each_prefix_length() {
+        // RFC 6052, section 2.4
+        let expected = Ipv4Addr::new(192, 0, 2, 33);
+        for (prefix, addr) in [
+            ("2001:db8::/32", "2001:db8:c000:221::"),
+            ("2001:db8:100::/40", "2001:db8:1c0:2:21::"),
+            ("2001:db8:122::/48", "2001:db8:122:c000:2:2100::"),
+            ("2001:db8:122:300::/56", "2001:db8:122:3c0:0:221::"),
+            ("2001:db8:122:344::/64", "2001:db8:122:344:c0:2:2100:0"),
+            ("2001:db8:122:344::/96", "2001:db8:122:344::192.0.2.33"),
+            ("64:ff9b::/96", "64:ff9b::192.0.2.33"),
+        ] {
+            let prefix = parse_nat64_prefix(prefix).unwrap();
+            assert_eq!(
+                embedded_ipv4(&prefix, addr.parse().unwrap()),
+                Some(expected),
+                "{prefix}"
+            );
+        }
+
+        let prefix = parse_nat64_prefix("2001:db8::/32").unwrap();
+        assert_eq!(embedded_ipv4(&prefix, "2001:db9::1".parse().unwrap()), None);
+    }
+
+    #[test]
+    fn nat64_prefixes() {
+        for len in [32, 40, 48, 56, 64, 96] {
+            let prefix = parse_nat64_prefix(&format!("2001:db8::/{len}")).unwrap();
+            assert_eq!(prefix.prefix_len(), len);
+        }
+        assert!(parse_nat64_prefix("2001:db8::/33").is_err());
+        assert!(parse_nat64_prefix("2001:db8::/128").is_err());
+        assert!(parse_nat64_prefix("192.0.2.0/24").is_err());
+        assert!(parse_nat64_prefix("2001:db8::").is_err());
+
+        // Host bits are dropped
+        assert_eq!(
+            parse_nat64_prefix("2001:db8:122:344::1/64").unwrap(),
+            "2001:db8:122:344::/64".parse().unwrap()
+        );
+    }
+
+    #[test]
+    fn detects_proxies_and_nat64() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-forwarded-for",
+            "198.51.100.17, 192.0.2.1".parse().unwrap(),
+        );
+        headers.insert("via", "1.1 proxy.example".parse().unwrap());
+        let remote = "[64:ff9b::c000:221]:443".parse().unwrap();
+
+        let annotations = Annotation::detect(remote, &headers, &[]);
+        assert_eq!(annotations.len(), 3);
+        assert!(matches!(
+            &annotations[0],
+            Annotation::Proxy { header, clients, .. } if header == "via" && clients.is_empty()
+        ));
+        assert!(matches!(
+            &annotations[1],
+            Annotation::Proxy { header, clients, .. }
+                if header == "x-forwarded-for" && *clients == ips(&["198.51.100.17", "192.0.2.1"])
+        ));
+        assert!(matches!(
+            annotations[2],
+            Annotation::Nat64 { ipv4, .. } if ipv4 == Ipv4Addr::new(192, 0, 2, 33)
+        ));
+
+        let configured = parse_nat64_prefix("2001:db8:122:344::/64").unwrap();
+        let remote = "[2001:db8:122:344:c0:2:2100:0]:443".parse().unwrap();
+        let annotations = Annotation::detect(remote, &HeaderMap::new(), &[configured]);
+        assert!(matches!(
+            annotations[..],
+            [Annotation::Nat64 { prefix, .. }] if prefix == configured
+        ));
+    }
+}
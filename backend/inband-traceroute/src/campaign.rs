@@ -0,0 +1,327 @@
+use std::{
+    fs::{self, OpenOptions},
+    future::Future,
+    io::Write,
+    num::{NonZeroU64, NonZeroUsize},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use futures::{Stream, StreamExt};
+use log::{info, warn};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{mpsc, Semaphore},
+    time::{self, MissedTickBehavior},
+};
+
+use crate::{
+    server::{AppState, OutboundTarget, TraceEvent},
+    tracer::TraceOptions,
+};
+
+/// Targets to trace periodically from this node, read from a JSON file
+#[derive(Debug, Deserialize)]
+pub(crate) struct CampaignConfig {
+    targets: Vec<CampaignTarget>,
+    /// Maximum number of traces running at once
+    #[serde(default = "default_max_concurrent")]
+    max_concurrent: NonZeroUsize,
+    /// Each run is delayed by a random amount up to this, so targets don't line up
+    #[serde(default)]
+    jitter_secs: u64,
+    /// Results are appended here, one JSON object per line
+    output: PathBuf,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct CampaignTarget {
+    #[serde(flatten)]
+    target: OutboundTarget,
+    interval_secs: NonZeroU64,
+    #[serde(default)]
+    options: TraceOptions,
+}
+
+/// One run of a campaign target
+#[derive(Debug, Serialize)]
+struct CampaignResult {
+    target: String,
+    /// Unix time the run started at
+    started: u64,
+    events: Vec<TraceEvent>,
+    error: Option<String>,
+}
+
+fn default_max_concurrent() -> NonZeroUsize {
+    NonZeroUsize::new(4).unwrap()
+}
+
+impl CampaignConfig {
+    pub(crate) fn load(path: &Path) -> anyhow::Result<Self> {
+        let config = fs::read(path)
+            .with_context(|| format!("Failed to read campaign config {}", path.display()))?;
+        serde_json::from_slice(&config)
+            .with_context(|| format!("Failed to parse campaign config {}", path.display()))
+    }
+}
+
+/// Start tracing each target on its interval, writing results in the background
+pub(crate) fn start_campaigns(config: CampaignConfig, state: Arc<AppState>) -> anyhow::Result<()> {
+    let results_tx = start_writer(&config.output)?;
+    schedule(&config, results_tx, move |target| {
+        let state = state.clone();
+        async move {
+            // Targets come from the operator's campaign file, so any address is fine
+            let (_, stream) = state
+                .outbound_trace_stream(target.target, target.options, &|_| true)
+                .await?;
+            anyhow::Ok(stream)
+        }
+    });
+
+    Ok(())
+}
+
+// Append the results sent to the returned channel to `path` in the background
+fn start_writer(path: &Path) -> anyhow::Result<mpsc::UnboundedSender<CampaignResult>> {
+    let mut output = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open {}", path.display()))?;
+
+    let (results_tx, mut results_rx) = mpsc::unbounded_channel();
+    tokio::task::spawn_blocking(move || {
+        while let Some(result) = results_rx.blocking_recv() {
+            let res = serde_json::to_string(&result)
+                .map_err(anyhow::Error::from)
+                .and_then(|line| Ok(writeln!(output, "{line}")?));
+            if let Err(err) = res {
+                warn!(
+                    "Failed to write campaign result for {}: {err:#}",
+                    result.target
+                );
+            }
+        }
+    });
+    Ok(results_tx)
+}
+
+// Run `trace` for each target on its interval and send the results
+fn schedule<F, Fut, S>(
+    config: &CampaignConfig,
+    results_tx: mpsc::UnboundedSender<CampaignResult>,
+    trace: F,
+) where
+    F: Fn(CampaignTarget) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = anyhow::Result<S>> + Send + 'static,
+    S: Stream<Item = anyhow::Result<TraceEvent>> + Send + 'static,
+{
+    let trace = Arc::new(trace);
+    let semaphore = Arc::new(Semaphore::new(config.max_concurrent.get()));
+    let jitter_ms = config.jitter_secs * 1000;
+
+    for target in config.targets.clone() {
+        info!(
+            "Tracing {} every {}s",
+            target.target.target, target.interval_secs
+        );
+
+        let trace = trace.clone();
+        let semaphore = semaphore.clone();
+        let results_tx = results_tx.clone();
+
+        tokio::spawn(async move {
+            let mut interval = time::interval(Duration::from_secs(target.interval_secs.get()));
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+            loop {
+                interval.tick().await;
+                let jitter = rand::thread_rng().gen_range(0..=jitter_ms);
+                time::sleep(Duration::from_millis(jitter)).await;
+
+                let _permit = semaphore
+                    .acquire()
+                    .await
+                    .expect("semaphore is never closed");
+                let result = run_once(&*trace, &target).await;
+                if results_tx.send(result).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+async fn run_once<F, Fut, S>(trace: &F, target: &CampaignTarget) -> CampaignResult
+where
+    F: Fn(CampaignTarget) -> Fut,
+    Fut: Future<Output = anyhow::Result<S>>,
+    S: Stream<Item = anyhow::Result<TraceEvent>>,
+{
+    let started = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let mut events = Vec::new();
+
+    let res = async {
+        let mut stream = Box::pin(trace(target.clone()).await?);
+        while let Some(event) = stream.next().await {
+            match event? {
+                TraceEvent::Padding(_) | TraceEvent::Done => {}
+                event => events.push(event),
+            }
+        }
+        anyhow::Ok(())
+    }
+    .await;
+
+    if let Err(err) = &res {
+        warn!("Campaign trace to {} failed: {err:#}", target.target.target);
+    }
+
+    CampaignResult {
+        target: target.target.target.clone(),
+        started,
+        events,
+        error: res.err().map(|err| format!("{err:#}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::outbound::OutboundConnection;
+
+    const CONFIG: &str = r#"{
+        "targets": [{"target": "example.com:443", "interval_secs": 10}],
+        "output": "/dev/null"
+    }"#;
+
+    fn reverse_dns(ttl: u8) -> TraceEvent {
+        TraceEvent::ReverseDns {
+            ttl,
+            ip: IpAddr::from([192, 0, 2, ttl]),
+            name: Ok(format!("hop{ttl}.example")),
+        }
+    }
+
+    #[test]
+    fn rejects_zero_interval() {
+        let config = CONFIG.replace("10", "0");
+        assert!(serde_json::from_str::<CampaignConfig>(&config).is_err());
+        assert!(serde_json::from_str::<CampaignConfig>(CONFIG).is_ok());
+    }
+
+    #[test]
+    fn rejects_zero_max_concurrent() {
+        let config = CONFIG.replace("\"output\"", "\"max_concurrent\": 0, \"output\"");
+        assert!(serde_json::from_str::<CampaignConfig>(&config).is_err());
+        let config = CONFIG.replace("\"output\"", "\"max_concurrent\": 1, \"output\"");
+        assert!(serde_json::from_str::<CampaignConfig>(&config).is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn traces_targets_on_interval() {
+        let config: CampaignConfig = serde_json::from_str(CONFIG).unwrap();
+        let (results_tx, mut results_rx) = mpsc::unbounded_channel();
+        schedule(&config, results_tx, |target| async move {
+            assert_eq!(target.target.target, "example.com:443");
+            let events = [
+                Ok(reverse_dns(1)),
+                Ok(TraceEvent::Padding(100)),
+                Ok(reverse_dns(2)),
+                Ok(TraceEvent::Done),
+            ];
+            anyhow::Ok(futures::stream::iter(events))
+        });
+
+        let start = time::Instant::now();
+        for run in 0..3 {
+            let result = results_rx.recv().await.unwrap();
+            assert_eq!(start.elapsed(), Duration::from_secs(10 * run));
+            assert_eq!(result.target, "example.com:443");
+            assert!(result.error.is_none());
+            // Padding and Done aren't recorded
+            let ttls: Vec<_> = result
+                .events
+                .iter()
+                .map(|event| match event {
+                    TraceEvent::ReverseDns { ttl, .. } => *ttl,
+                    event => panic!("unexpected event {event:?}"),
+                })
+                .collect();
+            assert_eq!(ttls, [1, 2]);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn records_failed_runs() {
+        let config: CampaignConfig = serde_json::from_str(CONFIG).unwrap();
+        let (results_tx, mut results_rx) = mpsc::unbounded_channel();
+        schedule(&config, results_tx, |_| async move {
+            let events = [Ok(reverse_dns(1)), Err(anyhow::anyhow!("connection reset"))];
+            anyhow::Ok(futures::stream::iter(events))
+        });
+
+        let result = results_rx.recv().await.unwrap();
+        assert_eq!(result.events.len(), 1);
+        assert_eq!(result.error.as_deref(), Some("connection reset"));
+    }
+
+    #[tokio::test]
+    async fn writes_results_of_local_target() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+        let output = std::env::temp_dir().join(format!("campaign-{}.jsonl", target.port()));
+        let _ = fs::remove_file(&output);
+
+        let config: CampaignConfig = serde_json::from_value(serde_json::json!({
+            "targets": [{"target": target.to_string(), "interval_secs": 3600}],
+            "output": output,
+        }))
+        .unwrap();
+        let results_tx = start_writer(&config.output).unwrap();
+        // Everything but the eBPF trace itself: connect to the target and read the socket
+        schedule(&config, results_tx, |target| async move {
+            let addr = target.target.target.parse()?;
+            let outbound = OutboundConnection::connect(None, addr, None).await?;
+            let stats = outbound.connection.stats()?;
+            anyhow::Ok(futures::stream::iter([
+                Ok(TraceEvent::ConnectionStats(stats)),
+                Ok(TraceEvent::Done),
+            ]))
+        });
+
+        let (_stream, client) = listener.accept().await.unwrap();
+        let line = time::timeout(Duration::from_secs(10), async {
+            loop {
+                let contents = fs::read_to_string(&output).unwrap_or_default();
+                if let Some(line) = contents.lines().next() {
+                    break line.to_owned();
+                }
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("no result written");
+        fs::remove_file(&output).unwrap();
+
+        let result: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(result["target"], target.to_string());
+        assert!(result["error"].is_null());
+        let events = result["events"].as_array().unwrap();
+        assert_eq!(events.len(), 1);
+        assert!(events[0]["ConnectionStats"]["rtt"].is_u64());
+        assert!(client.ip().is_loopback());
+    }
+}
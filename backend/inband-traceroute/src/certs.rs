@@ -0,0 +1,148 @@
+use std::{
+    fs::{self, File},
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+use axum_server::tls_rustls::RustlsConfig;
+use log::{error, info};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_rustls::rustls::ServerConfig;
+
+// How often the certificate files are checked for changes
+const WATCH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Load a TLS server config from PEM certificate chain and private key files
+pub(crate) fn load_server_config(
+    cert_path: &Path,
+    key_path: &Path,
+    alpn_protocols: Vec<Vec<u8>>,
+) -> anyhow::Result<ServerConfig> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(
+        File::open(cert_path).with_context(|| format!("Failed to open {}", cert_path.display()))?,
+    ))
+    .collect::<Result<Vec<_>, _>>()
+    .with_context(|| format!("Failed to parse certificates in {}", cert_path.display()))?;
+
+    let key = rustls_pemfile::private_key(&mut BufReader::new(
+        File::open(key_path).with_context(|| format!("Failed to open {}", key_path.display()))?,
+    ))
+    .with_context(|| format!("Failed to parse private key in {}", key_path.display()))?
+    .with_context(|| format!("No private key in {}", key_path.display()))?;
+
+    let mut config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("Invalid certificate or key")?;
+    config.alpn_protocols = alpn_protocols;
+
+    Ok(config)
+}
+
+/// Reload the certificate and key into `config` on SIGHUP or when either file changes. Failed
+/// reloads are logged and the previous certificate stays in use.
+pub(crate) fn watch(
+    config: RustlsConfig,
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    alpn_protocols: Vec<Vec<u8>>,
+) -> anyhow::Result<()> {
+    let mut hangup = signal(SignalKind::hangup()).context("Failed to listen for SIGHUP")?;
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(WATCH_INTERVAL);
+        let mut last_modified = modified(&cert_path, &key_path);
+
+        loop {
+            tokio::select! {
+                _ = hangup.recv() => info!("Received SIGHUP, reloading TLS certificate"),
+                _ = interval.tick() => {
+                    let current = modified(&cert_path, &key_path);
+                    if current == last_modified {
+                        continue;
+                    }
+                    info!("TLS certificate files changed, reloading");
+                }
+            }
+            last_modified = modified(&cert_path, &key_path);
+
+            match load_server_config(&cert_path, &key_path, alpn_protocols.clone()) {
+                Ok(server_config) => config.reload_from_config(Arc::new(server_config)),
+                Err(err) => error!("Failed to reload TLS certificate: {err:#}"),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+fn modified(cert_path: &Path, key_path: &Path) -> Option<(SystemTime, SystemTime)> {
+    let cert = fs::metadata(cert_path)
+        .and_then(|meta| meta.modified())
+        .ok()?;
+    let key = fs::metadata(key_path)
+        .and_then(|meta| meta.modified())
+        .ok()?;
+    Some((cert, key))
+}
+
+#[cfg(test)]
+mod tests {
+    use rcgen::CertifiedKey;
+
+    use super::*;
+
+    // A file with `contents` in the temporary directory, unique to the calling test
+    fn temp_file(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("certs-{}-{name}", std::process::id()));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn generate() -> CertifiedKey {
+        rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap()
+    }
+
+    #[test]
+    fn loads_generated_pair() {
+        let CertifiedKey { cert, key_pair } = generate();
+        let cert_path = temp_file("pair.crt", &cert.pem());
+        let key_path = temp_file("pair.key", &key_pair.serialize_pem());
+
+        let config = load_server_config(&cert_path, &key_path, vec![b"h2".to_vec()]);
+        fs::remove_file(cert_path).unwrap();
+        fs::remove_file(key_path).unwrap();
+        assert_eq!(config.unwrap().alpn_protocols, [b"h2".to_vec()]);
+    }
+
+    #[test]
+    fn rejects_mismatched_key() {
+        let cert_path = temp_file("mismatched.crt", &generate().cert.pem());
+        let key_path = temp_file("mismatched.key", &generate().key_pair.serialize_pem());
+
+        let err = load_server_config(&cert_path, &key_path, Vec::new()).unwrap_err();
+        fs::remove_file(cert_path).unwrap();
+        fs::remove_file(key_path).unwrap();
+        assert!(
+            format!("{err:#}").starts_with("Invalid certificate or key"),
+            "{err:#}"
+        );
+    }
+
+    #[test]
+    fn rejects_empty_key_file() {
+        let cert_path = temp_file("empty.crt", &generate().cert.pem());
+        let key_path = temp_file("empty.key", "");
+
+        let err = load_server_config(&cert_path, &key_path, Vec::new()).unwrap_err();
+        fs::remove_file(cert_path).unwrap();
+        fs::remove_file(key_path).unwrap();
+        assert!(
+            format!("{err:#}").starts_with("No private key in"),
+            "{err:#}"
+        );
+    }
+}
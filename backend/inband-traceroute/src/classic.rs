@@ -0,0 +1,228 @@
+use std::net::IpAddr;
+
+use serde::Serialize;
+
+use crate::hop::{Hop, HopType};
+
+/// Probe type of a classic traceroute
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub(crate) enum ClassicMethod {
+    IcmpEcho,
+    Udp,
+}
+
+impl ClassicMethod {
+    pub(crate) const ALL: [ClassicMethod; 2] = [ClassicMethod::IcmpEcho, ClassicMethod::Udp];
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub(crate) enum ClassicReply {
+    TimeExceeded,
+    /// Destination Unreachable from a router, usually a filter
+    Unreachable,
+    /// Echo Reply or Port Unreachable from the client
+    Destination,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct ClassicHop {
+    pub(crate) ttl: u8,
+    pub(crate) reply: Option<ClassicReply>,
+    pub(crate) addr: Option<IpAddr>,
+    pub(crate) rtt: Option<u64>,
+}
+
+/// A classic traceroute to the client, compared with the inband trace of the same connection
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct ClassicTrace {
+    pub(crate) method: ClassicMethod,
+    pub(crate) hops: Vec<ClassicHop>,
+    pub(crate) reached: bool,
+    /// First TTL after the last reply, if the inband trace saw hops there or reached the client
+    pub(crate) filtered_at: Option<u8>,
+    /// TTLs at which both traces got a reply from different routers
+    pub(crate) divergent_ttls: Vec<u8>,
+}
+
+impl ClassicTrace {
+    pub(crate) fn new(method: ClassicMethod, hops: Vec<ClassicHop>, inband: &[Hop]) -> Self {
+        let reached = hops
+            .iter()
+            .any(|hop| hop.reply == Some(ClassicReply::Destination));
+
+        let last_reply = hops
+            .iter()
+            .filter(|hop| hop.reply.is_some())
+            .map(|hop| hop.ttl)
+            .max()
+            .unwrap_or(0);
+        let inband_beyond = inband.iter().any(|hop| {
+            hop.ttl > last_reply && !matches!(hop.hop_type, HopType::Timeout | HopType::Origin)
+        });
+        let filtered_at = (!reached && inband_beyond).then_some(last_reply + 1);
+
+        let divergent_ttls = hops
+            .iter()
+            .filter(|hop| hop.reply == Some(ClassicReply::TimeExceeded))
+            .filter(|hop| {
+                inband.iter().any(|inband_hop| {
+                    inband_hop.ttl == hop.ttl
+                        && matches!(inband_hop.hop_type, HopType::IcmpTimeExceeded)
+                        && inband_hop.addr != hop.addr
+                })
+            })
+            .map(|hop| hop.ttl)
+            .collect();
+
+        Self {
+            method,
+            hops,
+            reached,
+            filtered_at,
+            divergent_ttls,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A: &str = "192.0.2.1";
+    const B: &str = "192.0.2.2";
+    const CLIENT: &str = "198.51.100.7";
+
+    fn classic(hops: &[(u8, Option<ClassicReply>, &str)]) -> Vec<ClassicHop> {
+        hops.iter()
+            .map(|&(ttl, reply, addr)| ClassicHop {
+                ttl,
+                reply,
+                addr: reply.map(|_| addr.parse().unwrap()),
+                rtt: reply.map(|_| 1_000_000),
+            })
+            .collect()
+    }
+
+    fn inband(hops: &[(u8, HopType, &str)]) -> Vec<Hop> {
+        hops.iter()
+            .map(|&(ttl, hop_type, addr)| Hop {
+                ttl,
+                hop_type,
+                addr: (!matches!(hop_type, HopType::Timeout)).then(|| addr.parse().unwrap()),
+                rtt: None,
+                enriched_info: None,
+                probe: None,
+                tos: None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn filtered_at_first_ttl_without_reply() {
+        use ClassicReply::*;
+        use HopType::*;
+
+        for (name, classic_hops, inband_hops, reached, filtered_at) in [
+            (
+                "both reach the client",
+                classic(&[(1, Some(TimeExceeded), A), (2, Some(Destination), CLIENT)]),
+                inband(&[(1, IcmpTimeExceeded, A), (2, TcpAck, CLIENT)]),
+                true,
+                None,
+            ),
+            (
+                "classic reaches the client, inband doesn't",
+                classic(&[(1, Some(TimeExceeded), A), (2, Some(Destination), CLIENT)]),
+                inband(&[(1, IcmpTimeExceeded, A), (2, Timeout, "")]),
+                true,
+                None,
+            ),
+            (
+                "classic stops, inband gets further",
+                classic(&[(1, Some(TimeExceeded), A), (2, None, ""), (3, None, "")]),
+                inband(&[
+                    (1, IcmpTimeExceeded, A),
+                    (2, IcmpTimeExceeded, B),
+                    (3, TcpRst, CLIENT),
+                ]),
+                false,
+                Some(2),
+            ),
+            (
+                "classic stops at a filter, inband reaches the client",
+                classic(&[
+                    (1, Some(TimeExceeded), A),
+                    (2, Some(Unreachable), B),
+                    (3, None, ""),
+                ]),
+                inband(&[
+                    (1, IcmpTimeExceeded, A),
+                    (2, IcmpTimeExceeded, B),
+                    (3, TcpAck, CLIENT),
+                ]),
+                false,
+                Some(3),
+            ),
+            (
+                "no classic reply at all",
+                classic(&[(1, None, ""), (2, None, "")]),
+                inband(&[(1, IcmpTimeExceeded, A), (2, TcpAck, CLIENT)]),
+                false,
+                Some(1),
+            ),
+            (
+                "both stop at the same hop",
+                classic(&[(1, Some(TimeExceeded), A), (2, None, "")]),
+                inband(&[(0, Origin, B), (1, IcmpTimeExceeded, A), (2, Timeout, "")]),
+                false,
+                None,
+            ),
+        ] {
+            let trace = ClassicTrace::new(ClassicMethod::Udp, classic_hops, &inband_hops);
+            assert_eq!(trace.reached, reached, "{name}");
+            assert_eq!(trace.filtered_at, filtered_at, "{name}");
+        }
+    }
+
+    #[test]
+    fn divergent_ttls_need_time_exceeded_from_both() {
+        use ClassicReply::*;
+        use HopType::*;
+
+        for (name, classic_hops, inband_hops, divergent) in [
+            (
+                "same routers",
+                classic(&[(1, Some(TimeExceeded), A), (2, Some(TimeExceeded), B)]),
+                inband(&[(1, IcmpTimeExceeded, A), (2, IcmpTimeExceeded, B)]),
+                vec![],
+            ),
+            (
+                "different routers",
+                classic(&[(1, Some(TimeExceeded), A), (2, Some(TimeExceeded), A)]),
+                inband(&[(1, IcmpTimeExceeded, B), (2, IcmpTimeExceeded, A)]),
+                vec![1],
+            ),
+            (
+                "inband timeout",
+                classic(&[(1, Some(TimeExceeded), A)]),
+                inband(&[(1, Timeout, "")]),
+                vec![],
+            ),
+            (
+                "classic unreachable",
+                classic(&[(1, Some(Unreachable), A)]),
+                inband(&[(1, IcmpTimeExceeded, B)]),
+                vec![],
+            ),
+            (
+                "inband reached the client",
+                classic(&[(1, Some(TimeExceeded), A), (2, Some(TimeExceeded), B)]),
+                inband(&[(1, IcmpTimeExceeded, A), (2, TcpAck, CLIENT)]),
+                vec![],
+            ),
+        ] {
+            let trace = ClassicTrace::new(ClassicMethod::IcmpEcho, classic_hops, &inband_hops);
+            assert_eq!(trace.divergent_ttls, divergent, "{name}");
+        }
+    }
+}
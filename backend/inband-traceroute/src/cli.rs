@@ -0,0 +1,107 @@
+use std::{net::IpAddr, sync::Arc};
+
+use anyhow::Context;
+use clap::Args;
+use futures::StreamExt;
+use inband_traceroute_common::EbpfConfig;
+use maxminddb::Reader;
+
+use crate::{
+    dns::ReverseDnsProvider,
+    ebpf::{self, start_event_processor},
+    outbound::{self, OutboundConnection},
+    tracer::{TraceHandle, TraceOptions, Tracer},
+    Opt,
+};
+
+#[derive(Debug, Args)]
+pub(crate) struct TraceArgs {
+    /// host:port to connect to
+    target: String,
+
+    /// Print one JSON object per hop instead of a table
+    #[arg(long)]
+    json: bool,
+
+    /// Complete a TLS handshake before tracing
+    #[arg(long)]
+    tls: bool,
+
+    /// Server name for TLS, defaults to the host part of the target
+    #[arg(long)]
+    sni: Option<String>,
+}
+
+/// Connect to the target, trace the connection and print the hops
+pub(crate) async fn run_trace(
+    opt: &Opt,
+    args: &TraceArgs,
+    ipdb: &'static Reader<Vec<u8>>,
+    dns_client: Arc<ReverseDnsProvider>,
+) -> anyhow::Result<()> {
+    let host = outbound::target_host(&args.target)?;
+    let target = tokio::net::lookup_host(&args.target)
+        .await
+        .with_context(|| format!("Failed to resolve {}", args.target))?
+        .next()
+        .with_context(|| format!("No address for {}", args.target))?;
+
+    // Without --ipv4/--ipv6, use the source address the kernel would pick
+    let local_ip = match target.ip() {
+        IpAddr::V4(_) => opt.ipv4.map(IpAddr::V4),
+        IpAddr::V6(_) => opt.ipv6.map(IpAddr::V6),
+    };
+    let local_ip = match local_ip {
+        Some(local_ip) => local_ip,
+        None => outbound::source_addr(target)?,
+    };
+
+    let local_addr = match local_ip {
+        IpAddr::V4(ipv4) => inband_traceroute_common::IPAddr::new_v4(ipv4.octets()),
+        IpAddr::V6(ipv6) => inband_traceroute_common::IPAddr::new_v6(ipv6.octets()),
+    };
+    // There is no listening port
+    let ebpf_config = match local_ip {
+        IpAddr::V4(_) => EbpfConfig::new(0, Some(local_addr), None),
+        IpAddr::V6(_) => EbpfConfig::new(0, None, Some(local_addr)),
+    };
+
+    // Loaded before connecting, so the connection's sequence numbers are seen from the handshake
+    // on. Note: program will be detached when dropped
+    let (mut ebpf, maps) =
+        ebpf::setup_ebpf(&opt.iface, &ebpf_config).context("EBPF setup failed")?;
+
+    let server_name = args.tls.then(|| args.sni.as_deref().unwrap_or(host));
+    let outbound = OutboundConnection::connect(Some(local_ip), target, server_name).await?;
+    let local = outbound.connection.local;
+
+    let tracer = Arc::new(
+        Tracer::new(local, opt.max_hops, maps, ipdb, dns_client)
+            .context("failed to create tracer")?,
+    );
+
+    match local.ip() {
+        IpAddr::V4(_) => start_event_processor(&mut ebpf, Some(tracer.clone()), None)?,
+        IpAddr::V6(_) => start_event_processor(&mut ebpf, None, Some(tracer.clone()))?,
+    }
+
+    let trace_handle =
+        TraceHandle::start_trace(tracer, outbound.connection.clone(), TraceOptions::default())
+            .await?;
+
+    if !args.json {
+        println!("inband trace from {local} to {target} ({})", args.target);
+    }
+
+    let mut hop_stream = Box::pin(trace_handle.hop_stream().await?);
+    while let Some(hop) = hop_stream.next().await {
+        let hop = hop?;
+        if args.json {
+            println!("{}", serde_json::to_string(&hop)?);
+        } else {
+            println!("{hop}");
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,350 @@
+use std::{
+    io, mem,
+    net::SocketAddr,
+    os::fd::{AsFd, AsRawFd, OwnedFd},
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+use axum_server::accept::Accept;
+use futures::future::BoxFuture;
+use libc::{c_void, socklen_t};
+use rustls_acme::futures_rustls;
+use serde::Serialize;
+use tokio::{
+    io::Interest,
+    net::TcpStream,
+    time::{timeout_at, Instant},
+};
+use tokio_rustls::rustls::ServerConnection;
+use tokio_util::compat::Compat;
+use tower_http::add_extension::AddExtension;
+
+use crate::ja4::{ClientHello, ClientHelloInfo, ParseError};
+
+// Longer ClientHellos aren't fingerprinted
+const MAX_CLIENT_HELLO_LEN: usize = 16 * 1024;
+
+// Same as the TLS acceptors' handshake timeout. Peeking at the ClientHello counts towards it.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// struct tcp_info from linux/tcp.h up to tcpi_bytes_retrans. libc's copy stops at
+// tcpi_total_retrans, and older kernels fill in less than this.
+#[repr(C)]
+#[derive(Default)]
+struct TcpInfo {
+    state: u8,
+    ca_state: u8,
+    retransmits: u8,
+    probes: u8,
+    backoff: u8,
+    options: u8,
+    wscale: u8,
+    app_limited: u8,
+    rto: u32,
+    ato: u32,
+    snd_mss: u32,
+    rcv_mss: u32,
+    unacked: u32,
+    sacked: u32,
+    lost: u32,
+    retrans: u32,
+    fackets: u32,
+    last_data_sent: u32,
+    last_ack_sent: u32,
+    last_data_recv: u32,
+    last_ack_recv: u32,
+    pmtu: u32,
+    rcv_ssthresh: u32,
+    rtt: u32,
+    rttvar: u32,
+    snd_ssthresh: u32,
+    snd_cwnd: u32,
+    advmss: u32,
+    reordering: u32,
+    rcv_rtt: u32,
+    rcv_space: u32,
+    total_retrans: u32,
+    pacing_rate: u64,
+    max_pacing_rate: u64,
+    bytes_acked: u64,
+    bytes_received: u64,
+    segs_out: u32,
+    segs_in: u32,
+    notsent_bytes: u32,
+    min_rtt: u32,
+    data_segs_in: u32,
+    data_segs_out: u32,
+    delivery_rate: u64,
+    busy_time: u64,
+    rwnd_limited: u64,
+    sndbuf_limited: u64,
+    delivered: u32,
+    delivered_ce: u32,
+    bytes_sent: u64,
+    bytes_retrans: u64,
+}
+
+/// An accepted TCP connection, available to handlers as a request extension
+#[derive(Debug)]
+pub(crate) struct Connection {
+    pub(crate) local: SocketAddr,
+    pub(crate) remote: SocketAddr,
+    /// What the client offered, if its ClientHello could be read before the handshake
+    pub(crate) client_hello: Option<ClientHelloInfo>,
+    /// Set once the TLS handshake completes, if the connection uses TLS
+    pub(crate) tls: OnceLock<TlsInfo>,
+    // A duplicate of the server's socket, so it can't be closed and reused while we hold it
+    socket: OwnedFd,
+}
+
+/// What the TLS handshake negotiated
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct TlsInfo {
+    pub(crate) version: Option<String>,
+    pub(crate) cipher_suite: Option<String>,
+    pub(crate) alpn: Option<String>,
+    /// Server name the client asked for
+    pub(crate) sni: Option<String>,
+}
+
+impl TlsInfo {
+    fn new(tls: &ServerConnection) -> Self {
+        Self {
+            // e.g. "TLSv1_3" to "TLSv1.3"
+            version: tls
+                .protocol_version()
+                .map(|version| match version.as_str() {
+                    Some(name) => name.replace('_', "."),
+                    None => format!("{version:?}"),
+                }),
+            cipher_suite: tls.negotiated_cipher_suite().map(|suite| {
+                let suite = suite.suite();
+                suite
+                    .as_str()
+                    .map_or_else(|| format!("{suite:?}"), str::to_owned)
+            }),
+            alpn: tls
+                .alpn_protocol()
+                .map(|alpn| String::from_utf8_lossy(alpn).into_owned()),
+            sni: tls.server_name().map(str::to_owned),
+        }
+    }
+}
+
+/// Streams produced by the acceptors we serve with, which may have completed a TLS handshake
+pub(crate) trait NegotiatedTls {
+    /// Whether the client starts with a ClientHello
+    const TLS: bool = true;
+
+    fn tls_info(&self) -> Option<TlsInfo>;
+}
+
+impl NegotiatedTls for TcpStream {
+    const TLS: bool = false;
+
+    fn tls_info(&self) -> Option<TlsInfo> {
+        None
+    }
+}
+
+impl NegotiatedTls for tokio_rustls::server::TlsStream<TcpStream> {
+    fn tls_info(&self) -> Option<TlsInfo> {
+        Some(TlsInfo::new(self.get_ref().1))
+    }
+}
+
+// rustls-acme's axum acceptor
+impl NegotiatedTls for Compat<futures_rustls::server::TlsStream<Compat<TcpStream>>> {
+    fn tls_info(&self) -> Option<TlsInfo> {
+        Some(TlsInfo::new(self.get_ref().get_ref().1))
+    }
+}
+
+/// The kernel's view of a connection, from TCP_INFO. Times are in nanoseconds like hop RTTs.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct ConnectionStats {
+    /// Smoothed RTT
+    pub(crate) rtt: u64,
+    pub(crate) rtt_var: u64,
+    pub(crate) min_rtt: Option<u64>,
+    /// RTT of the final ACK hop, if the trace has reached the client yet
+    pub(crate) path_rtt: Option<u64>,
+    pub(crate) rto: u64,
+    /// Segments retransmitted over the connection's lifetime
+    pub(crate) retransmits: u32,
+    pub(crate) lost: u32,
+    pub(crate) unacked: u32,
+    /// Congestion window in segments
+    pub(crate) snd_cwnd: u32,
+    pub(crate) snd_ssthresh: u32,
+    pub(crate) snd_mss: u32,
+    pub(crate) rcv_mss: u32,
+    pub(crate) pmtu: u32,
+    /// Bytes per second
+    pub(crate) delivery_rate: Option<u64>,
+    pub(crate) pacing_rate: Option<u64>,
+    pub(crate) bytes_acked: Option<u64>,
+    pub(crate) bytes_received: Option<u64>,
+    pub(crate) bytes_retrans: Option<u64>,
+}
+
+impl ConnectionStats {
+    fn new(info: &TcpInfo, len: usize) -> Self {
+        // Fields the kernel didn't fill in are left out rather than reported as zero. A field is
+        // filled in if the kernel's struct reaches the end of it.
+        let has = |end: usize| len >= end;
+        let micros = |us: u32| us as u64 * 1000;
+        Self {
+            rtt: micros(info.rtt),
+            rtt_var: micros(info.rttvar),
+            // ~0 until there is an RTT sample
+            min_rtt: (has(mem::offset_of!(TcpInfo, data_segs_in)) && info.min_rtt != u32::MAX)
+                .then(|| micros(info.min_rtt)),
+            path_rtt: None,
+            rto: micros(info.rto),
+            retransmits: info.total_retrans,
+            lost: info.lost,
+            unacked: info.unacked,
+            snd_cwnd: info.snd_cwnd,
+            snd_ssthresh: info.snd_ssthresh,
+            snd_mss: info.snd_mss,
+            rcv_mss: info.rcv_mss,
+            pmtu: info.pmtu,
+            delivery_rate: has(mem::offset_of!(TcpInfo, busy_time)).then_some(info.delivery_rate),
+            pacing_rate: has(mem::offset_of!(TcpInfo, max_pacing_rate)).then_some(info.pacing_rate),
+            bytes_acked: has(mem::offset_of!(TcpInfo, bytes_received)).then_some(info.bytes_acked),
+            bytes_received: has(mem::offset_of!(TcpInfo, segs_out)).then_some(info.bytes_received),
+            bytes_retrans: has(mem::size_of::<TcpInfo>()).then_some(info.bytes_retrans),
+        }
+    }
+}
+
+impl Connection {
+    pub(crate) fn new(stream: &TcpStream) -> io::Result<Self> {
+        Ok(Self {
+            local: stream.local_addr()?,
+            remote: stream.peer_addr()?,
+            client_hello: None,
+            tls: OnceLock::new(),
+            socket: stream.as_fd().try_clone_to_owned()?,
+        })
+    }
+
+    /// Read TCP_INFO from the socket
+    pub(crate) fn stats(&self) -> io::Result<ConnectionStats> {
+        let mut info = TcpInfo::default();
+        let mut len = mem::size_of::<TcpInfo>() as socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                self.socket.as_raw_fd(),
+                libc::IPPROTO_TCP,
+                libc::TCP_INFO,
+                &mut info as *mut TcpInfo as *mut c_void,
+                &mut len,
+            )
+        };
+
+        if ret == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(ConnectionStats::new(&info, len as usize))
+    }
+}
+
+/// Read the ClientHello without consuming it, so the TLS acceptor still gets it
+async fn peek_client_hello(stream: &TcpStream, deadline: Instant) -> Option<ClientHelloInfo> {
+    let mut buf = vec![0; MAX_CLIENT_HELLO_LEN];
+    let mut peeked = 0;
+    let hello = async {
+        loop {
+            let ready = stream.ready(Interest::READABLE).await.ok()?;
+            // Peeking leaves the stream readable, so a peek without new data clears the readiness
+            // to wait for the rest of the ClientHello
+            let res = stream.try_io(Interest::READABLE, || {
+                let len = peek(stream, &mut buf)?;
+                if len == peeked {
+                    return Err(io::ErrorKind::WouldBlock.into());
+                }
+                Ok(len)
+            });
+            match res {
+                Ok(len) => {
+                    peeked = len;
+                    match ClientHello::parse(&buf[..len]) {
+                        Ok(hello) => return Some(hello.info()),
+                        Err(ParseError::Incomplete) if len < buf.len() => {}
+                        Err(_) => return None,
+                    }
+                }
+                // No more data is coming after a FIN
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock && !ready.is_read_closed() => {}
+                Err(_) => return None,
+            }
+        }
+    };
+    timeout_at(deadline, hello).await.ok().flatten()
+}
+
+// Peek without waiting, the tokio stream only peeks once it's readable
+fn peek(stream: &TcpStream, buf: &mut [u8]) -> io::Result<usize> {
+    let ret = unsafe {
+        libc::recv(
+            stream.as_raw_fd(),
+            buf.as_mut_ptr() as *mut c_void,
+            buf.len(),
+            libc::MSG_PEEK | libc::MSG_DONTWAIT,
+        )
+    };
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ret as usize)
+}
+
+/// Wraps another acceptor, recording each accepted connection and its TLS parameters as a request
+/// extension
+#[derive(Debug, Clone)]
+pub(crate) struct ConnectionAcceptor<A> {
+    inner: A,
+}
+
+impl<A> ConnectionAcceptor<A> {
+    pub(crate) fn new(inner: A) -> Self {
+        Self { inner }
+    }
+}
+
+impl<A, S> Accept<TcpStream, S> for ConnectionAcceptor<A>
+where
+    A: Accept<TcpStream, AddExtension<S, Arc<Connection>>> + Clone + Send + 'static,
+    A::Future: Send,
+    A::Stream: NegotiatedTls,
+    S: Send + 'static,
+{
+    type Stream = A::Stream;
+    type Service = A::Service;
+    type Future = BoxFuture<'static, io::Result<(Self::Stream, Self::Service)>>;
+
+    fn accept(&self, stream: TcpStream, service: S) -> Self::Future {
+        let inner = self.inner.clone();
+
+        Box::pin(async move {
+            // One budget for peeking and the handshake, so peeking doesn't extend it
+            let deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+            let mut connection = Connection::new(&stream)?;
+            if <A::Stream as NegotiatedTls>::TLS {
+                connection.client_hello = peek_client_hello(&stream, deadline).await;
+            }
+            let connection = Arc::new(connection);
+            let accept = inner.accept(stream, AddExtension::new(service, connection.clone()));
+            let (stream, service) = timeout_at(deadline, accept).await.map_err(|_| {
+                io::Error::new(io::ErrorKind::TimedOut, "TLS handshake timed out")
+            })??;
+            if let Some(tls) = stream.tls_info() {
+                let _ = connection.tls.set(tls);
+            }
+            Ok((stream, service))
+        })
+    }
+}
@@ -1,26 +1,43 @@
 use core::panic;
-use std::sync::Arc;
+use std::{io, sync::Arc};
 
 use anyhow::Context;
 use aya::{
     maps::{Array, AsyncPerfEventArray, HashMap, MapData},
-    programs::{Xdp, XdpFlags},
+    programs::{tc, SchedClassifier, TcAttachType, Xdp, XdpFlags},
     util::online_cpus,
 };
 use bytes::BytesMut;
-use inband_traceroute_common::{EbpfConfig, TraceEvent};
+use inband_traceroute_common::{
+    ConnectionKey, ConnectionSequence, EbpfConfig, InitialSequence, SynFingerprint, TraceEntry,
+    TraceEvent,
+};
 use log::{debug, warn};
-use tokio::task;
+use tokio::{sync::Mutex, task};
 
 use crate::tracer::Tracer;
 
 pub(crate) type EventMap = AsyncPerfEventArray<MapData>;
-pub(crate) type TraceMap = HashMap<MapData, inband_traceroute_common::SocketAddr, u32>;
+pub(crate) type TraceMap = HashMap<MapData, ConnectionKey, TraceEntry>;
+pub(crate) type SynMap = HashMap<MapData, ConnectionKey, SynFingerprint>;
+pub(crate) type SequenceMap = HashMap<MapData, ConnectionKey, ConnectionSequence>;
+pub(crate) type HandshakeMap = HashMap<MapData, ConnectionKey, InitialSequence>;
+pub(crate) type ClassicTraceMap = HashMap<MapData, inband_traceroute_common::SocketAddr, u32>;
+
+/// The maps the tracers share with the eBPF programs
+#[derive(Debug, Clone)]
+pub(crate) struct EbpfMaps {
+    pub(crate) traces: Arc<Mutex<TraceMap>>,
+    pub(crate) classic_traces: Arc<Mutex<ClassicTraceMap>>,
+    pub(crate) syns: Arc<SynMap>,
+    pub(crate) sequences: Arc<Mutex<SequenceMap>>,
+    pub(crate) handshakes: Arc<HandshakeMap>,
+}
 
 pub(crate) fn setup_ebpf(
     iface: &str,
     config: &EbpfConfig,
-) -> anyhow::Result<(aya::Ebpf, TraceMap)> {
+) -> anyhow::Result<(aya::Ebpf, EbpfMaps)> {
     let mut ebpf = aya::Ebpf::load(aya::include_bytes_aligned!(concat!(
         env!("OUT_DIR"),
         "/inband-traceroute"
@@ -36,6 +53,25 @@ pub(crate) fn setup_ebpf(
         .attach(iface, XdpFlags::SKB_MODE)
         .context("failed to attach the XDP program - wrong mode?")?;
 
+    // Fails if the clsact qdisc already exists, which is fine. Without the egress classifier
+    // probes can't get the connection's sequence numbers, so anything else is fatal.
+    if let Err(err) = tc::qdisc_add_clsact(iface) {
+        if err.kind() != io::ErrorKind::AlreadyExists {
+            return Err(err).context("failed to add the clsact qdisc for the egress classifier");
+        }
+        debug!("clsact qdisc already exists: {err}");
+    }
+
+    let egress: &mut SchedClassifier = ebpf
+        .program_mut("inband_traceroute_egress")
+        .unwrap()
+        .try_into()?;
+
+    egress.load().context("Failed to load egress program")?;
+    egress
+        .attach(iface, TcAttachType::Egress)
+        .context("failed to attach the egress classifier")?;
+
     {
         let mut config_map: Array<MapData, EbpfConfig> =
             Array::try_from(ebpf.take_map("CONFIG").expect("failed to find CONFIG map"))?;
@@ -46,8 +82,29 @@ pub(crate) fn setup_ebpf(
 
     let trace_map: TraceMap =
         HashMap::try_from(ebpf.take_map("TRACES").expect("failed to find TRACES map"))?;
-
-    Ok((ebpf, trace_map))
+    let classic_trace_map: ClassicTraceMap = HashMap::try_from(
+        ebpf.take_map("CLASSIC_TRACES")
+            .expect("failed to find CLASSIC_TRACES map"),
+    )?;
+    let syn_map: SynMap =
+        HashMap::try_from(ebpf.take_map("SYNS").expect("failed to find SYNS map"))?;
+    let sequence_map: SequenceMap = HashMap::try_from(
+        ebpf.take_map("SEQUENCES")
+            .expect("failed to find SEQUENCES map"),
+    )?;
+    let handshake_map: HandshakeMap = HashMap::try_from(
+        ebpf.take_map("HANDSHAKES")
+            .expect("failed to find HANDSHAKES map"),
+    )?;
+
+    let maps = EbpfMaps {
+        traces: Arc::new(Mutex::new(trace_map)),
+        classic_traces: Arc::new(Mutex::new(classic_trace_map)),
+        syns: Arc::new(syn_map),
+        sequences: Arc::new(Mutex::new(sequence_map)),
+        handshakes: Arc::new(handshake_map),
+    };
+    Ok((ebpf, maps))
 }
 
 pub(crate) fn start_event_processor(
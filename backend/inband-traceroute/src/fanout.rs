@@ -0,0 +1,297 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex, Weak},
+};
+
+use async_stream::stream;
+use futures::{Stream, StreamExt};
+use tokio::sync::watch;
+
+use crate::{conn::Connection, server::TraceEvent, tracer::TraceOptions};
+
+/// Running traces by remote address and local port, so requests multiplexed on one HTTP/2
+/// connection can follow the trace another one started
+#[derive(Debug, Default)]
+pub(crate) struct ConnectionTraces {
+    traces: Mutex<HashMap<(SocketAddr, u16), Weak<SharedTrace>>>,
+}
+
+impl ConnectionTraces {
+    /// The trace running on `connection`, if it was started with the same options
+    pub(crate) fn attach(
+        &self,
+        connection: &Arc<Connection>,
+        options: TraceOptions,
+    ) -> Option<Arc<SharedTrace>> {
+        let mut traces = self.traces.lock().unwrap();
+        let key = (connection.remote, connection.local.port());
+        let Some(trace) = traces.get(&key).and_then(Weak::upgrade) else {
+            // The trace's last handle is gone
+            traces.remove(&key);
+            return None;
+        };
+
+        let attachable = Arc::ptr_eq(&trace.connection, connection)
+            && trace.options == options
+            && !trace.log.lock().unwrap().done;
+        attachable.then_some(trace)
+    }
+
+    /// Make `trace` the one requests on its connection attach to
+    pub(crate) fn insert(&self, trace: &Arc<SharedTrace>) {
+        let mut traces = self.traces.lock().unwrap();
+        traces.retain(|_, trace| trace.strong_count() > 0);
+        let connection = &trace.connection;
+        traces.insert(
+            (connection.remote, connection.local.port()),
+            Arc::downgrade(trace),
+        );
+    }
+}
+
+/// Events of a trace, kept so every subscriber gets all of them from the start
+#[derive(Debug)]
+pub(crate) struct SharedTrace {
+    pub(crate) trace_id: String,
+    connection: Arc<Connection>,
+    options: TraceOptions,
+    log: Mutex<TraceLog>,
+    // Bumped for each event, and closed once all subscribers are gone
+    changed: watch::Sender<()>,
+}
+
+#[derive(Debug, Default)]
+struct TraceLog {
+    events: Vec<Result<TraceEvent, String>>,
+    done: bool,
+}
+
+impl SharedTrace {
+    pub(crate) fn new(
+        trace_id: String,
+        connection: Arc<Connection>,
+        options: TraceOptions,
+    ) -> Self {
+        Self {
+            trace_id,
+            connection,
+            options,
+            log: Default::default(),
+            changed: watch::Sender::new(()),
+        }
+    }
+
+    /// Publish `events` until they end or all subscribers are gone
+    pub(crate) async fn run(
+        self: Arc<Self>,
+        events: impl Stream<Item = anyhow::Result<TraceEvent>>,
+    ) {
+        let mut events = std::pin::pin!(events);
+        loop {
+            tokio::select! {
+                event = events.next() => match event {
+                    Some(event) => self.publish(&event),
+                    None => break,
+                },
+                () = self.changed.closed() => break,
+            }
+        }
+
+        // End the subscribers' streams, also if the trace was cut short
+        self.log.lock().unwrap().done = true;
+        self.changed.send_replace(());
+    }
+
+    fn publish(&self, event: &anyhow::Result<TraceEvent>) {
+        let mut log = self.log.lock().unwrap();
+        log.done |= matches!(event, Ok(TraceEvent::Done));
+        log.events.push(match event {
+            Ok(event) => Ok(event.clone()),
+            Err(err) => Err(format!("{err:#}")),
+        });
+        self.changed.send_replace(());
+    }
+
+    /// All events of the trace, from the first one
+    pub(crate) fn subscribe(self: &Arc<Self>) -> impl Stream<Item = anyhow::Result<TraceEvent>> {
+        let trace = self.clone();
+        let mut changed = self.changed.subscribe();
+        stream! {
+            let mut next = 0;
+            loop {
+                let (events, done) = {
+                    let log = trace.log.lock().unwrap();
+                    (log.events[next..].to_vec(), log.done)
+                };
+                next += events.len();
+                for event in events {
+                    yield event.map_err(anyhow::Error::msg);
+                }
+
+                if done || changed.changed().await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::IpAddr, time::Duration};
+
+    use tokio::{
+        net::{TcpListener, TcpStream},
+        sync::mpsc,
+        time::timeout,
+    };
+    use tokio_stream::wrappers::UnboundedReceiverStream;
+
+    use super::*;
+
+    // One end of a loopback connection
+    async fn connection() -> Arc<Connection> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        Arc::new(Connection::new(&stream).unwrap())
+    }
+
+    fn reverse_dns(ttl: u8) -> TraceEvent {
+        TraceEvent::ReverseDns {
+            ttl,
+            ip: IpAddr::from([192, 0, 2, ttl]),
+            name: Ok(format!("hop{ttl}.example")),
+        }
+    }
+
+    // The events of a finished stream, with errors as their messages
+    async fn collect(events: impl Stream<Item = anyhow::Result<TraceEvent>>) -> Vec<String> {
+        let events = timeout(Duration::from_secs(10), events.collect::<Vec<_>>())
+            .await
+            .expect("events didn't end");
+        events
+            .into_iter()
+            .map(|event| match event {
+                Ok(TraceEvent::ReverseDns { ttl, .. }) => format!("hop {ttl}"),
+                Ok(TraceEvent::Done) => "done".to_owned(),
+                Ok(event) => panic!("unexpected event {event:?}"),
+                Err(err) => format!("error: {err}"),
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn attaches_to_running_trace_with_same_options() {
+        let traces = ConnectionTraces::default();
+        let connection = connection().await;
+        let options = TraceOptions::default();
+        assert!(traces.attach(&connection, options).is_none());
+
+        let trace = Arc::new(SharedTrace::new(
+            "1".to_owned(),
+            connection.clone(),
+            options,
+        ));
+        traces.insert(&trace);
+        let attached = traces.attach(&connection, options).unwrap();
+        assert!(Arc::ptr_eq(&attached, &trace));
+
+        // Other connections start their own
+        assert!(traces.attach(&self::connection().await, options).is_none());
+    }
+
+    #[tokio::test]
+    async fn queues_behind_other_options_or_finished_trace() {
+        let traces = ConnectionTraces::default();
+        let connection = connection().await;
+        let options = TraceOptions::default();
+        let trace = Arc::new(SharedTrace::new(
+            "1".to_owned(),
+            connection.clone(),
+            options,
+        ));
+        traces.insert(&trace);
+
+        let classic = TraceOptions {
+            classic: true,
+            ..options
+        };
+        assert!(traces.attach(&connection, classic).is_none());
+
+        trace.publish(&Ok(TraceEvent::Done));
+        assert!(traces.attach(&connection, options).is_none());
+    }
+
+    #[tokio::test]
+    async fn late_subscriber_gets_logged_events_then_done() {
+        let trace = Arc::new(SharedTrace::new(
+            "1".to_owned(),
+            connection().await,
+            TraceOptions::default(),
+        ));
+        let early = trace.subscribe();
+
+        let (events_tx, events_rx) = mpsc::unbounded_channel();
+        let run = tokio::spawn(trace.clone().run(UnboundedReceiverStream::new(events_rx)));
+        events_tx.send(Ok(reverse_dns(1))).unwrap();
+        events_tx
+            .send(Err(anyhow::anyhow!("probe failed")))
+            .unwrap();
+        events_tx.send(Ok(reverse_dns(2))).unwrap();
+        events_tx.send(Ok(TraceEvent::Done)).unwrap();
+        drop(events_tx);
+        timeout(Duration::from_secs(10), run)
+            .await
+            .unwrap()
+            .unwrap();
+
+        let expected = ["hop 1", "error: probe failed", "hop 2", "done"];
+        assert_eq!(collect(early).await, expected);
+        assert_eq!(collect(trace.subscribe()).await, expected);
+    }
+
+    #[tokio::test]
+    async fn run_stops_without_subscribers() {
+        let trace = Arc::new(SharedTrace::new(
+            "1".to_owned(),
+            connection().await,
+            TraceOptions::default(),
+        ));
+        let subscriber = trace.subscribe();
+        let run = tokio::spawn(trace.clone().run(futures::stream::pending()));
+        drop(subscriber);
+        timeout(Duration::from_secs(10), run)
+            .await
+            .unwrap()
+            .unwrap();
+
+        // Cut short without a Done event, but later subscribers still get an end
+        assert!(collect(trace.subscribe()).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn entry_removed_with_last_handle() {
+        let traces = ConnectionTraces::default();
+        let connection = connection().await;
+        let options = TraceOptions::default();
+        let trace = Arc::new(SharedTrace::new(
+            "1".to_owned(),
+            connection.clone(),
+            options,
+        ));
+        traces.insert(&trace);
+
+        // A subscriber's stream keeps the trace alive
+        let events = trace.subscribe();
+        drop(trace);
+        assert!(traces.attach(&connection, options).is_some());
+        assert_eq!(traces.traces.lock().unwrap().len(), 1);
+
+        drop(events);
+        assert!(traces.attach(&connection, options).is_none());
+        assert!(traces.traces.lock().unwrap().is_empty());
+    }
+}
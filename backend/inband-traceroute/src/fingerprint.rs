@@ -0,0 +1,158 @@
+use inband_traceroute_common::{SynFingerprint, SYN_NO_WSCALE};
+use serde::Serialize;
+
+// Initial TTLs in common use, smallest first
+const INITIAL_TTLS: [u8; 4] = [32, 64, 128, 255];
+
+/// Option layouts and initial TTLs of common stacks' SYNs, as in p0f's signatures
+const SIGNATURES: &[(&str, u8, &str)] = &[
+    ("mss,sok,ts,nop,ws", 64, "Linux"),
+    ("mss,nop,nop,sok,nop,ws", 64, "Linux"),
+    ("mss,nop,ws,nop,nop,ts,sok,eol", 64, "macOS/iOS"),
+    ("mss,nop,ws,sok,ts", 64, "FreeBSD"),
+    ("mss,nop,ws,nop,nop,sok", 128, "Windows"),
+    ("mss,nop,ws,nop,nop,ts,nop,nop,sok", 128, "Windows"),
+];
+
+/// The client's SYN as it arrived, and what it says about the client and the path back to us
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct ClientSyn {
+    pub(crate) ttl: u8,
+    /// Initial TTL the client most likely used
+    pub(crate) initial_ttl: u8,
+    /// Hops from the client to us, counted like the forward trace counts hops to the client
+    pub(crate) reverse_hops: u8,
+    pub(crate) window: u16,
+    pub(crate) mss: Option<u16>,
+    pub(crate) wscale: Option<u8>,
+    /// TCP option layout in p0f notation, e.g. "mss,sok,ts,nop,ws"
+    pub(crate) options: String,
+    pub(crate) df: bool,
+    /// Operating system whose SYNs look like this one
+    pub(crate) os: Option<&'static str>,
+}
+
+impl ClientSyn {
+    pub(crate) fn new(syn: &SynFingerprint) -> Self {
+        let ttl = syn.ttl;
+        let initial_ttl = INITIAL_TTLS
+            .into_iter()
+            .find(|initial| *initial >= ttl)
+            .unwrap_or(u8::MAX);
+
+        let count = (syn.option_count as usize).min(syn.options.len());
+        let options = syn.options[..count]
+            .iter()
+            .map(|kind| option_name(*kind))
+            .collect::<Vec<_>>()
+            .join(",");
+
+        let os = SIGNATURES
+            .iter()
+            .find(|(layout, signature_ttl, _)| *layout == options && *signature_ttl == initial_ttl)
+            .map(|(_, _, os)| *os);
+
+        Self {
+            ttl,
+            initial_ttl,
+            reverse_hops: initial_ttl - ttl + 1,
+            window: syn.window,
+            mss: (syn.mss != 0).then_some(syn.mss),
+            wscale: (syn.wscale != SYN_NO_WSCALE).then_some(syn.wscale),
+            options,
+            df: syn.df != 0,
+            os,
+        }
+    }
+}
+
+fn option_name(kind: u8) -> String {
+    match kind {
+        0 => "eol",
+        1 => "nop",
+        2 => "mss",
+        3 => "ws",
+        4 => "sok",
+        5 => "sack",
+        8 => "ts",
+        kind => return format!("?{kind}"),
+    }
+    .to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use inband_traceroute_common::SYN_MAX_OPTIONS;
+
+    use super::*;
+
+    fn syn(ttl: u8, kinds: &[u8]) -> SynFingerprint {
+        let mut options = [0; SYN_MAX_OPTIONS];
+        options[..kinds.len()].copy_from_slice(kinds);
+        SynFingerprint {
+            ttl,
+            df: 1,
+            window: 64240,
+            mss: 1460,
+            wscale: 7,
+            option_count: kinds.len() as u8,
+            options,
+        }
+    }
+
+    #[test]
+    fn infers_initial_ttl_and_reverse_hops() {
+        for (ttl, initial_ttl, reverse_hops) in [
+            (64, 64, 1),
+            (57, 64, 8),
+            (33, 64, 32),
+            (32, 32, 1),
+            (1, 32, 32),
+            (116, 128, 13),
+            (128, 128, 1),
+            (240, 255, 16),
+            (255, 255, 1),
+        ] {
+            let client = ClientSyn::new(&syn(ttl, &[]));
+            assert_eq!(client.initial_ttl, initial_ttl, "TTL {ttl}");
+            assert_eq!(client.reverse_hops, reverse_hops, "TTL {ttl}");
+        }
+    }
+
+    #[test]
+    fn matches_signatures() {
+        let linux = [2, 4, 8, 1, 3];
+        let client = ClientSyn::new(&syn(52, &linux));
+        assert_eq!(client.options, "mss,sok,ts,nop,ws");
+        assert_eq!(client.os, Some("Linux"));
+
+        // Same layout, but Windows' initial TTL
+        assert_eq!(ClientSyn::new(&syn(116, &linux)).os, None);
+
+        let windows = ClientSyn::new(&syn(110, &[2, 1, 3, 1, 1, 4]));
+        assert_eq!(windows.os, Some("Windows"));
+
+        let macos = ClientSyn::new(&syn(60, &[2, 1, 3, 1, 1, 8, 4, 0]));
+        assert_eq!(macos.os, Some("macOS/iOS"));
+
+        let unknown = ClientSyn::new(&syn(64, &[2, 30, 4]));
+        assert_eq!(unknown.options, "mss,?30,sok");
+        assert_eq!(unknown.os, None);
+    }
+
+    #[test]
+    fn missing_options() {
+        let mut fingerprint = syn(64, &[]);
+        fingerprint.mss = 0;
+        fingerprint.wscale = SYN_NO_WSCALE;
+        fingerprint.df = 0;
+        // More options than were recorded
+        fingerprint.option_count = u8::MAX;
+
+        let client = ClientSyn::new(&fingerprint);
+        assert_eq!(client.mss, None);
+        assert_eq!(client.wscale, None);
+        assert!(!client.df);
+        assert_eq!(client.options.split(',').count(), SYN_MAX_OPTIONS);
+    }
+}
@@ -0,0 +1,460 @@
+use std::{collections::VecDeque, net::IpAddr, pin::Pin, sync::Arc};
+
+use async_stream::stream;
+use futures::Stream;
+use log::warn;
+use tokio::sync::Mutex;
+use tonic::{Request, Response, Status};
+
+use crate::{
+    annotation::Annotation,
+    classic::{ClassicHop, ClassicMethod, ClassicReply, ClassicTrace},
+    conn::{Connection, ConnectionStats},
+    fingerprint::ClientSyn,
+    hop::{Hop, HopType, LoadedHop, PathMtu, ProbeHeaders, TosTraversal, TtlField},
+    report::{ReportHop, TraceReport, TraceSummary},
+    server::{AppState, TraceEvent, TraceStart},
+    tracer::{EcnCodepoint, TraceOptions},
+};
+
+pub(crate) mod proto {
+    tonic::include_proto!("inband_traceroute");
+}
+
+use proto::{
+    inband_traceroute_server::{InbandTraceroute, InbandTracerouteServer},
+    reverse_dns::Lookup,
+    trace_event::Event,
+};
+
+// Completed traces kept for GetTrace
+const RECENT_REPORTS: usize = 256;
+
+/// gRPC service, served next to the axum routes on the same listeners
+#[derive(Clone)]
+pub(crate) struct GrpcService {
+    state: Arc<AppState>,
+    reports: Arc<Mutex<VecDeque<(String, proto::TraceReport)>>>,
+}
+
+impl GrpcService {
+    pub(crate) fn new(state: Arc<AppState>) -> InbandTracerouteServer<Self> {
+        InbandTracerouteServer::new(Self {
+            state,
+            reports: Default::default(),
+        })
+    }
+}
+
+#[tonic::async_trait]
+impl InbandTraceroute for GrpcService {
+    type StartTraceStream =
+        Pin<Box<dyn Stream<Item = Result<proto::TraceEvent, Status>> + Send + 'static>>;
+
+    async fn start_trace(
+        &self,
+        request: Request<proto::StartTraceRequest>,
+    ) -> Result<Response<Self::StartTraceStream>, Status> {
+        let connection = request
+            .extensions()
+            .get::<Arc<Connection>>()
+            .cloned()
+            .ok_or_else(|| Status::internal("No connection for request"))?;
+        let headers = request.metadata().clone().into_headers();
+        let options = TraceOptions::try_from(request.into_inner())?;
+
+        let (trace_id, events) = self
+            .state
+            .trace_stream(connection, &headers, options)
+            .await
+            .map_err(|err| Status::internal(format!("{err:#}")))?;
+
+        let reports = self.reports.clone();
+
+        Ok(Response::new(Box::pin(stream! {
+            let mut report = TraceReport::new(trace_id.clone());
+            for await event in events {
+                match event {
+                    Ok(TraceEvent::Padding(_)) => {}
+                    Ok(TraceEvent::Done) => {
+                        report.finish();
+                        let report = proto::TraceReport::from(&report);
+                        yield Ok(proto::TraceEvent {
+                            event: Some(Event::Summary(report.summary.clone().unwrap_or_default())),
+                        });
+
+                        let mut reports = reports.lock().await;
+                        if reports.len() == RECENT_REPORTS {
+                            reports.pop_front();
+                        }
+                        reports.push_back((trace_id.clone(), report));
+                    }
+                    Ok(event) => {
+                        if let Some(message) = proto::TraceEvent::new(&event) {
+                            yield Ok(message);
+                        }
+                        report.add(&Ok(event));
+                    }
+                    Err(err) => {
+                        warn!("Error: {err}");
+                        report.add(&Err(err));
+                    }
+                }
+            }
+        })))
+    }
+
+    async fn get_trace(
+        &self,
+        request: Request<proto::GetTraceRequest>,
+    ) -> Result<Response<proto::TraceReport>, Status> {
+        let trace_id = request.into_inner().trace_id;
+        let reports = self.reports.lock().await;
+        reports
+            .iter()
+            .find(|(id, _)| *id == trace_id)
+            .map(|(_, report)| Response::new(report.clone()))
+            .ok_or_else(|| Status::not_found(format!("No trace with ID {trace_id}")))
+    }
+}
+
+impl TryFrom<proto::StartTraceRequest> for TraceOptions {
+    type Error = Status;
+
+    fn try_from(request: proto::StartTraceRequest) -> Result<Self, Self::Error> {
+        let ecn = match request.ecn.map(proto::EcnCodepoint::try_from) {
+            None | Some(Ok(proto::EcnCodepoint::Unspecified)) => None,
+            Some(Ok(proto::EcnCodepoint::NotEct)) => Some(EcnCodepoint::NotEct),
+            Some(Ok(proto::EcnCodepoint::Ect1)) => Some(EcnCodepoint::Ect1),
+            Some(Ok(proto::EcnCodepoint::Ect0)) => Some(EcnCodepoint::Ect0),
+            Some(Ok(proto::EcnCodepoint::Ce)) => Some(EcnCodepoint::Ce),
+            Some(Err(_)) => return Err(Status::invalid_argument("Unknown ECN codepoint")),
+        };
+        let dscp = request
+            .dscp
+            .map(|dscp| {
+                u8::try_from(dscp)
+                    .ok()
+                    .filter(|dscp| *dscp < 64)
+                    .ok_or_else(|| Status::invalid_argument("DSCP must be below 64"))
+            })
+            .transpose()?;
+
+        Ok(Self {
+            pmtu: request.pmtu,
+            ecn,
+            dscp,
+            load: request.load,
+            classic: request.classic,
+        })
+    }
+}
+
+impl proto::TraceEvent {
+    /// `None` for events that only make sense on a stream of their own, like padding
+    fn new(event: &TraceEvent) -> Option<Self> {
+        let event = match event {
+            TraceEvent::Start(start) => Event::Start(start.into()),
+            TraceEvent::Hop(hop) => Event::Hop(hop.into()),
+            TraceEvent::ReverseDns { ttl, ip, name } => {
+                Event::ReverseDns(proto::ReverseDns::new(*ttl, *ip, name))
+            }
+            TraceEvent::PathMtu(path_mtu) => Event::PathMtu(path_mtu.into()),
+            TraceEvent::HopUnderLoad(hop) => Event::HopUnderLoad(hop.into()),
+            TraceEvent::ClassicTrace(trace) => Event::ClassicTrace(trace.into()),
+            TraceEvent::ConnectionStats(stats) => Event::ConnectionStats(stats.into()),
+            TraceEvent::Annotation(annotation) => Event::Annotation(annotation.into()),
+            TraceEvent::Padding(_) | TraceEvent::Done => return None,
+        };
+        Some(Self { event: Some(event) })
+    }
+}
+
+impl proto::ReverseDns {
+    fn new(ttl: u8, ip: IpAddr, name: &Result<String, String>) -> Self {
+        Self {
+            ttl: ttl.into(),
+            ip: ip.to_string(),
+            lookup: Some(match name {
+                Ok(name) => Lookup::Name(name.clone()),
+                Err(err) => Lookup::Error(err.clone()),
+            }),
+        }
+    }
+}
+
+impl From<&TraceReport> for proto::TraceReport {
+    fn from(report: &TraceReport) -> Self {
+        Self {
+            hops: report.hops.iter().map(Into::into).collect(),
+            path_mtu: report.path_mtu.as_ref().map(Into::into),
+            hops_under_load: report.hops_under_load.iter().map(Into::into).collect(),
+            classic_traces: report.classic_traces.iter().map(Into::into).collect(),
+            connection_stats: report.connection_stats.iter().map(Into::into).collect(),
+            summary: Some(proto::TraceSummary::new(&report.summary, &report.trace_id)),
+            errors: report.errors.clone(),
+            start: report.start.as_ref().map(Into::into),
+            annotations: report.annotations.iter().map(Into::into).collect(),
+        }
+    }
+}
+
+impl From<&Annotation> for proto::Annotation {
+    fn from(annotation: &Annotation) -> Self {
+        let kind = match annotation {
+            Annotation::Proxy {
+                header,
+                value,
+                clients,
+            } => proto::annotation::Kind::Proxy(proto::ProxyAnnotation {
+                header: header.clone(),
+                value: value.clone(),
+                clients: clients.iter().map(ToString::to_string).collect(),
+            }),
+            Annotation::Nat64 { prefix, ipv4 } => {
+                proto::annotation::Kind::Nat64(proto::Nat64Annotation {
+                    prefix: prefix.to_string(),
+                    ipv4: ipv4.to_string(),
+                })
+            }
+        };
+        Self { kind: Some(kind) }
+    }
+}
+
+impl From<&ConnectionStats> for proto::ConnectionStats {
+    fn from(stats: &ConnectionStats) -> Self {
+        Self {
+            rtt: stats.rtt,
+            rtt_var: stats.rtt_var,
+            min_rtt: stats.min_rtt,
+            path_rtt: stats.path_rtt,
+            rto: stats.rto,
+            retransmits: stats.retransmits,
+            lost: stats.lost,
+            unacked: stats.unacked,
+            snd_cwnd: stats.snd_cwnd,
+            snd_ssthresh: stats.snd_ssthresh,
+            snd_mss: stats.snd_mss,
+            rcv_mss: stats.rcv_mss,
+            pmtu: stats.pmtu,
+            delivery_rate: stats.delivery_rate,
+            pacing_rate: stats.pacing_rate,
+            bytes_acked: stats.bytes_acked,
+            bytes_received: stats.bytes_received,
+            bytes_retrans: stats.bytes_retrans,
+        }
+    }
+}
+
+impl From<&TraceStart> for proto::TraceStart {
+    fn from(start: &TraceStart) -> Self {
+        Self {
+            trace_id: start.trace_id.clone(),
+            node_name: start.node_name.clone(),
+            node_addr: start.node_addr.to_string(),
+            time: start.time,
+            client: start.client.to_string(),
+            ip_version: start.ip_version.into(),
+            tls: start.tls.as_ref().map(|tls| proto::TlsInfo {
+                version: tls.version.clone(),
+                cipher_suite: tls.cipher_suite.clone(),
+                alpn: tls.alpn.clone(),
+                sni: tls.sni.clone(),
+            }),
+            client_hello: start
+                .client_hello
+                .as_ref()
+                .map(|hello| proto::ClientHelloInfo {
+                    ja4: hello.ja4.clone(),
+                    sni: hello.sni.clone(),
+                    alpn: hello.alpn.clone(),
+                }),
+            syn: start.syn.as_ref().map(Into::into),
+            options: Some(start.options.into()),
+        }
+    }
+}
+
+impl From<&ClientSyn> for proto::ClientSyn {
+    fn from(syn: &ClientSyn) -> Self {
+        Self {
+            ttl: syn.ttl.into(),
+            initial_ttl: syn.initial_ttl.into(),
+            reverse_hops: syn.reverse_hops.into(),
+            window: syn.window.into(),
+            mss: syn.mss.map(Into::into),
+            wscale: syn.wscale.map(Into::into),
+            options: syn.options.clone(),
+            df: syn.df,
+            os: syn.os.map(str::to_owned),
+        }
+    }
+}
+
+impl From<TraceOptions> for proto::StartTraceRequest {
+    fn from(options: TraceOptions) -> Self {
+        let ecn = options.ecn.map(|ecn| match ecn {
+            EcnCodepoint::NotEct => proto::EcnCodepoint::NotEct,
+            EcnCodepoint::Ect1 => proto::EcnCodepoint::Ect1,
+            EcnCodepoint::Ect0 => proto::EcnCodepoint::Ect0,
+            EcnCodepoint::Ce => proto::EcnCodepoint::Ce,
+        });
+        Self {
+            pmtu: options.pmtu,
+            ecn: ecn.map(Into::into),
+            dscp: options.dscp.map(Into::into),
+            load: options.load,
+            classic: options.classic,
+        }
+    }
+}
+
+impl proto::TraceSummary {
+    fn new(summary: &TraceSummary, trace_id: &str) -> Self {
+        Self {
+            trace_id: trace_id.to_owned(),
+            reached: summary.reached,
+            hops: summary.hops.into(),
+            rtt: summary.rtt,
+            reverse_hops: summary.reverse_hops.map(Into::into),
+        }
+    }
+}
+
+impl From<&ReportHop> for proto::ReportHop {
+    fn from(hop: &ReportHop) -> Self {
+        Self {
+            hop: Some((&hop.hop).into()),
+            reverse_dns: match (&hop.reverse_dns, hop.hop.addr) {
+                (Some(name), Some(ip)) => Some(proto::ReverseDns::new(hop.hop.ttl, ip, name)),
+                _ => None,
+            },
+        }
+    }
+}
+
+impl From<&Hop> for proto::Hop {
+    fn from(hop: &Hop) -> Self {
+        Self {
+            ttl: hop.ttl.into(),
+            hop_type: proto::HopType::from(hop.hop_type) as i32,
+            addr: hop.addr.map(|addr| addr.to_string()),
+            rtt: hop.rtt,
+            enriched_info: hop.enriched_info.as_ref().map(|info| proto::AsnInfo {
+                as_domain: info.as_domain.map(str::to_owned),
+                as_name: info.as_name.map(str::to_owned),
+                asn: info.asn.map(str::to_owned),
+                continent: info.continent.map(str::to_owned),
+                continent_code: info.continent_code.map(str::to_owned),
+                country: info.country.map(str::to_owned),
+                country_code: info.country_code.map(str::to_owned),
+            }),
+            probe: hop.probe.as_ref().map(Into::into),
+            tos: hop.tos.as_ref().map(Into::into),
+        }
+    }
+}
+
+impl From<HopType> for proto::HopType {
+    fn from(hop_type: HopType) -> Self {
+        match hop_type {
+            HopType::Timeout => Self::Timeout,
+            HopType::TcpRst => Self::TcpRst,
+            HopType::TcpAck => Self::TcpAck,
+            HopType::IcmpTimeExceeded => Self::IcmpTimeExceeded,
+            HopType::Origin => Self::Origin,
+        }
+    }
+}
+
+impl From<&ProbeHeaders> for proto::ProbeHeaders {
+    fn from(probe: &ProbeHeaders) -> Self {
+        Self {
+            tos: probe.tos.into(),
+            flow_label: probe.flow_label,
+            observed: probe.observed,
+            ttl_field: match probe.ttl_field {
+                TtlField::Ipv4Identification => proto::TtlField::Ipv4Identification,
+                TtlField::Ipv6PayloadLength => proto::TtlField::Ipv6PayloadLength,
+            } as i32,
+        }
+    }
+}
+
+impl From<&TosTraversal> for proto::TosTraversal {
+    fn from(tos: &TosTraversal) -> Self {
+        Self {
+            sent_dscp: tos.sent_dscp.into(),
+            sent_ecn: tos.sent_ecn.into(),
+            quoted_dscp: tos.quoted_dscp.into(),
+            quoted_ecn: tos.quoted_ecn.into(),
+            ecn_cleared: tos.ecn_cleared,
+            dscp_remarked: tos.dscp_remarked,
+        }
+    }
+}
+
+impl From<&PathMtu> for proto::PathMtu {
+    fn from(path_mtu: &PathMtu) -> Self {
+        Self {
+            mtu: path_mtu.mtu.map(Into::into),
+            reported_mtu: path_mtu.reported_mtu,
+            constrained_by: path_mtu.constrained_by.map(|addr| addr.to_string()),
+            constrained_ttl: path_mtu.constrained_ttl.map(Into::into),
+            black_holed: path_mtu
+                .black_holed
+                .iter()
+                .copied()
+                .map(Into::into)
+                .collect(),
+        }
+    }
+}
+
+impl From<&LoadedHop> for proto::LoadedHop {
+    fn from(hop: &LoadedHop) -> Self {
+        Self {
+            ttl: hop.ttl.into(),
+            addr: hop.addr.map(|addr| addr.to_string()),
+            idle_rtt: hop.idle_rtt,
+            loaded_rtt: hop.loaded_rtt,
+        }
+    }
+}
+
+impl From<&ClassicTrace> for proto::ClassicTrace {
+    fn from(trace: &ClassicTrace) -> Self {
+        Self {
+            method: match trace.method {
+                ClassicMethod::IcmpEcho => proto::ClassicMethod::IcmpEcho,
+                ClassicMethod::Udp => proto::ClassicMethod::Udp,
+            } as i32,
+            hops: trace.hops.iter().map(Into::into).collect(),
+            reached: trace.reached,
+            filtered_at: trace.filtered_at.map(Into::into),
+            divergent_ttls: trace
+                .divergent_ttls
+                .iter()
+                .copied()
+                .map(Into::into)
+                .collect(),
+        }
+    }
+}
+
+impl From<&ClassicHop> for proto::ClassicHop {
+    fn from(hop: &ClassicHop) -> Self {
+        Self {
+            ttl: hop.ttl.into(),
+            reply: match hop.reply {
+                None => proto::ClassicReply::Unspecified,
+                Some(ClassicReply::TimeExceeded) => proto::ClassicReply::TimeExceeded,
+                Some(ClassicReply::Unreachable) => proto::ClassicReply::Unreachable,
+                Some(ClassicReply::Destination) => proto::ClassicReply::Destination,
+            } as i32,
+            addr: hop.addr.map(|addr| addr.to_string()),
+            rtt: hop.rtt,
+        }
+    }
+}
@@ -0,0 +1,349 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use log::{debug, info, warn};
+use rusqlite::{params, OptionalExtension};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{report::TraceReport, server::TraceEvent, tracer::TraceOptions};
+
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// Most recent traces returned for a client
+const CLIENT_TRACES_LIMIT: u32 = 100;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS traces (
+        id TEXT PRIMARY KEY,
+        client_ip TEXT NOT NULL,
+        finished_at INTEGER NOT NULL,
+        record TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS traces_client_ip ON traces (client_ip, finished_at);
+    CREATE INDEX IF NOT EXISTS traces_finished_at ON traces (finished_at);
+";
+
+/// Completed traces, kept in SQLite so they can be linked to later
+#[derive(Debug)]
+pub(crate) struct TraceStore {
+    db: Mutex<rusqlite::Connection>,
+    retention: Duration,
+}
+
+/// A stored trace. The report is kept as JSON and returned as is.
+#[derive(Debug, Serialize)]
+struct TraceRecord<'a> {
+    client: SocketAddr,
+    /// Unix time in milliseconds
+    started_at: u64,
+    finished_at: u64,
+    /// False if the client went away before the trace finished
+    completed: bool,
+    options: TraceOptions,
+    report: &'a TraceReport,
+}
+
+impl TraceStore {
+    pub(crate) fn open(path: &Path, retention: Duration) -> anyhow::Result<Arc<Self>> {
+        let db = rusqlite::Connection::open(path)
+            .with_context(|| format!("Failed to open trace history {}", path.display()))?;
+
+        let store = Arc::new(Self::new(db, retention)?);
+        tokio::spawn(store.clone().prune_periodically());
+        Ok(store)
+    }
+
+    fn new(db: rusqlite::Connection, retention: Duration) -> anyhow::Result<Self> {
+        db.execute_batch(SCHEMA)
+            .context("Failed to create trace history tables")?;
+        Ok(Self {
+            db: Mutex::new(db),
+            retention,
+        })
+    }
+
+    /// Stored record of a trace, as JSON
+    pub(crate) async fn get(self: &Arc<Self>, id: String) -> anyhow::Result<Option<Value>> {
+        let store = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let db = store.db.lock().unwrap();
+            let record: Option<String> = db
+                .query_row(
+                    "SELECT record FROM traces WHERE id = ?1",
+                    params![id],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            record
+                .map(|record| serde_json::from_str(&record).context("Corrupt trace record"))
+                .transpose()
+        })
+        .await?
+    }
+
+    /// Most recent traces of a client, newest first
+    pub(crate) async fn by_client(self: &Arc<Self>, client: IpAddr) -> anyhow::Result<Vec<Value>> {
+        let store = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let db = store.db.lock().unwrap();
+            let mut statement = db.prepare_cached(
+                "SELECT record FROM traces WHERE client_ip = ?1 ORDER BY finished_at DESC LIMIT ?2",
+            )?;
+            let rows = statement
+                .query_map(params![client.to_string(), CLIENT_TRACES_LIMIT], |row| {
+                    row.get::<_, String>(0)
+                })?;
+
+            let mut records = Vec::new();
+            for record in rows {
+                records.push(serde_json::from_str(&record?).context("Corrupt trace record")?);
+            }
+            Ok(records)
+        })
+        .await?
+    }
+
+    fn insert(
+        &self,
+        id: &str,
+        client: IpAddr,
+        finished_at: u64,
+        record: &str,
+    ) -> anyhow::Result<()> {
+        let db = self.db.lock().unwrap();
+        db.execute(
+            "INSERT OR REPLACE INTO traces (id, client_ip, finished_at, record) VALUES (?1, ?2, ?3, ?4)",
+            params![id, client.to_string(), finished_at, record],
+        )?;
+        Ok(())
+    }
+
+    // Delete the traces that finished longer than the retention before `now`
+    fn prune(&self, now: u64) -> rusqlite::Result<usize> {
+        let cutoff = now.saturating_sub(self.retention.as_millis() as u64);
+        let db = self.db.lock().unwrap();
+        db.execute("DELETE FROM traces WHERE finished_at < ?1", params![cutoff])
+    }
+
+    async fn prune_periodically(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+        loop {
+            interval.tick().await;
+
+            let store = self.clone();
+            let pruned = tokio::task::spawn_blocking(move || store.prune(unix_millis())).await;
+
+            match pruned {
+                Ok(Ok(0)) => {}
+                Ok(Ok(count)) => info!("Pruned {count} traces from the history"),
+                Ok(Err(err)) => warn!("Failed to prune trace history: {err}"),
+                Err(err) => warn!("Failed to prune trace history: {err}"),
+            }
+        }
+    }
+}
+
+/// Collects the events of a trace as they are streamed and stores the trace once the stream is
+/// dropped, whether or not it completed
+pub(crate) struct Recorder {
+    store: Arc<TraceStore>,
+    client: SocketAddr,
+    started_at: u64,
+    options: TraceOptions,
+    report: TraceReport,
+    completed: bool,
+}
+
+impl Recorder {
+    pub(crate) fn new(
+        store: Arc<TraceStore>,
+        trace_id: String,
+        client: SocketAddr,
+        options: TraceOptions,
+    ) -> Self {
+        Self {
+            store,
+            client,
+            started_at: unix_millis(),
+            options,
+            report: TraceReport::new(trace_id),
+            completed: false,
+        }
+    }
+
+    pub(crate) fn add(&mut self, event: &anyhow::Result<TraceEvent>) {
+        if matches!(event, Ok(TraceEvent::Done)) {
+            self.completed = true;
+        }
+        self.report.add(event);
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        self.report.finish();
+        let finished_at = unix_millis();
+        let record = TraceRecord {
+            client: self.client,
+            started_at: self.started_at,
+            finished_at,
+            completed: self.completed,
+            options: self.options,
+            report: &self.report,
+        };
+        let record = match serde_json::to_string(&record) {
+            Ok(record) => record,
+            Err(err) => {
+                warn!("Failed to serialize trace {}: {err}", self.report.trace_id);
+                return;
+            }
+        };
+
+        let store = self.store.clone();
+        let id = self.report.trace_id.clone();
+        let client = self.client.ip();
+        tokio::task::spawn_blocking(move || {
+            match store.insert(&id, client, finished_at, &record) {
+                Ok(()) => debug!("Stored trace {id}"),
+                Err(err) => warn!("Failed to store trace {id}: {err:#}"),
+            }
+        });
+    }
+}
+
+pub(crate) fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    const HOUR: u64 = 60 * 60 * 1000;
+
+    fn store() -> Arc<TraceStore> {
+        let db = rusqlite::Connection::open_in_memory().unwrap();
+        Arc::new(TraceStore::new(db, Duration::from_secs(24 * 60 * 60)).unwrap())
+    }
+
+    fn ip(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn gets_trace_by_id() {
+        let store = store();
+        store
+            .insert("a", ip("192.0.2.1"), 1, &json!({ "n": 1 }).to_string())
+            .unwrap();
+        // Storing a trace again replaces it
+        store
+            .insert("a", ip("192.0.2.1"), 2, &json!({ "n": 2 }).to_string())
+            .unwrap();
+
+        assert_eq!(
+            store.get("a".to_owned()).await.unwrap(),
+            Some(json!({ "n": 2 }))
+        );
+        assert_eq!(store.get("b".to_owned()).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn lists_client_traces_newest_first() {
+        let store = store();
+        for (id, client, finished_at) in [
+            ("old", "192.0.2.1", 1),
+            ("other", "192.0.2.2", 2),
+            ("new", "192.0.2.1", 3),
+            ("v6", "2001:db8::1", 4),
+        ] {
+            let record = json!({ "id": id }).to_string();
+            store.insert(id, ip(client), finished_at, &record).unwrap();
+        }
+
+        let ids = |records: Vec<Value>| -> Vec<Value> {
+            records
+                .into_iter()
+                .map(|record| record["id"].clone())
+                .collect()
+        };
+        assert_eq!(
+            ids(store.by_client(ip("192.0.2.1")).await.unwrap()),
+            [json!("new"), json!("old")]
+        );
+        assert_eq!(
+            ids(store.by_client(ip("2001:db8::1")).await.unwrap()),
+            [json!("v6")]
+        );
+        assert!(store.by_client(ip("192.0.2.3")).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn prunes_traces_past_retention() {
+        let store = store();
+        let now = unix_millis();
+        let client = ip("192.0.2.1");
+        store
+            .insert("expired", client, now - 25 * HOUR, "{}")
+            .unwrap();
+        store.insert("kept", client, now - 23 * HOUR, "{}").unwrap();
+
+        assert_eq!(store.prune(now).unwrap(), 1);
+        assert_eq!(store.get("expired".to_owned()).await.unwrap(), None);
+        assert!(store.get("kept".to_owned()).await.unwrap().is_some());
+        assert_eq!(store.prune(now).unwrap(), 0);
+    }
+
+    // The recorder stores its trace in the background once dropped
+    async fn stored(store: &Arc<TraceStore>, id: &str) -> Value {
+        tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                if let Some(record) = store.get(id.to_owned()).await.unwrap() {
+                    return record;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("trace not stored")
+    }
+
+    #[tokio::test]
+    async fn recorder_stores_trace_when_dropped() {
+        let store = store();
+        let client: SocketAddr = "192.0.2.1:4711".parse().unwrap();
+
+        let mut recorder =
+            Recorder::new(store.clone(), "done".to_owned(), client, Default::default());
+        recorder.add(&Err(anyhow::anyhow!("probe failed")));
+        recorder.add(&Ok(TraceEvent::Done));
+        drop(recorder);
+
+        let mut recorder =
+            Recorder::new(store.clone(), "cut".to_owned(), client, Default::default());
+        recorder.add(&Err(anyhow::anyhow!("connection reset")));
+        drop(recorder);
+
+        let record = stored(&store, "done").await;
+        assert_eq!(record["client"], "192.0.2.1:4711");
+        assert_eq!(record["completed"], true);
+        assert_eq!(record["report"]["trace_id"], "done");
+        assert_eq!(record["report"]["errors"], json!(["probe failed"]));
+        assert!(record["finished_at"].as_u64() >= record["started_at"].as_u64());
+
+        let record = stored(&store, "cut").await;
+        assert_eq!(record["completed"], false);
+        assert_eq!(store.by_client(client.ip()).await.unwrap().len(), 2);
+    }
+}
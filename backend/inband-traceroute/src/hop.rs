@@ -37,6 +37,55 @@ impl fmt::Display for HopType {
     }
 }
 
+/// Header field that carries the probe's TTL back to us in the ICMP quote
+#[derive(Debug, Clone, Copy, Serialize)]
+pub(crate) enum TtlField {
+    Ipv4Identification,
+    Ipv6PayloadLength,
+}
+
+/// IP header fields a probe was sent with
+#[derive(Debug, Clone, Copy, Serialize)]
+pub(crate) struct ProbeHeaders {
+    /// IPv4 TOS or IPv6 traffic class
+    pub(crate) tos: u8,
+    /// IPv6 flow label, `None` for IPv4
+    pub(crate) flow_label: Option<u32>,
+    /// Whether `tos` and `flow_label` were copied from the connection (false if no packet of the
+    /// connection had been seen yet and defaults were used)
+    pub(crate) observed: bool,
+    pub(crate) ttl_field: TtlField,
+}
+
+/// DSCP and ECN a probe was sent with compared to what the hop quoted back, i.e. how the probe
+/// looked when it reached the hop. A change between two consecutive hops means the earlier one
+/// (or the link after it) re-marked or bleached the packet.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub(crate) struct TosTraversal {
+    pub(crate) sent_dscp: u8,
+    pub(crate) sent_ecn: u8,
+    pub(crate) quoted_dscp: u8,
+    pub(crate) quoted_ecn: u8,
+    /// ECN was sent as ECT(0)/ECT(1)/CE but arrived as Not-ECT
+    pub(crate) ecn_cleared: bool,
+    pub(crate) dscp_remarked: bool,
+}
+
+impl TosTraversal {
+    pub(crate) fn new(sent: u8, quoted: u8) -> Self {
+        let (sent_dscp, sent_ecn) = (sent >> 2, sent & 0b11);
+        let (quoted_dscp, quoted_ecn) = (quoted >> 2, quoted & 0b11);
+        Self {
+            sent_dscp,
+            sent_ecn,
+            quoted_dscp,
+            quoted_ecn,
+            ecn_cleared: sent_ecn != 0 && quoted_ecn == 0,
+            dscp_remarked: sent_dscp != quoted_dscp,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize)]
 pub(crate) struct Hop {
     pub(crate) ttl: u8,
@@ -44,6 +93,8 @@ pub(crate) struct Hop {
     pub(crate) addr: Option<IpAddr>,
     pub(crate) rtt: Option<u64>,
     pub(crate) enriched_info: Option<IpinfoCountryASN<'static>>,
+    pub(crate) probe: Option<ProbeHeaders>,
+    pub(crate) tos: Option<TosTraversal>,
 }
 
 impl Hop {
@@ -52,6 +103,7 @@ impl Hop {
         hop_type: HopType,
         addr: Option<IpAddr>,
         rtt: Option<u64>,
+        probe: Option<ProbeHeaders>,
         ipdb: &'static Reader<Vec<u8>>,
     ) -> Self {
         Self {
@@ -60,10 +112,36 @@ impl Hop {
             addr,
             rtt,
             enriched_info: addr.and_then(|addr| ipdb.lookup::<IpinfoCountryASN>(addr).unwrap()),
+            probe,
+            tos: None,
         }
     }
 }
 
+/// Result of the path MTU discovery phase
+#[derive(Debug, Clone, Default, Serialize)]
+pub(crate) struct PathMtu {
+    /// Largest probe (IP packet size) acknowledged by the client
+    pub(crate) mtu: Option<u16>,
+    /// Next-hop MTU from the last ICMP Fragmentation Needed / Packet Too Big
+    pub(crate) reported_mtu: Option<u32>,
+    /// Hop that sent it
+    pub(crate) constrained_by: Option<IpAddr>,
+    /// TTL of that hop, if it also answered the hop-by-hop trace
+    pub(crate) constrained_ttl: Option<u8>,
+    /// Probe sizes that got neither an ACK nor an ICMP error, i.e. an MTU black hole
+    pub(crate) black_holed: Vec<u16>,
+}
+
+/// RTT to a hop while bulk data was flowing on the connection, next to the idle RTT
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct LoadedHop {
+    pub(crate) ttl: u8,
+    pub(crate) addr: Option<IpAddr>,
+    pub(crate) idle_rtt: Option<u64>,
+    pub(crate) loaded_rtt: Option<u64>,
+}
+
 impl fmt::Display for Hop {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}: {}", self.ttl, self.hop_type)?;
@@ -73,6 +151,14 @@ impl fmt::Display for Hop {
         if let Some(rtt) = self.rtt {
             write!(f, " (rtt {}ms)", rtt / 1000000)?;
         }
+        if let Some(tos) = self.tos {
+            if tos.ecn_cleared {
+                write!(f, " [ECN cleared]")?;
+            }
+            if tos.dscp_remarked {
+                write!(f, " [DSCP {} -> {}]", tos.sent_dscp, tos.quoted_dscp)?;
+            }
+        }
         Ok(())
     }
 }
@@ -0,0 +1,432 @@
+use aws_lc_rs::digest;
+use serde::Serialize;
+
+// JA4 fingerprints of TLS ClientHellos, see https://github.com/FoxIO-LLC/ja4
+
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+
+const EXT_SERVER_NAME: u16 = 0x0000;
+const EXT_SIGNATURE_ALGORITHMS: u16 = 0x000d;
+const EXT_ALPN: u16 = 0x0010;
+const EXT_SUPPORTED_VERSIONS: u16 = 0x002b;
+
+const SERVER_NAME_TYPE_HOST_NAME: u8 = 0;
+
+// Hash of an empty list
+const EMPTY_HASH: &str = "000000000000";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ParseError {
+    /// More bytes are needed to tell
+    Incomplete,
+    /// Not a ClientHello
+    Invalid,
+}
+
+/// The fields of a ClientHello that go into its fingerprint, in the order the client sent them
+#[derive(Debug, Default)]
+pub(crate) struct ClientHello {
+    pub(crate) legacy_version: u16,
+    pub(crate) cipher_suites: Vec<u16>,
+    pub(crate) extensions: Vec<u16>,
+    pub(crate) server_name: Option<String>,
+    pub(crate) alpn: Vec<Vec<u8>>,
+    pub(crate) signature_algorithms: Vec<u16>,
+    pub(crate) supported_versions: Vec<u16>,
+}
+
+/// What the client offered in its ClientHello, as reported with a trace
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct ClientHelloInfo {
+    pub(crate) ja4: String,
+    pub(crate) sni: Option<String>,
+    pub(crate) alpn: Vec<String>,
+}
+
+impl ClientHello {
+    /// Parse the ClientHello at the start of a connection, which may span several TLS records
+    pub(crate) fn parse(data: &[u8]) -> Result<Self, ParseError> {
+        let message = handshake_message(data)?;
+        let mut reader = Reader::new(&message[4..]);
+
+        let mut hello = Self {
+            legacy_version: reader.u16()?,
+            ..Default::default()
+        };
+        // Random and session ID
+        reader.take(32)?;
+        reader.vec8()?;
+
+        let mut cipher_suites = reader.vec16()?;
+        while !cipher_suites.is_empty() {
+            hello.cipher_suites.push(cipher_suites.u16()?);
+        }
+        // Compression methods
+        reader.vec8()?;
+
+        if reader.is_empty() {
+            return Ok(hello);
+        }
+        let mut extensions = reader.vec16()?;
+        while !extensions.is_empty() {
+            let extension = extensions.u16()?;
+            let mut body = extensions.vec16()?;
+            hello.extensions.push(extension);
+
+            match extension {
+                EXT_SERVER_NAME => {
+                    let mut names = body.vec16()?;
+                    while !names.is_empty() {
+                        let name_type = names.u8()?;
+                        let name = names.vec16()?;
+                        if name_type == SERVER_NAME_TYPE_HOST_NAME {
+                            hello.server_name =
+                                Some(String::from_utf8_lossy(name.data).into_owned());
+                        }
+                    }
+                }
+                EXT_SIGNATURE_ALGORITHMS => {
+                    let mut algorithms = body.vec16()?;
+                    while !algorithms.is_empty() {
+                        hello.signature_algorithms.push(algorithms.u16()?);
+                    }
+                }
+                EXT_ALPN => {
+                    let mut protocols = body.vec16()?;
+                    while !protocols.is_empty() {
+                        hello.alpn.push(protocols.vec8()?.data.to_vec());
+                    }
+                }
+                EXT_SUPPORTED_VERSIONS => {
+                    let mut versions = body.vec8()?;
+                    while !versions.is_empty() {
+                        hello.supported_versions.push(versions.u16()?);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(hello)
+    }
+
+    /// JA4 fingerprint, e.g. "t13d1516h2_8daaf6152771_e5627efa2ab1"
+    pub(crate) fn ja4(&self) -> String {
+        let version = self
+            .supported_versions
+            .iter()
+            .copied()
+            .filter(|version| !is_grease(*version))
+            .max()
+            .unwrap_or(self.legacy_version);
+        let version = match version {
+            0x0304 => "13",
+            0x0303 => "12",
+            0x0302 => "11",
+            0x0301 => "10",
+            0x0300 => "s3",
+            0x0002 => "s2",
+            _ => "00",
+        };
+        let sni = if self.extensions.contains(&EXT_SERVER_NAME) {
+            'd'
+        } else {
+            'i'
+        };
+
+        let mut cipher_suites: Vec<u16> = self
+            .cipher_suites
+            .iter()
+            .copied()
+            .filter(|suite| !is_grease(*suite))
+            .collect();
+        let extensions: Vec<u16> = self
+            .extensions
+            .iter()
+            .copied()
+            .filter(|extension| !is_grease(*extension))
+            .collect();
+
+        let alpn = match self.alpn.first() {
+            Some(alpn) if !alpn.is_empty() => alpn_chars(alpn),
+            _ => "00".to_owned(),
+        };
+
+        let prefix = format!(
+            "t{version}{sni}{:02}{:02}{alpn}",
+            cipher_suites.len().min(99),
+            extensions.len().min(99)
+        );
+
+        cipher_suites.sort_unstable();
+        let cipher_hash = truncated_hash(&hex_list(&cipher_suites));
+
+        // SNI and ALPN are already in the prefix
+        let mut sorted_extensions: Vec<u16> = extensions
+            .into_iter()
+            .filter(|extension| *extension != EXT_SERVER_NAME && *extension != EXT_ALPN)
+            .collect();
+        sorted_extensions.sort_unstable();
+        let extension_hash = if sorted_extensions.is_empty() {
+            EMPTY_HASH.to_owned()
+        } else {
+            let mut extensions = hex_list(&sorted_extensions);
+            if !self.signature_algorithms.is_empty() {
+                extensions.push('_');
+                extensions.push_str(&hex_list(&self.signature_algorithms));
+            }
+            truncated_hash(&extensions)
+        };
+
+        format!("{prefix}_{cipher_hash}_{extension_hash}")
+    }
+
+    pub(crate) fn info(&self) -> ClientHelloInfo {
+        ClientHelloInfo {
+            ja4: self.ja4(),
+            sni: self.server_name.clone(),
+            alpn: self
+                .alpn
+                .iter()
+                .map(|alpn| String::from_utf8_lossy(alpn).into_owned())
+                .collect(),
+        }
+    }
+}
+
+/// The first handshake message, reassembled from its records
+fn handshake_message(mut records: &[u8]) -> Result<Vec<u8>, ParseError> {
+    let mut message = Vec::new();
+    loop {
+        if message
+            .first()
+            .is_some_and(|kind| *kind != HANDSHAKE_CLIENT_HELLO)
+        {
+            return Err(ParseError::Invalid);
+        }
+        if message.len() >= 4 {
+            let len = 4 + u32::from_be_bytes([0, message[1], message[2], message[3]]) as usize;
+            if message.len() >= len {
+                message.truncate(len);
+                return Ok(message);
+            }
+        }
+
+        // Checked byte by byte, so anything else is rejected without waiting for more
+        match records {
+            [] => return Err(ParseError::Incomplete),
+            [content_type, ..] if *content_type != CONTENT_TYPE_HANDSHAKE => {
+                return Err(ParseError::Invalid)
+            }
+            [_, major, ..] if *major != 3 => return Err(ParseError::Invalid),
+            [_, _, _, len_high, len_low, rest @ ..] => {
+                let len = u16::from_be_bytes([*len_high, *len_low]) as usize;
+                if rest.len() < len {
+                    return Err(ParseError::Incomplete);
+                }
+                message.extend_from_slice(&rest[..len]);
+                records = &rest[len..];
+            }
+            _ => return Err(ParseError::Incomplete),
+        }
+    }
+}
+
+// GREASE values (RFC 8701) are 0x0a0a, 0x1a1a, ... 0xfafa
+fn is_grease(value: u16) -> bool {
+    value & 0x0f0f == 0x0a0a && value >> 8 == value & 0xff
+}
+
+fn alpn_chars(alpn: &[u8]) -> String {
+    let first = alpn[0];
+    let last = alpn[alpn.len() - 1];
+    if first.is_ascii_alphanumeric() && last.is_ascii_alphanumeric() {
+        format!("{}{}", first as char, last as char)
+    } else {
+        // First and last characters of the hex representation
+        format!("{:x}{:x}", first >> 4, last & 0xf)
+    }
+}
+
+fn hex_list(values: &[u16]) -> String {
+    values
+        .iter()
+        .map(|value| format!("{value:04x}"))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+// First 12 hex characters of the SHA-256
+fn truncated_hash(list: &str) -> String {
+    if list.is_empty() {
+        return EMPTY_HASH.to_owned();
+    }
+    digest::digest(&digest::SHA256, list.as_bytes())
+        .as_ref()
+        .iter()
+        .take(6)
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], ParseError> {
+        if self.data.len() < len {
+            return Err(ParseError::Invalid);
+        }
+        let (taken, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, ParseError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, ParseError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    /// A vector with a one byte length
+    fn vec8(&mut self) -> Result<Reader<'a>, ParseError> {
+        let len = self.u8()? as usize;
+        Ok(Reader::new(self.take(len)?))
+    }
+
+    /// A vector with a two byte length
+    fn vec16(&mut self) -> Result<Reader<'a>, ParseError> {
+        let len = self.u16()? as usize;
+        Ok(Reader::new(self.take(len)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Cipher suites, extensions and signature algorithms of the Chrome ClientHello in JA4's
+    // documentation, with GREASE added
+    const CIPHER_SUITES: &[u16] = &[
+        0x8a8a, 0x1301, 0x1302, 0x1303, 0xc02b, 0xc02f, 0xc02c, 0xc030, 0xcca9, 0xcca8, 0xc013,
+        0xc014, 0x009c, 0x009d, 0x002f, 0x0035,
+    ];
+    const EXTENSIONS: &[u16] = &[
+        0x3a3a, 0x0000, 0x0017, 0xff01, 0x000a, 0x000b, 0x0023, 0x0010, 0x0005, 0x000d, 0x0012,
+        0x0033, 0x002d, 0x002b, 0x001b, 0x4469, 0x0015,
+    ];
+    const SIGNATURE_ALGORITHMS: &[u16] = &[
+        0x0403, 0x0804, 0x0401, 0x0503, 0x0805, 0x0501, 0x0806, 0x0601,
+    ];
+
+    fn vec8(body: &[u8]) -> Vec<u8> {
+        [&[body.len() as u8], body].concat()
+    }
+
+    fn vec16(body: &[u8]) -> Vec<u8> {
+        [&(body.len() as u16).to_be_bytes(), body].concat()
+    }
+
+    fn u16_list(values: &[u16]) -> Vec<u8> {
+        values
+            .iter()
+            .flat_map(|value| value.to_be_bytes())
+            .collect()
+    }
+
+    fn extension_body(extension: u16) -> Vec<u8> {
+        match extension {
+            EXT_SERVER_NAME => vec16(&[&[0], &vec16(b"example.com")[..]].concat()),
+            EXT_ALPN => vec16(&[vec8(b"h2"), vec8(b"http/1.1")].concat()),
+            EXT_SIGNATURE_ALGORITHMS => vec16(&u16_list(SIGNATURE_ALGORITHMS)),
+            EXT_SUPPORTED_VERSIONS => vec8(&u16_list(&[0x5a5a, 0x0304, 0x0303])),
+            _ => Vec::new(),
+        }
+    }
+
+    fn client_hello() -> Vec<u8> {
+        let extensions: Vec<u8> = EXTENSIONS
+            .iter()
+            .flat_map(|extension| {
+                [
+                    &extension.to_be_bytes()[..],
+                    &vec16(&extension_body(*extension)),
+                ]
+                .concat()
+            })
+            .collect();
+        let body = [
+            &[0x03, 0x03][..],
+            &[0; 32],
+            &vec8(&[7; 32]),
+            &vec16(&u16_list(CIPHER_SUITES)),
+            &vec8(&[0]),
+            &vec16(&extensions),
+        ]
+        .concat();
+        let len = (body.len() as u32).to_be_bytes();
+        let message = [&[HANDSHAKE_CLIENT_HELLO, len[1], len[2], len[3]][..], &body].concat();
+        [&[CONTENT_TYPE_HANDSHAKE, 0x03, 0x01][..], &vec16(&message)].concat()
+    }
+
+    #[test]
+    fn test_ja4() {
+        let hello = ClientHello::parse(&client_hello()).unwrap();
+        assert_eq!(hello.ja4(), "t13d1516h2_8daaf6152771_e5627efa2ab1");
+        assert_eq!(hello.server_name.as_deref(), Some("example.com"));
+        assert_eq!(hello.alpn, [b"h2".to_vec(), b"http/1.1".to_vec()]);
+    }
+
+    #[test]
+    fn test_split_records() {
+        let record = client_hello();
+        let message = &record[5..];
+        let (first, second) = message.split_at(100);
+        let records = [
+            &[CONTENT_TYPE_HANDSHAKE, 0x03, 0x01][..],
+            &vec16(first),
+            &[CONTENT_TYPE_HANDSHAKE, 0x03, 0x01],
+            &vec16(second),
+        ]
+        .concat();
+
+        let hello = ClientHello::parse(&records).unwrap();
+        assert_eq!(hello.ja4(), "t13d1516h2_8daaf6152771_e5627efa2ab1");
+    }
+
+    #[test]
+    fn test_incomplete() {
+        let record = client_hello();
+        for len in [0, 1, 5, 100, record.len() - 1] {
+            assert_eq!(
+                ClientHello::parse(&record[..len]).unwrap_err(),
+                ParseError::Incomplete
+            );
+        }
+    }
+
+    #[test]
+    fn test_not_tls() {
+        assert_eq!(
+            ClientHello::parse(b"GET / HTTP/1.1\r\n").unwrap_err(),
+            ParseError::Invalid
+        );
+        assert_eq!(
+            ClientHello::parse(b"PRI * HTTP/2.0\r\n").unwrap_err(),
+            ParseError::Invalid
+        );
+    }
+}
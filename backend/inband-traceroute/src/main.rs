@@ -1,27 +1,49 @@
+mod acme;
+mod annotation;
+mod campaign;
+mod certs;
+mod classic;
+mod cli;
+mod conn;
 mod dns;
 mod ebpf;
+mod fanout;
+mod fingerprint;
+mod grpc;
+mod history;
 mod hop;
+mod ja4;
+mod outbound;
 mod raw;
+mod replay;
+mod report;
 mod server;
+mod text;
+mod text_service;
 mod tracer;
+mod ws;
 
 use std::{
     net::{IpAddr, SocketAddr},
     path::PathBuf,
     sync::Arc,
+    time::Duration,
 };
 
 use anyhow::Context;
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use ebpf::start_event_processor;
 use inband_traceroute_common::EbpfConfig;
 use log::info;
-use tokio::{signal, sync::Mutex};
+use tokio::signal;
 use tracing_subscriber::EnvFilter;
 
 #[derive(Debug, Parser)]
-#[command(version, about)]
+#[command(version, about, subcommand_negates_reqs = true)]
 struct Opt {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     #[arg(short, long, default_value = "eth0")]
     iface: String,
 
@@ -34,8 +56,8 @@ struct Opt {
     ipv6: Option<std::net::Ipv6Addr>,
 
     /// Domain for TLS certificate
-    #[arg(short, long = "domain", required = true)]
-    domain: String,
+    #[arg(short, long = "domain", required_unless_present_any = ["no_tls", "tls_cert"])]
+    domain: Option<String>,
 
     /// Contact info for TLS certificate
     #[arg(short, long = "email")]
@@ -52,6 +74,22 @@ struct Opt {
     /// (see https://letsencrypt.org/docs/staging-environment/)
     #[clap(long)]
     prod: bool,
+
+    /// ACME directory URL to use instead of Let's Encrypt, e.g. step-ca, ZeroSSL or Pebble
+    #[arg(long, conflicts_with = "prod")]
+    acme_directory: Option<String>,
+
+    /// Additional PEM CA certificates to trust when talking to the ACME directory
+    #[arg(long)]
+    acme_ca_cert: Option<PathBuf>,
+
+    /// Key ID for ACME External Account Binding
+    #[arg(long, requires = "eab_hmac_key")]
+    eab_kid: Option<String>,
+
+    /// Base64url HMAC key for ACME External Account Binding
+    #[arg(long, requires = "eab_kid")]
+    eab_hmac_key: Option<String>,
     /// Maximum number of hops
     #[arg(long, default_value = "32")]
     max_hops: u8,
@@ -61,6 +99,69 @@ struct Opt {
 
     #[arg(long, default_value = "true")]
     v4_v6_subdomains: bool,
+
+    /// Serve /outbound, which traces a connection from this node to a public host:port. Needs
+    /// --outbound-token or --outbound-allow.
+    #[arg(long)]
+    enable_outbound: bool,
+
+    /// Bearer token /outbound requests must carry
+    #[arg(long, requires = "enable_outbound")]
+    outbound_token: Option<String>,
+
+    /// Network /outbound may trace, e.g. 192.0.2.0/24. Private and special-purpose addresses are
+    /// refused regardless.
+    #[arg(long = "outbound-allow", requires = "enable_outbound")]
+    outbound_allow: Vec<ipnet::IpNet>,
+
+    /// Outbound traces each client (IPv4 address or IPv6 /64) may start per minute
+    #[arg(long, default_value = "10")]
+    outbound_rate_limit: u32,
+
+    /// Periodically trace the targets in this JSON campaign file
+    #[arg(long)]
+    campaigns: Option<PathBuf>,
+
+    /// Also stream traces as plain text to any TCP client connecting to this port
+    #[arg(long)]
+    raw_tcp_port: Option<u16>,
+
+    /// Serve cleartext HTTP/1.1 and h2c instead of getting a certificate through ACME, for lab
+    /// networks without a public domain
+    #[arg(long, conflicts_with = "tls_cert")]
+    no_tls: bool,
+
+    /// PEM certificate chain to serve instead of getting one through ACME. Reloaded on SIGHUP or
+    /// when the file changes.
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key for --tls-cert
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// SQLite database to store every trace in, served at /traces/{id} and /traces
+    #[arg(long)]
+    history_db: Option<PathBuf>,
+
+    /// Days to keep traces in the history
+    #[arg(long, default_value = "30")]
+    history_retention_days: u64,
+
+    /// Name of this node in the Start event of each trace, defaults to --domain or the hostname
+    #[arg(long)]
+    node_name: Option<String>,
+
+    /// NAT64 prefix in use besides 64:ff9b::/96, e.g. a network-specific one. Traces of
+    /// addresses in these prefixes are annotated with the embedded IPv4 address.
+    #[arg(long = "nat64-prefix", value_parser = annotation::parse_nat64_prefix)]
+    nat64_prefixes: Vec<ipnet::Ipv6Net>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Trace a connection to host:port from this machine and print the hops, instead of serving
+    Trace(cli::TraceArgs),
 }
 
 #[tokio::main]
@@ -93,6 +194,10 @@ async fn main() -> anyhow::Result<()> {
             .context("Failed to connect to DNS server")?,
     );
 
+    if let Some(Command::Trace(args)) = &opt.command {
+        return cli::run_trace(&opt, args, reader, dns_client).await;
+    }
+
     info!("Loading eBPF program...");
 
     let ebpf_config = EbpfConfig::new(
@@ -104,11 +209,9 @@ async fn main() -> anyhow::Result<()> {
     );
 
     // Note: program will be detached when dropped
-    let (mut ebpf, trace_map) =
+    let (mut ebpf, maps) =
         ebpf::setup_ebpf(&opt.iface, &ebpf_config).context("EBPF setup failed")?;
 
-    let trace_map = Arc::new(Mutex::new(trace_map));
-
     info!("Initializing raw sockets...");
 
     let tracer_v4 = opt
@@ -117,7 +220,7 @@ async fn main() -> anyhow::Result<()> {
             tracer::Tracer::new(
                 SocketAddr::new(IpAddr::V4(ipv4), opt.port),
                 opt.max_hops,
-                trace_map.clone(),
+                maps.clone(),
                 reader,
                 dns_client.clone(),
             )
@@ -132,7 +235,7 @@ async fn main() -> anyhow::Result<()> {
             tracer::Tracer::new(
                 SocketAddr::new(IpAddr::V6(ipv6), opt.port),
                 opt.max_hops,
-                trace_map,
+                maps,
                 reader,
                 dns_client,
             )
@@ -143,16 +246,75 @@ async fn main() -> anyhow::Result<()> {
 
     start_event_processor(&mut ebpf, tracer_v4.clone(), tracer_v6.clone())?;
 
+    let history = opt
+        .history_db
+        .as_deref()
+        .map(|path| {
+            history::TraceStore::open(
+                path,
+                Duration::from_secs(opt.history_retention_days * 24 * 60 * 60),
+            )
+        })
+        .transpose()?;
+
+    let node_name = match opt.node_name.clone().or_else(|| opt.domain.clone()) {
+        Some(name) => name,
+        None => nix::unistd::gethostname()
+            .context("Failed to get hostname")?
+            .to_string_lossy()
+            .into_owned(),
+    };
+
+    let outbound = opt
+        .enable_outbound
+        .then(|| {
+            outbound::OutboundPolicy::new(
+                opt.outbound_token.clone(),
+                opt.outbound_allow.clone(),
+                opt.outbound_rate_limit,
+            )
+        })
+        .transpose()?;
+
     let state = Arc::new(server::AppState {
+        node_name,
         tracer_v4,
         tracer_v6,
+        acme_status: Default::default(),
+        history,
+        replay: Default::default(),
+        traces: Default::default(),
+        nat64_prefixes: opt.nat64_prefixes.clone(),
+        outbound,
     });
 
-    info!("Setting up server...");
+    if let Some(path) = &opt.campaigns {
+        info!("Starting measurement campaigns...");
+        let config = campaign::CampaignConfig::load(path)?;
+        campaign::start_campaigns(config, state.clone())?;
+    }
 
-    server::setup_server(&opt, state);
+    info!("Setting up server...");
 
-    info!("Access server at https://{}:{}/", opt.domain, opt.port);
+    if let Some(port) = opt.raw_tcp_port {
+        text_service::setup_text_service(&opt, port, state.clone()).await?;
+    }
+
+    server::setup_server(&opt, state)?;
+
+    let scheme = if opt.no_tls { "http" } else { "https" };
+    match &opt.domain {
+        Some(domain) => info!("Access server at {scheme}://{domain}:{}/", opt.port),
+        None => {
+            let ips = [opt.ipv4.map(IpAddr::V4), opt.ipv6.map(IpAddr::V6)];
+            for ip in ips.into_iter().flatten() {
+                info!(
+                    "Access server at {scheme}://{}/",
+                    SocketAddr::new(ip, opt.port)
+                );
+            }
+        }
+    }
 
     info!("Server started. Press Ctrl+C to stop.");
 
@@ -0,0 +1,361 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use aws_lc_rs::constant_time;
+use http::{header, HeaderMap, StatusCode};
+use ipnet::IpNet;
+use tokio::{
+    net::{TcpSocket, TcpStream},
+    time::timeout,
+};
+use tokio_rustls::{
+    client::TlsStream,
+    rustls::{pki_types::ServerName, ClientConfig, RootCertStore},
+    TlsConnector,
+};
+
+use crate::conn::Connection;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+
+// Special-purpose ranges (RFC 6890 and its updates) that /outbound must not reach: private, shared,
+// loopback and link-local networks, and ranges that embed an IPv4 address of any of those
+const REFUSED_NETWORKS: [&str; 27] = [
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.0.0.0/24",
+    "192.0.2.0/24",
+    "192.88.99.0/24",
+    "192.168.0.0/16",
+    "198.18.0.0/15",
+    "198.51.100.0/24",
+    "203.0.113.0/24",
+    "224.0.0.0/4",
+    "240.0.0.0/4",
+    "::/128",
+    "::1/128",
+    "::ffff:0:0/96",
+    "64:ff9b::/96",
+    "64:ff9b:1::/48",
+    "100::/64",
+    "2001::/32",
+    "2001:db8::/32",
+    "2002::/16",
+    "fc00::/7",
+    "fe80::/10",
+    "ff00::/8",
+];
+
+/// A connection we opened to a third-party service in order to trace it, kept open for as long
+/// as it is traced
+#[derive(Debug)]
+pub(crate) struct OutboundConnection {
+    pub(crate) connection: Arc<Connection>,
+    _stream: OutboundStream,
+}
+
+#[derive(Debug)]
+enum OutboundStream {
+    Tcp(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl OutboundConnection {
+    /// Connect to `target`, from `local_ip` if given, completing a TLS handshake for
+    /// `server_name` if given
+    pub(crate) async fn connect(
+        local_ip: Option<IpAddr>,
+        target: SocketAddr,
+        server_name: Option<&str>,
+    ) -> anyhow::Result<Self> {
+        let socket = match target {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        // Probes are sent from the address the XDP program watches
+        if let Some(local_ip) = local_ip {
+            socket
+                .bind(SocketAddr::new(local_ip, 0))
+                .context("Failed to bind outbound socket")?;
+        }
+
+        let stream = timeout(CONNECT_TIMEOUT, socket.connect(target))
+            .await
+            .with_context(|| format!("Timed out connecting to {target}"))?
+            .with_context(|| format!("Failed to connect to {target}"))?;
+        let connection = Arc::new(Connection::new(&stream)?);
+
+        let stream = match server_name {
+            None => OutboundStream::Tcp(stream),
+            Some(name) => {
+                let name = ServerName::try_from(name.to_owned())
+                    .with_context(|| format!("Invalid TLS server name {name}"))?;
+                let stream = timeout(CONNECT_TIMEOUT, tls_connector().connect(name, stream))
+                    .await
+                    .with_context(|| format!("Timed out in TLS handshake with {target}"))?
+                    .with_context(|| format!("TLS handshake with {target} failed"))?;
+                OutboundStream::Tls(Box::new(stream))
+            }
+        };
+
+        Ok(Self {
+            connection,
+            _stream: stream,
+        })
+    }
+}
+
+/// Who may start outbound traces through the HTTP API, and to where
+#[derive(Debug)]
+pub(crate) struct OutboundPolicy {
+    /// Bearer token requests must carry
+    token: Option<String>,
+    /// Networks targets must be in, if any are given
+    allow: Vec<IpNet>,
+    /// Traces each client may start per `RATE_LIMIT_WINDOW`
+    rate_limit: u32,
+    // Start of each client's window and the traces started in it
+    clients: Mutex<HashMap<IpAddr, (Instant, u32)>>,
+}
+
+/// Why an outbound trace request was refused
+#[derive(Debug)]
+pub(crate) enum Refusal {
+    Unauthorized,
+    RateLimited,
+    /// None of the target's addresses may be traced
+    Target(String),
+}
+
+impl fmt::Display for Refusal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unauthorized => write!(f, "Missing or wrong bearer token"),
+            Self::RateLimited => write!(f, "Too many outbound traces, try again later"),
+            Self::Target(target) => write!(f, "{target} may not be traced from this node"),
+        }
+    }
+}
+
+impl std::error::Error for Refusal {}
+
+impl Refusal {
+    pub(crate) fn status(&self) -> StatusCode {
+        match self {
+            Self::Unauthorized => StatusCode::UNAUTHORIZED,
+            Self::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            Self::Target(_) => StatusCode::FORBIDDEN,
+        }
+    }
+}
+
+impl OutboundPolicy {
+    /// Requests need `token` if given, and targets must be in `allow` if it isn't empty. Public
+    /// addresses are the only ones allowed either way.
+    pub(crate) fn new(
+        token: Option<String>,
+        allow: Vec<IpNet>,
+        rate_limit: u32,
+    ) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            token.is_some() || !allow.is_empty(),
+            "--enable-outbound needs --outbound-token or --outbound-allow"
+        );
+        Ok(Self {
+            token,
+            allow,
+            rate_limit,
+            clients: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Check the request's token and count it against `client`'s rate limit
+    pub(crate) fn authorize(&self, headers: &HeaderMap, client: IpAddr) -> Result<(), Refusal> {
+        if let Some(token) = &self.token {
+            let given = headers
+                .get(header::AUTHORIZATION)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.strip_prefix("Bearer "))
+                .unwrap_or_default();
+            constant_time::verify_slices_are_equal(given.as_bytes(), token.as_bytes())
+                .map_err(|_| Refusal::Unauthorized)?;
+        }
+
+        let now = Instant::now();
+        let mut clients = self.clients.lock().unwrap();
+        clients.retain(|_, (start, _)| now.duration_since(*start) < RATE_LIMIT_WINDOW);
+        let (_, started) = clients.entry(rate_limit_key(client)).or_insert((now, 0));
+        if *started >= self.rate_limit {
+            return Err(Refusal::RateLimited);
+        }
+        *started += 1;
+        Ok(())
+    }
+
+    /// Whether a resolved address of the target may be traced
+    pub(crate) fn permits(&self, addr: IpAddr) -> bool {
+        let refused = REFUSED_NETWORKS.iter().any(|network| {
+            network
+                .parse::<IpNet>()
+                .expect("valid network")
+                .contains(&addr)
+        });
+        !refused && (self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&addr)))
+    }
+}
+
+// Clients usually get a whole IPv6 /64, so it counts as one
+fn rate_limit_key(client: IpAddr) -> IpAddr {
+    match client {
+        IpAddr::V4(_) => client,
+        IpAddr::V6(ipv6) => {
+            let prefix = u128::from(ipv6) & !(u128::MAX >> 64);
+            IpAddr::V6(Ipv6Addr::from(prefix))
+        }
+    }
+}
+
+/// The address the kernel would send from to reach `target`
+pub(crate) fn source_addr(target: SocketAddr) -> anyhow::Result<IpAddr> {
+    let unspecified = match target {
+        SocketAddr::V4(_) => IpAddr::from([0; 4]),
+        SocketAddr::V6(_) => IpAddr::from([0; 16]),
+    };
+    // Connecting a UDP socket only looks up the route
+    let socket = std::net::UdpSocket::bind(SocketAddr::new(unspecified, 0))?;
+    socket
+        .connect(target)
+        .with_context(|| format!("No route to {target}"))?;
+    Ok(socket.local_addr()?.ip())
+}
+
+/// Host part of a host:port target, without the brackets of an IPv6 literal
+pub(crate) fn target_host(target: &str) -> anyhow::Result<&str> {
+    let (host, _port) = target
+        .rsplit_once(':')
+        .context("Target must be host:port")?;
+    Ok(host.trim_start_matches('[').trim_end_matches(']'))
+}
+
+fn tls_connector() -> TlsConnector {
+    let roots = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    TlsConnector::from(Arc::new(config))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(allow: &[&str]) -> OutboundPolicy {
+        let allow = allow.iter().map(|net| net.parse().unwrap()).collect();
+        OutboundPolicy::new(Some("secret".to_owned()), allow, 2).unwrap()
+    }
+
+    fn bearer(token: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::AUTHORIZATION,
+            format!("Bearer {token}").parse().unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn refuses_special_addresses() {
+        let policy = policy(&[]);
+        for addr in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.31.255.255",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "255.255.255.255",
+            "::1",
+            "::",
+            "::ffff:127.0.0.1",
+            "64:ff9b::10.0.0.1",
+            "fd00::1",
+            "fe80::1",
+            "ff02::1",
+            "2002:a00:1::",
+        ] {
+            assert!(!policy.permits(addr.parse().unwrap()), "{addr}");
+        }
+    }
+
+    #[test]
+    fn permits_public_addresses() {
+        let policy = policy(&[]);
+        for addr in ["1.1.1.1", "8.8.8.8", "2606:4700::1111", "2a00:1450::1"] {
+            assert!(policy.permits(addr.parse().unwrap()), "{addr}");
+        }
+    }
+
+    #[test]
+    fn allowlist_narrows_targets() {
+        let policy = policy(&["1.1.1.0/24", "10.0.0.0/8"]);
+        assert!(policy.permits("1.1.1.1".parse().unwrap()));
+        assert!(!policy.permits("8.8.8.8".parse().unwrap()));
+        // Still refused when allowed
+        assert!(!policy.permits("10.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn needs_token_or_allowlist() {
+        assert!(OutboundPolicy::new(None, Vec::new(), 1).is_err());
+        assert!(OutboundPolicy::new(None, vec!["1.1.1.0/24".parse().unwrap()], 1).is_ok());
+    }
+
+    #[test]
+    fn checks_token() {
+        let policy = policy(&[]);
+        let client = "192.0.2.1".parse().unwrap();
+        assert!(matches!(
+            policy.authorize(&HeaderMap::new(), client),
+            Err(Refusal::Unauthorized)
+        ));
+        assert!(matches!(
+            policy.authorize(&bearer("wrong"), client),
+            Err(Refusal::Unauthorized)
+        ));
+        assert!(policy.authorize(&bearer("secret"), client).is_ok());
+    }
+
+    #[test]
+    fn rate_limits_clients() {
+        let policy = policy(&[]);
+        let headers = bearer("secret");
+        let client = "2001:db8::1".parse().unwrap();
+        assert!(policy.authorize(&headers, client).is_ok());
+        // Same /64
+        assert!(policy
+            .authorize(&headers, "2001:db8::2".parse().unwrap())
+            .is_ok());
+        assert!(matches!(
+            policy.authorize(&headers, client),
+            Err(Refusal::RateLimited)
+        ));
+        assert!(policy
+            .authorize(&headers, "2001:db8:1::1".parse().unwrap())
+            .is_ok());
+    }
+}
@@ -1,4 +1,5 @@
 use anyhow::Context;
+use inband_traceroute_common::PROBE_MARK;
 use libc::{IPPROTO_RAW, SOCK_RAW};
 use socket2::{Domain, SockAddr, Socket};
 use tokio::io::unix::AsyncFd;
@@ -17,6 +18,9 @@ impl AsyncWriteOnlyIPRawSocket {
         socket
             .set_nonblocking(true)
             .context("Failed to set nonblocking")?;
+        socket
+            .set_mark(PROBE_MARK)
+            .context("Failed to set socket mark")?;
 
         let inner = AsyncFd::new(socket)?;
         Ok(AsyncWriteOnlyIPRawSocket { inner })
@@ -0,0 +1,132 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+// How long the events of a trace are kept after its last event
+const REPLAY_TTL: Duration = Duration::from_secs(10 * 60);
+
+const MAX_REPLAY_TRACES: usize = 1024;
+
+/// SSE events of recent traces, so a reconnecting EventSource can be sent what it missed
+#[derive(Debug, Default)]
+pub(crate) struct ReplayBuffers {
+    logs: Mutex<HashMap<String, ReplayLog>>,
+}
+
+#[derive(Debug)]
+struct ReplayLog {
+    /// Event data by sequence number
+    events: Vec<String>,
+    complete: bool,
+    updated: Instant,
+}
+
+/// Events of an earlier trace after the client's `Last-Event-ID`
+#[derive(Debug)]
+pub(crate) struct Resume {
+    pub(crate) trace_id: String,
+    /// Sequence numbers and data
+    pub(crate) events: Vec<(u64, String)>,
+    /// Whether the trace ran to completion, so there is nothing to continue
+    pub(crate) complete: bool,
+}
+
+/// SSE event ID of the event with sequence number `seq` in a trace
+pub(crate) fn event_id(trace_id: &str, seq: u64) -> String {
+    format!("{trace_id}-{seq}")
+}
+
+impl ReplayBuffers {
+    /// Add the event with sequence number `seq` to the trace's log, unless another subscriber of
+    /// the trace already did
+    pub(crate) fn push(&self, trace_id: &str, seq: u64, data: String, done: bool) {
+        let mut logs = self.logs.lock().unwrap();
+        if !logs.contains_key(trace_id) {
+            logs.retain(|_, log| log.updated.elapsed() < REPLAY_TTL);
+            if logs.len() >= MAX_REPLAY_TRACES {
+                let oldest = logs
+                    .iter()
+                    .min_by_key(|(_, log)| log.updated)
+                    .map(|(trace_id, _)| trace_id.clone());
+                if let Some(oldest) = oldest {
+                    logs.remove(&oldest);
+                }
+            }
+        }
+
+        let log = logs
+            .entry(trace_id.to_owned())
+            .or_insert_with(|| ReplayLog {
+                events: Vec::new(),
+                complete: false,
+                updated: Instant::now(),
+            });
+        if log.events.len() as u64 == seq {
+            log.events.push(data);
+            log.complete |= done;
+            log.updated = Instant::now();
+        }
+    }
+
+    /// The events following `last_event_id`, if its trace is still known
+    pub(crate) fn resume(&self, last_event_id: &str) -> Option<Resume> {
+        let (trace_id, seq) = last_event_id.rsplit_once('-')?;
+        // IDs past the last sequence number are invalid, not a wrap back to the start
+        let next = usize::try_from(seq.parse::<u64>().ok()?)
+            .ok()?
+            .checked_add(1)?;
+
+        let logs = self.logs.lock().unwrap();
+        let log = logs.get(trace_id)?;
+        let events = log
+            .events
+            .iter()
+            .enumerate()
+            .skip(next)
+            .map(|(seq, data)| (seq as u64, data.clone()))
+            .collect();
+
+        Some(Resume {
+            trace_id: trace_id.to_owned(),
+            events,
+            complete: log.complete,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffers() -> ReplayBuffers {
+        let buffers = ReplayBuffers::default();
+        for seq in 0..3 {
+            buffers.push("trace", seq, format!("event {seq}"), seq == 2);
+        }
+        buffers
+    }
+
+    #[test]
+    fn resumes_after_last_event() {
+        let resume = buffers().resume(&event_id("trace", 0)).unwrap();
+        assert_eq!(resume.trace_id, "trace");
+        assert_eq!(
+            resume.events,
+            [(1, "event 1".to_owned()), (2, "event 2".to_owned())]
+        );
+        assert!(resume.complete);
+
+        assert!(buffers().resume("trace-2").unwrap().events.is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_ids() {
+        let buffers = buffers();
+        assert!(buffers.resume("other-0").is_none());
+        assert!(buffers.resume("trace").is_none());
+        assert!(buffers.resume("trace-x").is_none());
+        assert!(buffers.resume(&event_id("trace", u64::MAX)).is_none());
+    }
+}
@@ -0,0 +1,274 @@
+use std::fmt;
+
+use futures::{Stream, StreamExt};
+use serde::Serialize;
+
+use crate::{
+    annotation::Annotation,
+    classic::ClassicTrace,
+    conn::ConnectionStats,
+    hop::{Hop, HopType, LoadedHop, PathMtu},
+    server::{TraceEvent, TraceStart},
+};
+
+/// A completed trace as one document
+#[derive(Debug, Default, Serialize)]
+pub(crate) struct TraceReport {
+    pub(crate) trace_id: String,
+    pub(crate) start: Option<TraceStart>,
+    /// Findings that change what the trace describes, e.g. a proxy in front of the client
+    pub(crate) annotations: Vec<Annotation>,
+    pub(crate) hops: Vec<ReportHop>,
+    pub(crate) path_mtu: Option<PathMtu>,
+    pub(crate) hops_under_load: Vec<LoadedHop>,
+    pub(crate) classic_traces: Vec<ClassicTrace>,
+    /// TCP_INFO samples taken while the trace ran
+    pub(crate) connection_stats: Vec<ConnectionStats>,
+    pub(crate) summary: TraceSummary,
+    pub(crate) errors: Vec<String>,
+}
+
+/// A hop merged with its reverse DNS result
+#[derive(Debug, Serialize)]
+pub(crate) struct ReportHop {
+    #[serde(flatten)]
+    pub(crate) hop: Hop,
+    pub(crate) reverse_dns: Option<Result<String, String>>,
+}
+
+impl TraceReport {
+    pub(crate) fn new(trace_id: String) -> Self {
+        Self {
+            trace_id,
+            ..Default::default()
+        }
+    }
+
+    /// Wait for the trace to finish and collect its events
+    pub(crate) async fn collect(
+        trace_id: String,
+        events: impl Stream<Item = anyhow::Result<TraceEvent>>,
+    ) -> Self {
+        let mut report = Self::new(trace_id);
+        let mut events = std::pin::pin!(events);
+        while let Some(event) = events.next().await {
+            report.add(&event);
+        }
+        report.finish();
+        report
+    }
+
+    /// Sort the hops and fill in the summary, once all events have been added
+    pub(crate) fn finish(&mut self) {
+        self.hops.sort_by_key(|hop| hop.hop.ttl);
+        self.summary = TraceSummary::new(self.hops.iter().map(|hop| &hop.hop));
+        self.summary.reverse_hops = self
+            .start
+            .as_ref()
+            .and_then(|start| start.syn.as_ref())
+            .map(|syn| syn.reverse_hops);
+    }
+
+    pub(crate) fn add(&mut self, event: &anyhow::Result<TraceEvent>) {
+        match event {
+            Ok(TraceEvent::Hop(hop)) => self.hops.push(ReportHop {
+                hop: hop.clone(),
+                reverse_dns: None,
+            }),
+            Ok(TraceEvent::ReverseDns { ttl, name, .. }) => {
+                if let Some(hop) = self.hops.iter_mut().find(|hop| hop.hop.ttl == *ttl) {
+                    hop.reverse_dns = Some(name.clone());
+                }
+            }
+            Ok(TraceEvent::PathMtu(path_mtu)) => self.path_mtu = Some(path_mtu.clone()),
+            Ok(TraceEvent::HopUnderLoad(hop)) => self.hops_under_load.push(hop.clone()),
+            Ok(TraceEvent::ClassicTrace(trace)) => self.classic_traces.push(trace.clone()),
+            Ok(TraceEvent::ConnectionStats(stats)) => self.connection_stats.push(stats.clone()),
+            Ok(TraceEvent::Start(start)) => self.start = Some(start.clone()),
+            Ok(TraceEvent::Annotation(annotation)) => self.annotations.push(annotation.clone()),
+            Ok(TraceEvent::Padding(_) | TraceEvent::Done) => {}
+            Err(err) => self.errors.push(format!("{err:#}")),
+        }
+    }
+}
+
+/// Whether and how quickly the trace reached the client
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub(crate) struct TraceSummary {
+    pub(crate) reached: bool,
+    /// Highest TTL that was probed
+    pub(crate) hops: u8,
+    /// RTT of the probe that reached the client
+    pub(crate) rtt: Option<u64>,
+    /// Hops from the client back to us, inferred from the TTL of its SYN. A different count than
+    /// `hops` means the route back differs from the traced one.
+    pub(crate) reverse_hops: Option<u8>,
+}
+
+impl TraceSummary {
+    pub(crate) fn new<'a>(hops: impl Iterator<Item = &'a Hop> + Clone) -> Self {
+        let last = hops
+            .clone()
+            .filter(|hop| !matches!(hop.hop_type, HopType::Timeout | HopType::Origin))
+            .max_by_key(|hop| hop.ttl);
+
+        match last {
+            Some(hop) if matches!(hop.hop_type, HopType::TcpAck | HopType::TcpRst) => Self {
+                reached: true,
+                hops: hop.ttl,
+                rtt: hop.rtt,
+                reverse_hops: None,
+            },
+            _ => Self {
+                reached: false,
+                hops: hops.map(|hop| hop.ttl).max().unwrap_or(0),
+                rtt: None,
+                reverse_hops: None,
+            },
+        }
+    }
+}
+
+impl fmt::Display for TraceSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.reached {
+            write!(f, "client not reached after {} hops", self.hops)?;
+        } else {
+            write!(f, "client reached in {} hops", self.hops)?;
+            if let Some(rtt) = self.rtt {
+                write!(f, ", rtt {}ms", rtt / 1000000)?;
+            }
+        }
+        if let Some(reverse_hops) = self.reverse_hops {
+            write!(f, ", {reverse_hops} hops back")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use inband_traceroute_common::{SynFingerprint, SYN_MAX_OPTIONS};
+
+    use super::*;
+    use crate::{fingerprint::ClientSyn, tracer::TraceOptions};
+
+    fn hop(ttl: u8, hop_type: HopType, rtt_ms: u64) -> anyhow::Result<TraceEvent> {
+        let replied = !matches!(hop_type, HopType::Timeout);
+        Ok(TraceEvent::Hop(Hop {
+            ttl,
+            hop_type,
+            addr: replied.then(|| IpAddr::from([192, 0, 2, ttl])),
+            rtt: replied.then_some(rtt_ms * 1_000_000),
+            enriched_info: None,
+            probe: None,
+            tos: None,
+        }))
+    }
+
+    fn start(syn_ttl: u8) -> anyhow::Result<TraceEvent> {
+        let syn = SynFingerprint {
+            ttl: syn_ttl,
+            df: 1,
+            window: 64240,
+            mss: 1460,
+            wscale: 7,
+            option_count: 0,
+            options: [0; SYN_MAX_OPTIONS],
+        };
+        Ok(TraceEvent::Start(TraceStart {
+            trace_id: "1".to_owned(),
+            node_name: "node".to_owned(),
+            node_addr: "192.0.2.100:443".parse().unwrap(),
+            time: 0,
+            client: "198.51.100.7:4711".parse().unwrap(),
+            ip_version: 4,
+            tls: None,
+            client_hello: None,
+            syn: Some(ClientSyn::new(&syn)),
+            options: TraceOptions::default(),
+        }))
+    }
+
+    fn report(events: &[anyhow::Result<TraceEvent>]) -> TraceReport {
+        let mut report = TraceReport::new("1".to_owned());
+        for event in events {
+            report.add(event);
+        }
+        report.finish();
+        report
+    }
+
+    #[test]
+    fn reached_with_final_hop_rtt() {
+        // Hops arrive out of order when probes are answered out of order
+        let report = report(&[
+            hop(0, HopType::Origin, 0),
+            hop(1, HopType::IcmpTimeExceeded, 10),
+            hop(3, HopType::TcpAck, 30),
+            hop(2, HopType::IcmpTimeExceeded, 20),
+            Ok(TraceEvent::Done),
+        ]);
+
+        let ttls: Vec<_> = report.hops.iter().map(|hop| hop.hop.ttl).collect();
+        assert_eq!(ttls, [0, 1, 2, 3]);
+        assert!(report.summary.reached);
+        assert_eq!(report.summary.hops, 3);
+        assert_eq!(report.summary.rtt, Some(30_000_000));
+        assert_eq!(report.summary.reverse_hops, None);
+        assert!(report.errors.is_empty());
+    }
+
+    #[test]
+    fn not_reached_counts_probed_hops() {
+        let report = report(&[
+            hop(1, HopType::IcmpTimeExceeded, 10),
+            hop(2, HopType::Timeout, 0),
+            hop(3, HopType::Timeout, 0),
+        ]);
+
+        assert!(!report.summary.reached);
+        assert_eq!(report.summary.hops, 3);
+        assert_eq!(report.summary.rtt, None);
+        assert_eq!(
+            report.summary.to_string(),
+            "client not reached after 3 hops"
+        );
+    }
+
+    #[test]
+    fn reverse_hops_from_client_syn() {
+        let report = report(&[start(57), hop(1, HopType::TcpRst, 5)]);
+
+        assert_eq!(report.summary.reverse_hops, Some(8));
+        assert_eq!(
+            report.summary.to_string(),
+            "client reached in 1 hops, rtt 5ms, 8 hops back"
+        );
+    }
+
+    #[test]
+    fn merges_reverse_dns_and_collects_errors() {
+        let report = report(&[
+            hop(1, HopType::IcmpTimeExceeded, 10),
+            Err(anyhow::anyhow!("no reply").context("probe failed")),
+            Ok(TraceEvent::ReverseDns {
+                ttl: 1,
+                ip: IpAddr::from([192, 0, 2, 1]),
+                name: Ok("one.example".to_owned()),
+            }),
+            Err(anyhow::anyhow!("connection reset")),
+        ]);
+
+        assert_eq!(
+            report.hops[0].reverse_dns,
+            Some(Ok("one.example".to_owned()))
+        );
+        assert_eq!(
+            report.errors,
+            ["probe failed: no reply", "connection reset"]
+        );
+    }
+}
@@ -2,108 +2,483 @@ use std::{
     convert::Infallible,
     net::{IpAddr, SocketAddr},
     sync::Arc,
+    time::Duration,
 };
 
+use anyhow::Context;
 use async_stream::stream;
 use axum::{
     body::Body,
-    extract::{ConnectInfo, State},
-    http::HeaderValue,
-    response::{sse::Event, Response, Sse},
-    routing::get,
-    Router,
+    extract::{ws::WebSocketUpgrade, ConnectInfo, Path, Query, State},
+    http::{header, HeaderMap, HeaderValue, Request, StatusCode},
+    middleware::AddExtension,
+    response::{sse::Event, IntoResponse, Response, Sse},
+    routing::{any, get},
+    Extension, Json, Router,
+};
+use axum_server::{
+    accept::{Accept, DefaultAcceptor},
+    service::SendService,
+    tls_rustls::{RustlsAcceptor, RustlsConfig},
 };
 use futures::Stream;
 use http::request::Parts as RequestParts;
-use hyper::Method;
+use hyper::{body::Incoming, Method};
+use ipnet::Ipv6Net;
 use log::{error, info};
-use rustls_acme::{caches::DirCache, AcmeConfig};
-use tokio_stream::{wrappers::UnboundedReceiverStream, StreamExt};
+use rand::{rngs::OsRng, Rng};
+use rustls_acme::{
+    acme::{LETS_ENCRYPT_PRODUCTION_DIRECTORY, LETS_ENCRYPT_STAGING_DIRECTORY},
+    AcmeConfig,
+};
+use serde::Deserialize;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+    sync::{mpsc, oneshot},
+};
+use tokio_stream::{
+    wrappers::{ReceiverStream, UnboundedReceiverStream},
+    StreamExt,
+};
 use tower_http::{
     cors::{AllowOrigin, CorsLayer},
     trace::{self, TraceLayer},
 };
 use tracing::{warn, Level};
 
-use crate::tracer::{TraceHandle, Tracer};
+use crate::{
+    acme::{self, AcmeCache, AcmeStatus, ExternalAccountBinding},
+    annotation::Annotation,
+    classic::ClassicTrace,
+    conn::{Connection, ConnectionAcceptor, ConnectionStats, TlsInfo},
+    fanout::{ConnectionTraces, SharedTrace},
+    fingerprint::ClientSyn,
+    grpc::GrpcService,
+    history::{self, Recorder, TraceStore},
+    hop::{HopType, LoadedHop, PathMtu},
+    ja4::ClientHelloInfo,
+    outbound::{self, OutboundPolicy, Refusal},
+    replay::{self, ReplayBuffers},
+    report::TraceReport,
+    text,
+    tracer::{TraceHandle, TraceOptions, Tracer},
+    ws,
+};
 
-#[derive(serde::Serialize, Debug)]
+#[derive(serde::Serialize, Debug, Clone)]
 pub enum TraceEvent {
+    Start(TraceStart),
     Hop(crate::hop::Hop),
     ReverseDns {
         ttl: u8,
         ip: IpAddr,
         name: Result<String, String>,
     },
+    PathMtu(PathMtu),
+    HopUnderLoad(LoadedHop),
+    ClassicTrace(ClassicTrace),
+    ConnectionStats(ConnectionStats),
+    /// Sent after `Start` for each finding that changes what the trace describes
+    Annotation(Annotation),
+    /// Bulk data for the load test, sent as an SSE comment of this many bytes
+    #[serde(skip)]
+    Padding(usize),
     Done,
 }
 
+/// First event of every trace, with the connection as the server sees it
+#[derive(serde::Serialize, Debug, Clone)]
+pub(crate) struct TraceStart {
+    pub(crate) trace_id: String,
+    pub(crate) node_name: String,
+    pub(crate) node_addr: SocketAddr,
+    /// Unix time in milliseconds
+    pub(crate) time: u64,
+    /// The client's address after any NAT along the way
+    pub(crate) client: SocketAddr,
+    pub(crate) ip_version: u8,
+    pub(crate) tls: Option<TlsInfo>,
+    pub(crate) client_hello: Option<ClientHelloInfo>,
+    /// The client's SYN, if XDP saw it arrive
+    pub(crate) syn: Option<ClientSyn>,
+    pub(crate) options: TraceOptions,
+}
+
+impl TraceStart {
+    pub(crate) fn new(
+        trace_id: String,
+        node_name: String,
+        connection: &Connection,
+        syn: Option<ClientSyn>,
+        options: TraceOptions,
+    ) -> Self {
+        Self {
+            trace_id,
+            node_name,
+            node_addr: connection.local,
+            time: history::unix_millis(),
+            client: connection.remote,
+            ip_version: if connection.remote.is_ipv4() { 4 } else { 6 },
+            tls: connection.tls.get().cloned(),
+            client_hello: connection.client_hello.clone(),
+            syn,
+            options,
+        }
+    }
+}
+
+/// A random ID for a new trace
+pub(crate) fn new_trace_id() -> String {
+    format!("{:016x}", OsRng.gen::<u64>())
+}
+
+const PADDING_CHUNK: usize = 16 * 1024;
+
+// Only a few chunks are queued so the padding is paced by the connection
+pub(crate) const PADDING_QUEUE: usize = 4;
+
+const MAX_LOAD: u64 = 64 * 1024 * 1024;
+
+// Give the bulk transfer time to open its congestion window before probing under load
+const LOAD_RAMP_UP: Duration = Duration::from_secs(1);
+
+// How often TCP_INFO is sampled while a trace runs
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
+
 #[derive(Debug)]
 pub(crate) struct AppState {
+    /// Name of this server, as shown to clients
+    pub(crate) node_name: String,
     pub(crate) tracer_v4: Option<Arc<crate::tracer::Tracer>>,
     pub(crate) tracer_v6: Option<Arc<crate::tracer::Tracer>>,
+    pub(crate) acme_status: AcmeStatus,
+    pub(crate) history: Option<Arc<TraceStore>>,
+    pub(crate) replay: ReplayBuffers,
+    pub(crate) traces: ConnectionTraces,
+    /// NAT64 prefixes in use besides the well-known one
+    pub(crate) nat64_prefixes: Vec<Ipv6Net>,
+    /// Who may use /outbound, which is only served if set
+    pub(crate) outbound: Option<OutboundPolicy>,
+}
+
+/// Target of an outbound trace, taken from the query string
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct OutboundTarget {
+    /// host:port to connect to
+    pub(crate) target: String,
+    /// Complete a TLS handshake before tracing, so firewalls see an established TLS session
+    #[serde(default)]
+    pub(crate) tls: bool,
+    /// Server name for TLS, defaults to the host part of `target`
+    pub(crate) sni: Option<String>,
 }
 
 impl AppState {
-    fn get_tracer(&self, remote: SocketAddr) -> Arc<crate::tracer::Tracer> {
-        match remote {
+    pub(crate) fn get_tracer(&self, remote: SocketAddr) -> Arc<crate::tracer::Tracer> {
+        self.tracer_for(remote).expect(
+            "If we got a connection in this protocol, the program should have a tracer for it",
+        )
+    }
+
+    fn tracer_for(&self, addr: SocketAddr) -> Option<Arc<crate::tracer::Tracer>> {
+        match addr {
             SocketAddr::V4(_) => self.tracer_v4.clone(),
             SocketAddr::V6(_) => self.tracer_v6.clone(),
         }
-        .expect("If we got a connection in this protocol, the program should have a tracer for it")
     }
 
-    async fn trace_stream_inner(
+    pub(crate) async fn trace_stream_inner(
         tracer: Arc<Tracer>,
         trace_handle: Arc<TraceHandle>,
         tx: &tokio::sync::mpsc::UnboundedSender<anyhow::Result<TraceEvent>>,
+        padding_tx: mpsc::Sender<anyhow::Result<TraceEvent>>,
     ) -> anyhow::Result<()> {
-        let mut hop_stream = Box::pin(trace_handle.hop_stream().await?);
-        while let Some(hop) = hop_stream.next().await {
-            let addr = hop.addr;
-            let ttl = hop.ttl;
-            tx.send(Ok(TraceEvent::Hop(hop))).unwrap();
-            if let Some(ip) = addr {
-                let tx = tx.clone();
-                let dns_client = tracer.dns_client.clone();
-                tokio::spawn(async move {
-                    tx.send(Ok(TraceEvent::ReverseDns {
-                        ttl,
-                        ip,
-                        name: dns_client
-                            .reverse_lookup(&ip)
-                            .await
-                            .map_err(|err| err.to_string()),
-                    }))
+        // RTT of the final ACK hop, to compare TCP's own RTT estimate with
+        let path_rtt = std::sync::Mutex::new(None);
+
+        let inband = async {
+            let mut hops = Vec::new();
+            let mut hop_stream = Box::pin(trace_handle.hop_stream().await?);
+            while let Some(hop) = hop_stream.next().await {
+                let hop = hop?;
+                let addr = hop.addr;
+                let ttl = hop.ttl;
+                if matches!(hop.hop_type, HopType::TcpAck) {
+                    *path_rtt.lock().unwrap() = hop.rtt;
+                }
+                hops.push(hop.clone());
+                tx.send(Ok(TraceEvent::Hop(hop))).unwrap();
+                if let Some(ip) = addr {
+                    let tx = tx.clone();
+                    let dns_client = tracer.dns_client.clone();
+                    tokio::spawn(async move {
+                        tx.send(Ok(TraceEvent::ReverseDns {
+                            ttl,
+                            ip,
+                            name: dns_client
+                                .reverse_lookup(&ip)
+                                .await
+                                .map_err(|err| err.to_string()),
+                        }))
+                        .unwrap();
+                    });
+                }
+            }
+            anyhow::Ok(hops)
+        };
+
+        let trace = async {
+            let hops = if trace_handle.options().classic {
+                let (hops, classic) = tokio::join!(inband, trace_handle.classic_traces());
+                let hops = hops?;
+                for (method, classic_hops) in classic? {
+                    tx.send(Ok(TraceEvent::ClassicTrace(ClassicTrace::new(
+                        method,
+                        classic_hops,
+                        &hops,
+                    ))))
                     .unwrap();
-                });
+                }
+                hops
+            } else {
+                inband.await?
+            };
+
+            if trace_handle.options().pmtu {
+                let path_mtu = trace_handle.path_mtu(&hops).await?;
+                tx.send(Ok(TraceEvent::PathMtu(path_mtu))).unwrap();
             }
+
+            if let Some(load) = trace_handle.options().load {
+                let (stop_tx, stop_rx) = oneshot::channel();
+                tokio::spawn(Self::send_padding(padding_tx, load.min(MAX_LOAD), stop_rx));
+                tokio::time::sleep(LOAD_RAMP_UP).await;
+
+                let loaded = trace_handle.rtt_under_load(&hops).await;
+                let _ = stop_tx.send(());
+
+                for hop in loaded? {
+                    tx.send(Ok(TraceEvent::HopUnderLoad(hop))).unwrap();
+                }
+            }
+            anyhow::Ok(())
+        };
+
+        let stats = async {
+            let mut interval = tokio::time::interval(STATS_INTERVAL);
+            loop {
+                interval.tick().await;
+                if !Self::send_connection_stats(&trace_handle, &path_rtt, tx) {
+                    break;
+                }
+            }
+            // The trace goes on without them
+            std::future::pending::<()>().await
+        };
+
+        tokio::select! {
+            res = trace => res?,
+            () = stats => {}
         }
+        // A last sample with everything the trace measured
+        Self::send_connection_stats(&trace_handle, &path_rtt, tx);
         Ok(())
     }
 
-    async fn trace_stream(
+    /// Send a `ConnectionStats` event, returning false if TCP_INFO can't be read
+    fn send_connection_stats(
+        trace_handle: &TraceHandle,
+        path_rtt: &std::sync::Mutex<Option<u64>>,
+        tx: &mpsc::UnboundedSender<anyhow::Result<TraceEvent>>,
+    ) -> bool {
+        match trace_handle.connection_stats() {
+            Ok(mut stats) => {
+                stats.path_rtt = *path_rtt.lock().unwrap();
+                tx.send(Ok(TraceEvent::ConnectionStats(stats))).unwrap();
+                true
+            }
+            Err(err) => {
+                warn!("{err:#}");
+                false
+            }
+        }
+    }
+
+    async fn send_padding(
+        padding_tx: mpsc::Sender<anyhow::Result<TraceEvent>>,
+        mut remaining: u64,
+        mut stop: oneshot::Receiver<()>,
+    ) {
+        while remaining > 0 {
+            let len = remaining.min(PADDING_CHUNK as u64) as usize;
+            tokio::select! {
+                _ = &mut stop => break,
+                res = padding_tx.send(Ok(TraceEvent::Padding(len))) => {
+                    if res.is_err() {
+                        break;
+                    }
+                }
+            }
+            remaining -= len as u64;
+        }
+    }
+
+    /// Start a trace of the connection that other requests on it can attach to, returning it with
+    /// the events to publish and the padding. It runs after any other trace of the connection.
+    async fn start_shared_trace(
         &self,
-        remote: SocketAddr,
-    ) -> anyhow::Result<impl Stream<Item = anyhow::Result<TraceEvent>>> {
+        connection: Arc<Connection>,
+        headers: &HeaderMap,
+        options: TraceOptions,
+    ) -> anyhow::Result<(
+        Arc<SharedTrace>,
+        impl Stream<Item = anyhow::Result<TraceEvent>>,
+        mpsc::Receiver<anyhow::Result<TraceEvent>>,
+    )> {
+        let remote = connection.remote;
         let tracer = self.get_tracer(remote);
+        let trace_id = new_trace_id();
 
-        info!("Remote: {remote:?}");
+        info!("Remote: {remote:?}, trace {trace_id}");
 
-        let trace_handle = TraceHandle::start_trace(tracer.clone(), remote).await?;
+        let start = TraceStart::new(
+            trace_id.clone(),
+            self.node_name.clone(),
+            &connection,
+            tracer.client_syn(&connection),
+            options,
+        );
+        let annotations = Annotation::detect(remote, headers, &self.nat64_prefixes);
+        let trace_handle =
+            TraceHandle::start_trace(tracer.clone(), connection.clone(), options).await?;
 
         // channels automatically close when all senders are dropped
         let (tx, rx) = tokio::sync::mpsc::unbounded_channel::<anyhow::Result<TraceEvent>>();
+        let (padding_tx, padding_rx) = mpsc::channel(PADDING_QUEUE);
 
         tokio::spawn(async move {
-            if let Err(err) = Self::trace_stream_inner(tracer, trace_handle, &tx).await {
+            let _round = trace_handle.start_round(options).await;
+            if let Err(err) =
+                Self::trace_stream_inner(tracer, trace_handle.clone(), &tx, padding_tx).await
+            {
                 tx.send(Err(err)).unwrap();
             }
         });
 
-        Ok(UnboundedReceiverStream::new(rx).chain(stream! {
+        let events = futures::stream::iter(
+            std::iter::once(TraceEvent::Start(start))
+                .chain(annotations.into_iter().map(TraceEvent::Annotation))
+                .map(Ok),
+        )
+        .chain(UnboundedReceiverStream::new(rx))
+        .chain(stream! {
             yield Ok(TraceEvent::Done);
-        }))
+        });
+
+        let trace = Arc::new(SharedTrace::new(trace_id.clone(), connection, options));
+        self.traces.insert(&trace);
+
+        let mut recorder = self
+            .history
+            .as_ref()
+            .map(|store| Recorder::new(store.clone(), trace_id, remote, options));
+        let events = stream! {
+            for await event in events {
+                if let Some(recorder) = &mut recorder {
+                    recorder.add(&event);
+                }
+                yield event;
+            }
+        };
+
+        Ok((trace, events, padding_rx))
+    }
+
+    /// Trace the connection, returning the trace's ID and its events
+    pub(crate) async fn trace_stream(
+        &self,
+        connection: Arc<Connection>,
+        headers: &HeaderMap,
+        options: TraceOptions,
+    ) -> anyhow::Result<(String, impl Stream<Item = anyhow::Result<TraceEvent>>)> {
+        let remote = connection.remote;
+
+        // Only the request that started a trace gets its padding, so load tests queue their own
+        // trace instead of attaching
+        let attached = match options.load {
+            None => self.traces.attach(&connection, options),
+            Some(_) => None,
+        };
+        let (trace, events, padding_rx) = match attached {
+            Some(trace) => {
+                info!("Remote: {remote:?}, attaching to trace {}", trace.trace_id);
+                let events = trace.subscribe();
+                // Without load there is no padding
+                let (_, padding_rx) = mpsc::channel(1);
+                (trace, events, padding_rx)
+            }
+            None => {
+                let (trace, published, padding_rx) = self
+                    .start_shared_trace(connection, headers, options)
+                    .await?;
+                // Subscribe first, so the trace doesn't stop for lack of subscribers
+                let events = trace.subscribe();
+                tokio::spawn(trace.clone().run(published));
+                (trace, events, padding_rx)
+            }
+        };
+
+        Ok((
+            trace.trace_id.clone(),
+            events.merge(ReceiverStream::new(padding_rx)),
+        ))
+    }
+
+    /// Connect to a third-party service and trace the connection, keeping it open until the
+    /// stream is dropped. Only resolved addresses that are `permitted` are connected to.
+    pub(crate) async fn outbound_trace_stream(
+        &self,
+        target: OutboundTarget,
+        options: TraceOptions,
+        permitted: &(dyn Fn(IpAddr) -> bool + Sync),
+    ) -> anyhow::Result<(String, impl Stream<Item = anyhow::Result<TraceEvent>>)> {
+        let host = outbound::target_host(&target.target)?;
+
+        // Checked on the addresses we connect to, so the name can't resolve differently in between
+        let usable: Vec<_> = tokio::net::lookup_host(&target.target)
+            .await
+            .with_context(|| format!("Failed to resolve {}", target.target))?
+            .filter_map(|addr| self.tracer_for(addr).map(|tracer| (addr, tracer)))
+            .collect();
+        anyhow::ensure!(
+            !usable.is_empty(),
+            "No usable address for {}",
+            target.target
+        );
+        let (addr, tracer) = usable
+            .into_iter()
+            // Tracing our own address would only trace the loopback interface
+            .find(|(addr, tracer)| addr.ip() != tracer.listen_addr().ip() && permitted(addr.ip()))
+            .ok_or_else(|| Refusal::Target(target.target.clone()))?;
+
+        let server_name = target.tls.then(|| target.sni.as_deref().unwrap_or(host));
+        let outbound = tracer.connect_outbound(addr, server_name).await?;
+        info!("Outbound trace to {addr} ({})", target.target);
+
+        let (trace_id, events) = self
+            .trace_stream(outbound.connection.clone(), &HeaderMap::new(), options)
+            .await?;
+
+        Ok((
+            trace_id,
+            stream! {
+                let _outbound = outbound;
+                for await event in events {
+                    yield event;
+                }
+            },
+        ))
     }
 }
 
@@ -115,41 +490,338 @@ async fn index_handler() -> Response {
         .unwrap()
 }
 
+async fn acme_status_handler(state: State<Arc<AppState>>) -> Json<acme::AcmeStatusInner> {
+    Json(state.acme_status.snapshot())
+}
+
 async fn sse_handler(
-    ConnectInfo(remote): ConnectInfo<SocketAddr>,
+    Extension(connection): Extension<Arc<Connection>>,
+    Query(options): Query<TraceOptions>,
+    headers: HeaderMap,
     state: State<Arc<AppState>>,
+) -> Response {
+    if accepts(&headers, "text/plain") {
+        return text_handler(Extension(connection), Query(options), headers, state).await;
+    }
+    if accepts(&headers, "application/x-ndjson") {
+        return ndjson_handler(Extension(connection), Query(options), headers, state).await;
+    }
+
+    // Sent by EventSource when it reconnects
+    let resume = headers
+        .get("last-event-id")
+        .and_then(|id| id.to_str().ok())
+        .and_then(|id| state.replay.resume(id));
+
+    let Some(resume) = resume else {
+        return match state.trace_stream(connection, &headers, options).await {
+            Ok((trace_id, stream_result)) => {
+                sse_events(state.0, trace_id, stream_result).into_response()
+            }
+            Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, format!("{err:#}")).into_response(),
+        };
+    };
+
+    // Nothing was missed, and 204 stops EventSource from reconnecting again
+    if resume.complete && resume.events.is_empty() {
+        return StatusCode::NO_CONTENT.into_response();
+    }
+
+    info!(
+        "Replaying {} events of trace {} to {}",
+        resume.events.len(),
+        resume.trace_id,
+        connection.remote
+    );
+    let replayed = futures::stream::iter(resume.events.into_iter().map(|(seq, data)| {
+        Ok::<_, Infallible>(
+            Event::default()
+                .id(replay::event_id(&resume.trace_id, seq))
+                .data(data),
+        )
+    }));
+
+    // The earlier trace was cut short with its connection, so trace the new one
+    let continuation = if resume.complete {
+        None
+    } else {
+        match state.trace_stream(connection, &headers, options).await {
+            Ok((trace_id, stream_result)) => {
+                Some(sse_events_stream(state.0, trace_id, stream_result))
+            }
+            Err(err) => {
+                return (StatusCode::INTERNAL_SERVER_ERROR, format!("{err:#}")).into_response();
+            }
+        }
+    };
+
+    Sse::new(replayed.chain(stream! {
+        if let Some(continuation) = continuation {
+            for await event in continuation {
+                yield event;
+            }
+        }
+    }))
+    .into_response()
+}
+
+/// Interactive trace over a WebSocket, via HTTP/1.1 upgrade or HTTP/2 extended CONNECT
+async fn ws_handler(
+    ws: WebSocketUpgrade,
+    Extension(connection): Extension<Arc<Connection>>,
+    Query(options): Query<TraceOptions>,
+    headers: HeaderMap,
+    State(state): State<Arc<AppState>>,
+) -> Response {
+    let annotations = Annotation::detect(connection.remote, &headers, &state.nat64_prefixes);
+    ws.on_upgrade(move |socket| ws::serve(socket, state, connection, options, annotations))
+}
+
+/// Traceroute table streamed as plain text, for curl and other terminal clients
+async fn text_handler(
+    Extension(connection): Extension<Arc<Connection>>,
+    Query(options): Query<TraceOptions>,
+    headers: HeaderMap,
+    state: State<Arc<AppState>>,
+) -> Response {
+    if let Err(rejection) = reject_load(&options) {
+        return rejection.into_response();
+    }
+
+    let remote = connection.remote;
+    let (trace_id, stream_result) = match state.trace_stream(connection, &headers, options).await {
+        Ok(trace) => trace,
+        Err(err) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, format!("{err:#}")).into_response();
+        }
+    };
+    let lines = text::text_lines(remote, &trace_id, stream_result)
+        .map(|line| Ok::<_, Infallible>(line + "\n"));
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+        // Keep browsers from buffering the stream to sniff its type
+        .header(header::X_CONTENT_TYPE_OPTIONS, "nosniff")
+        .body(Body::from_stream(lines))
+        .unwrap()
+}
+
+async fn outbound_handler(
+    Extension(connection): Extension<Arc<Connection>>,
+    Query(target): Query<OutboundTarget>,
+    Query(options): Query<TraceOptions>,
+    headers: HeaderMap,
+    state: State<Arc<AppState>>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, (StatusCode, String)> {
+    reject_load(&options)?;
+
+    let policy = state
+        .outbound
+        .as_ref()
+        .expect("/outbound is only served with a policy");
+    policy
+        .authorize(&headers, connection.remote.ip())
+        .map_err(|refusal| (refusal.status(), refusal.to_string()))?;
+
+    let (trace_id, stream_result) = state
+        .outbound_trace_stream(target, options, &|addr| policy.permits(addr))
+        .await
+        .map_err(|err| match err.downcast_ref::<Refusal>() {
+            Some(refusal) => (refusal.status(), refusal.to_string()),
+            None => (StatusCode::BAD_GATEWAY, format!("{err:#}")),
+        })?;
+
+    Ok(sse_events(state.0, trace_id, stream_result))
+}
+
+/// Trace events as newline-delimited JSON, without SSE framing
+async fn ndjson_handler(
+    Extension(connection): Extension<Arc<Connection>>,
+    Query(options): Query<TraceOptions>,
+    headers: HeaderMap,
+    state: State<Arc<AppState>>,
+) -> Response {
+    if let Err(rejection) = reject_load(&options) {
+        return rejection.into_response();
+    }
+
+    let (_, stream_result) = match state.trace_stream(connection, &headers, options).await {
+        Ok(trace) => trace,
+        Err(err) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, format!("{err:#}")).into_response();
+        }
+    };
+    let lines = stream_result.filter_map(|event| match event {
+        Ok(TraceEvent::Padding(_)) => None,
+        Ok(event) => {
+            let mut line = serde_json::to_vec(&event).unwrap();
+            line.push(b'\n');
+            Some(Ok::<_, Infallible>(line))
+        }
+        Err(err) => {
+            warn!("Error: {err}");
+            None
+        }
+    });
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, "application/x-ndjson")
+        .body(Body::from_stream(lines))
+        .unwrap()
+}
+
+/// Wait for the trace to complete and return it as a single JSON document
+async fn json_handler(
+    Extension(connection): Extension<Arc<Connection>>,
+    Query(options): Query<TraceOptions>,
+    headers: HeaderMap,
+    state: State<Arc<AppState>>,
+) -> Result<Json<TraceReport>, (StatusCode, String)> {
+    reject_load(&options)?;
+
+    let (trace_id, stream_result) = state
+        .trace_stream(connection, &headers, options)
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, format!("{err:#}")))?;
+
+    Ok(Json(TraceReport::collect(trace_id, stream_result).await))
+}
+
+// Padding is only streamed as SSE comments on the traced connection. The other formats and
+// outbound traces would report RTTs under load without any load.
+fn reject_load(options: &TraceOptions) -> Result<(), (StatusCode, String)> {
+    if options.load.is_some() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "load is only supported by the SSE endpoint".to_owned(),
+        ));
+    }
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+struct HistoryQuery {
+    /// Defaults to the requester's address, which is the only one allowed
+    client: Option<IpAddr>,
+}
+
+/// A stored trace by ID
+async fn history_handler(
+    Path(trace_id): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let store = state
+        .history
+        .as_ref()
+        .expect("Route is only served with a history");
+    match store.get(trace_id).await {
+        Ok(Some(record)) => Ok(Json(record)),
+        Ok(None) => Err((StatusCode::NOT_FOUND, "No such trace".to_owned())),
+        Err(err) => Err((StatusCode::INTERNAL_SERVER_ERROR, format!("{err:#}"))),
+    }
+}
+
+/// Most recent stored traces of the requester's address. Trace IDs are hard to guess, listing
+/// them for any address would not be.
+async fn history_client_handler(
+    Extension(connection): Extension<Arc<Connection>>,
+    Query(query): Query<HistoryQuery>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Vec<serde_json::Value>>, (StatusCode, String)> {
+    let requester = connection.remote.ip();
+    let client = query.client.unwrap_or(requester);
+    if client != requester {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "Only traces of your own address can be listed".to_owned(),
+        ));
+    }
+
+    let store = state
+        .history
+        .as_ref()
+        .expect("Route is only served with a history");
+    store
+        .by_client(client)
+        .await
+        .map(Json)
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, format!("{err:#}")))
+}
+
+fn accepts(headers: &HeaderMap, mime: &str) -> bool {
+    headers
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.starts_with(mime))
+}
+
+fn sse_events(
+    state: Arc<AppState>,
+    trace_id: String,
+    events: impl Stream<Item = anyhow::Result<TraceEvent>>,
 ) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
-    let stream_result = state.trace_stream(remote).await.unwrap();
-
-    Sse::new(
-        stream_result.filter_map(|event| -> Option<Result<Event, Infallible>> {
-            match event {
-                Ok(event) => {
-                    let event = Event::default().json_data(event).unwrap();
-                    Some(Ok(event))
-                }
-                Err(err) => {
-                    warn!("Error: {err}");
-                    None
-                }
+    Sse::new(sse_events_stream(state, trace_id, events))
+}
+
+/// Events with IDs of the form `<trace id>-<sequence>`, kept for replay to reconnecting clients
+fn sse_events_stream(
+    state: Arc<AppState>,
+    trace_id: String,
+    events: impl Stream<Item = anyhow::Result<TraceEvent>>,
+) -> impl Stream<Item = Result<Event, Infallible>> {
+    // Every subscriber of a trace gets the same events, so they number them alike
+    let mut seq = 0;
+    events.filter_map(move |event| -> Option<Result<Event, Infallible>> {
+        match event {
+            Ok(TraceEvent::Padding(len)) => Some(Ok(Event::default().comment(" ".repeat(len)))),
+            Ok(event) => {
+                let data = serde_json::to_string(&event).unwrap();
+                let done = matches!(event, TraceEvent::Done);
+                state.replay.push(&trace_id, seq, data.clone(), done);
+                let id = replay::event_id(&trace_id, seq);
+                seq += 1;
+                Some(Ok(Event::default().id(id).data(data)))
             }
-        }),
-    )
+            Err(err) => {
+                warn!("Error: {err}");
+                None
+            }
+        }
+    })
 }
 
-pub(crate) fn setup_server(opt: &crate::Opt, state: Arc<AppState>) {
-    let mut domains = vec![opt.domain.clone()];
-    if opt.v4_v6_subdomains {
-        domains.push("ipv4.".to_owned() + &opt.domain);
-        domains.push("ipv6.".to_owned() + &opt.domain);
+// What the make service gives the acceptor for each connection
+type ConnectionService = AddExtension<Router, ConnectInfo<SocketAddr>>;
+
+// Serve the app on each address with the acceptor of the TLS mode. The bounds are those of
+// `axum_server::Server::serve`.
+fn spawn_listeners<A>(addresses: &[SocketAddr], app: &Router, acceptor: A)
+where
+    A: Accept<TcpStream, ConnectionService> + Clone + Send + Sync + 'static,
+    A::Stream: AsyncRead + AsyncWrite + Unpin + Send,
+    A::Service: SendService<Request<Incoming>> + Send,
+    A::Future: Send,
+{
+    for &addr in addresses {
+        info!("Listening on {addr}");
+        let service = app
+            .clone()
+            .into_make_service_with_connect_info::<SocketAddr>();
+        let acceptor = acceptor.clone();
+        tokio::task::spawn(async move {
+            let mut server = axum_server::bind(addr).acceptor(acceptor);
+            // Needed for WebSockets over HTTP/2 (RFC 8441)
+            server.http_builder().http2().enable_connect_protocol();
+            server.serve(service).await.unwrap();
+        });
     }
+}
 
-    let mut acme_state = AcmeConfig::new(domains)
-        .contact(opt.emails.iter().map(|e| format!("mailto:{e}")))
-        .cache_option(opt.cache_dir.clone().map(DirCache::new))
-        .directory_lets_encrypt(opt.prod)
-        .state();
+fn alpn_protocols() -> Vec<Vec<u8>> {
+    vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+}
 
+pub(crate) fn setup_server(opt: &crate::Opt, state: Arc<AppState>) -> anyhow::Result<()> {
     let cors = CorsLayer::new()
         .allow_origin(AllowOrigin::predicate(
             |origin: &HeaderValue, _request_parts: &RequestParts| {
@@ -169,53 +841,120 @@ pub(crate) fn setup_server(opt: &crate::Opt, state: Arc<AppState>) {
         ))
         .allow_methods([Method::GET]);
 
-    let app = Router::new()
+    let mut app = Router::new()
         .route("/", get(index_handler))
         .route("/sse", get(sse_handler))
-        .with_state(state)
-        .layer(cors)
-        .layer(
-            TraceLayer::new_for_http()
-                .make_span_with(trace::DefaultMakeSpan::new().level(Level::INFO))
-                .on_response(
-                    trace::DefaultOnResponse::new()
-                        .level(Level::INFO)
-                        .include_headers(true),
-                )
-                .on_request(trace::DefaultOnRequest::new().level(Level::INFO)),
+        .route("/trace.txt", get(text_handler))
+        .route("/trace.ndjson", get(ndjson_handler))
+        .route("/trace.json", get(json_handler))
+        // HTTP/2 WebSockets use CONNECT instead of GET
+        .route("/ws", any(ws_handler))
+        .route("/acme/status", get(acme_status_handler));
+    if state.outbound.is_some() {
+        app = app.route("/outbound", get(outbound_handler));
+    }
+    if state.history.is_some() {
+        app = app
+            .route("/traces", get(history_client_handler))
+            .route("/traces/{id}", get(history_handler));
+    }
+
+    // gRPC paths don't overlap with the routes above, and gRPC clients negotiate h2 via ALPN
+    let grpc = tonic::service::Routes::new(GrpcService::new(state.clone())).into_axum_router();
+
+    let app = app.with_state(state.clone()).merge(grpc).layer(cors).layer(
+        TraceLayer::new_for_http()
+            .make_span_with(trace::DefaultMakeSpan::new().level(Level::INFO))
+            .on_response(
+                trace::DefaultOnResponse::new()
+                    .level(Level::INFO)
+                    .include_headers(true),
+            )
+            .on_request(trace::DefaultOnRequest::new().level(Level::INFO)),
+    );
+
+    let addresses: Vec<SocketAddr> = [opt.ipv4.map(IpAddr::V4), opt.ipv6.map(IpAddr::V6)]
+        .into_iter()
+        .filter_map(|ip| ip.map(|ip| SocketAddr::new(ip, opt.port)))
+        .collect();
+
+    if opt.no_tls {
+        warn!(
+            "TLS is disabled (--no-tls): serving cleartext HTTP/1.1 and h2c, \
+             only use this on trusted networks"
         );
+        let acceptor = ConnectionAcceptor::new(DefaultAcceptor::new());
+        spawn_listeners(&addresses, &app, acceptor);
+        return Ok(());
+    }
+
+    if let (Some(cert_path), Some(key_path)) = (&opt.tls_cert, &opt.tls_key) {
+        let server_config =
+            crate::certs::load_server_config(cert_path, key_path, alpn_protocols())?;
+        let rustls_config = RustlsConfig::from_config(Arc::new(server_config));
+        crate::certs::watch(
+            rustls_config.clone(),
+            cert_path.clone(),
+            key_path.clone(),
+            alpn_protocols(),
+        )?;
+
+        let acceptor = ConnectionAcceptor::new(RustlsAcceptor::new(rustls_config));
+        spawn_listeners(&addresses, &app, acceptor);
+        return Ok(());
+    }
+
+    let domain = opt
+        .domain
+        .clone()
+        .expect("--domain is required without a subcommand or --no-tls");
+    let mut domains = vec![domain.clone()];
+    if opt.v4_v6_subdomains {
+        domains.push("ipv4.".to_owned() + &domain);
+        domains.push("ipv6.".to_owned() + &domain);
+    }
+
+    let directory_url = opt.acme_directory.clone().unwrap_or_else(|| {
+        if opt.prod {
+            LETS_ENCRYPT_PRODUCTION_DIRECTORY
+        } else {
+            LETS_ENCRYPT_STAGING_DIRECTORY
+        }
+        .to_owned()
+    });
+    let eab = match (&opt.eab_kid, &opt.eab_hmac_key) {
+        (Some(kid), Some(hmac_key)) => Some(ExternalAccountBinding::new(kid, hmac_key)?),
+        _ => None,
+    };
+    let client_config = acme::client_config(opt.acme_ca_cert.as_deref())?;
+
+    let mut acme_state = AcmeConfig::new(domains)
+        .contact(opt.emails.iter().map(|e| format!("mailto:{e}")))
+        .client_tls_config(client_config.clone())
+        .cache(AcmeCache::new(opt.cache_dir.clone(), eab, client_config))
+        .directory(&directory_url)
+        .state();
+    state.acme_status.set_directory(directory_url);
 
     let mut rustls_config = acme_state.default_rustls_config();
-    Arc::get_mut(&mut rustls_config).unwrap().alpn_protocols =
-        vec![b"h2".to_vec(), b"http/1.1".to_vec()];
-    let acceptor = acme_state.axum_acceptor(rustls_config);
+    Arc::get_mut(&mut rustls_config).unwrap().alpn_protocols = alpn_protocols();
+    let acceptor = ConnectionAcceptor::new(acme_state.axum_acceptor(rustls_config));
 
     tokio::spawn(async move {
         loop {
             match acme_state.next().await.unwrap() {
-                Ok(ok) => info!("ACME event: {ok:?}"),
-                Err(err) => error!("ACME error: {err:?}"),
+                Ok(ok) => {
+                    info!("ACME event: {ok:?}");
+                    state.acme_status.record(true, format!("{ok:?}"));
+                }
+                Err(err) => {
+                    error!("ACME error: {err:?}");
+                    state.acme_status.record(false, err.to_string());
+                }
             }
         }
     });
 
-    let addresses: Vec<SocketAddr> = [opt.ipv4.map(IpAddr::V4), opt.ipv6.map(IpAddr::V6)]
-        .into_iter()
-        .filter_map(|ip| ip.map(|ip| SocketAddr::new(ip, opt.port)))
-        .collect();
-
-    for addr in addresses {
-        info!("Listening on {addr}");
-        let service = app
-            .clone()
-            .into_make_service_with_connect_info::<SocketAddr>();
-        let acceptor = acceptor.clone();
-        tokio::task::spawn(async move {
-            axum_server::bind(addr)
-                .acceptor(acceptor)
-                .serve(service)
-                .await
-                .unwrap();
-        });
-    }
+    spawn_listeners(&addresses, &app, acceptor);
+    Ok(())
 }
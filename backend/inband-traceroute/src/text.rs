@@ -0,0 +1,250 @@
+use std::{collections::VecDeque, net::SocketAddr};
+
+use async_stream::stream;
+use futures::Stream;
+
+use crate::{
+    conn::ConnectionStats, fingerprint::ClientSyn, hop::Hop, report::TraceSummary,
+    server::TraceEvent,
+};
+
+// Width of the column holding `Hop`'s Display output
+const HOP_WIDTH: usize = 56;
+const NAME_WIDTH: usize = 40;
+
+/// Render trace events as a traceroute table, one line per hop.
+///
+/// A hop's line is held back until its reverse DNS result arrives, so the name column can be
+/// filled in without reordering the table.
+pub(crate) fn text_lines(
+    remote: SocketAddr,
+    trace_id: &str,
+    events: impl Stream<Item = anyhow::Result<TraceEvent>>,
+) -> impl Stream<Item = String> {
+    let title = format!("inband traceroute to {remote} (trace {trace_id})");
+    stream! {
+        yield title;
+        yield format!("{:<HOP_WIDTH$} {:<NAME_WIDTH$} AS", "hop", "name");
+
+        let mut table = TextTable::default();
+        let mut syn = None;
+        let mut stats = None;
+        for await event in events {
+            match event {
+                Ok(TraceEvent::Start(start)) => syn = start.syn,
+                Ok(TraceEvent::Annotation(annotation)) => {
+                    yield format!("note: {annotation}");
+                }
+                Ok(TraceEvent::Hop(hop)) => table.push(hop),
+                Ok(TraceEvent::ReverseDns { ttl, name, .. }) => table.resolve(ttl, name.ok()),
+                Ok(TraceEvent::PathMtu(path_mtu)) => {
+                    for line in table.flush() {
+                        yield line;
+                    }
+                    let mtu = path_mtu.mtu.map_or("unknown".to_owned(), |mtu| mtu.to_string());
+                    yield format!("path MTU {mtu}");
+                }
+                Ok(TraceEvent::ConnectionStats(latest)) => stats = Some(latest),
+                Ok(TraceEvent::Done) => {
+                    for line in table.flush() {
+                        yield line;
+                    }
+                    yield table.summary(syn.as_ref());
+                    if let Some(syn) = &syn {
+                        yield syn_line(syn);
+                    }
+                    if let Some(stats) = &stats {
+                        yield stats_line(stats);
+                    }
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    yield format!("error: {err:#}");
+                }
+            }
+
+            for line in table.ready() {
+                yield line;
+            }
+        }
+    }
+}
+
+fn syn_line(syn: &ClientSyn) -> String {
+    let mut line = format!(
+        "client syn ttl {} (initial {}), window {}",
+        syn.ttl, syn.initial_ttl, syn.window
+    );
+    if let Some(mss) = syn.mss {
+        line += &format!(", mss {mss}");
+    }
+    if let Some(wscale) = syn.wscale {
+        line += &format!(", wscale {wscale}");
+    }
+    line += &format!(", options {}", syn.options);
+    if let Some(os) = syn.os {
+        line += &format!(", looks like {os}");
+    }
+    line
+}
+
+fn stats_line(stats: &ConnectionStats) -> String {
+    let ms = |ns: u64| ns as f64 / 1e6;
+    let mut line = format!(
+        "tcp rtt {:.1}ms (var {:.1}ms)",
+        ms(stats.rtt),
+        ms(stats.rtt_var)
+    );
+    if let Some(path_rtt) = stats.path_rtt {
+        line += &format!(", traced rtt {:.1}ms", ms(path_rtt));
+    }
+    line += &format!(
+        ", cwnd {}, mss {}, retransmits {}",
+        stats.snd_cwnd, stats.snd_mss, stats.retransmits
+    );
+    if let Some(rate) = stats.delivery_rate {
+        line += &format!(", delivery rate {:.1}Mbit/s", rate as f64 * 8.0 / 1e6);
+    }
+    line
+}
+
+#[derive(Default)]
+struct TextTable {
+    /// Hops not printed yet, with their reverse DNS name once the lookup finished
+    pending: VecDeque<(Hop, Option<Option<String>>)>,
+    printed: Vec<Hop>,
+}
+
+impl TextTable {
+    fn push(&mut self, hop: Hop) {
+        let name = hop.addr.is_none().then_some(None);
+        self.pending.push_back((hop, name));
+    }
+
+    fn resolve(&mut self, ttl: u8, name: Option<String>) {
+        if let Some((_, pending_name)) = self.pending.iter_mut().find(|(hop, _)| hop.ttl == ttl) {
+            *pending_name = Some(name);
+        }
+    }
+
+    /// Lines of the hops at the front whose lookups have finished
+    fn ready(&mut self) -> Vec<String> {
+        let mut lines = Vec::new();
+        while self.pending.front().is_some_and(|(_, name)| name.is_some()) {
+            let (hop, name) = self.pending.pop_front().unwrap();
+            lines.push(self.line(hop, name.flatten()));
+        }
+        lines
+    }
+
+    fn flush(&mut self) -> Vec<String> {
+        let pending = std::mem::take(&mut self.pending);
+        pending
+            .into_iter()
+            .map(|(hop, name)| self.line(hop, name.flatten()))
+            .collect()
+    }
+
+    fn line(&mut self, hop: Hop, name: Option<String>) -> String {
+        let asn = hop
+            .enriched_info
+            .as_ref()
+            .map(|info| {
+                [info.asn, info.as_name]
+                    .into_iter()
+                    .flatten()
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            })
+            .unwrap_or_default();
+        let line = format!(
+            "{:<HOP_WIDTH$} {:<NAME_WIDTH$} {asn}",
+            hop.to_string(),
+            name.unwrap_or_default()
+        );
+        self.printed.push(hop);
+        line.trim_end().to_owned()
+    }
+
+    fn summary(&self, syn: Option<&ClientSyn>) -> String {
+        let mut summary = TraceSummary::new(self.printed.iter());
+        summary.reverse_hops = syn.map(|syn| syn.reverse_hops);
+        summary.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{iter, net::IpAddr};
+
+    use futures::{FutureExt, StreamExt};
+    use tokio::sync::mpsc;
+    use tokio_stream::wrappers::UnboundedReceiverStream;
+
+    use super::*;
+    use crate::hop::HopType;
+
+    fn hop(ttl: u8, hop_type: HopType) -> TraceEvent {
+        TraceEvent::Hop(Hop {
+            ttl,
+            hop_type,
+            addr: (!matches!(hop_type, HopType::Timeout)).then(|| IpAddr::from([192, 0, 2, ttl])),
+            rtt: None,
+            enriched_info: None,
+            probe: None,
+            tos: None,
+        })
+    }
+
+    fn reverse_dns(ttl: u8, name: Result<&str, &str>) -> TraceEvent {
+        TraceEvent::ReverseDns {
+            ttl,
+            ip: IpAddr::from([192, 0, 2, ttl]),
+            name: name.map(str::to_owned).map_err(str::to_owned),
+        }
+    }
+
+    #[tokio::test]
+    async fn hops_wait_for_reverse_dns_in_order() {
+        let (events_tx, events_rx) = mpsc::unbounded_channel();
+        let remote = "198.51.100.7:4711".parse().unwrap();
+        let mut lines = Box::pin(text_lines(
+            remote,
+            "1",
+            UnboundedReceiverStream::new(events_rx),
+        ));
+        // Send `events` and take the lines they let through
+        let mut send = |events: Vec<TraceEvent>| -> Vec<String> {
+            for event in events {
+                events_tx.send(Ok(event)).unwrap();
+            }
+            iter::from_fn(|| lines.next().now_or_never().flatten()).collect()
+        };
+
+        assert_eq!(send(vec![]).len(), 2, "title and header");
+        assert!(send(vec![
+            hop(1, HopType::IcmpTimeExceeded),
+            hop(2, HopType::IcmpTimeExceeded),
+        ])
+        .is_empty());
+        // Hop 2 is resolved, but must not be printed before hop 1
+        assert!(send(vec![reverse_dns(2, Ok("two.example"))]).is_empty());
+        assert!(send(vec![hop(3, HopType::Timeout)]).is_empty());
+
+        // A lookup that timed out releases its hop without a name, and the hops behind it
+        let released = send(vec![reverse_dns(1, Err("request timed out"))]);
+        assert_eq!(released.len(), 3, "{released:?}");
+        assert_eq!(released[0], "1: ICMP Time Exceeded from 192.0.2.1");
+        assert!(released[1].starts_with("2: ICMP Time Exceeded from 192.0.2.2 "));
+        assert!(released[1].ends_with(" two.example"));
+        // Nothing to look up
+        assert_eq!(released[2], "3: [timeout]");
+
+        // Done prints hops still waiting for their lookup
+        assert!(send(vec![hop(4, HopType::TcpAck)]).is_empty());
+        assert_eq!(
+            send(vec![TraceEvent::Done]),
+            ["4: TCP ACK from 192.0.2.4", "client reached in 4 hops"]
+        );
+    }
+}
@@ -0,0 +1,112 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use anyhow::Context;
+use futures::StreamExt;
+use http::HeaderMap;
+use log::{info, warn};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{
+    annotation::Annotation,
+    conn::Connection,
+    server::AppState,
+    tracer::{TraceHandle, TraceOptions},
+};
+
+/// Listen for plain TCP connections and stream each client the trace of its own connection as
+/// text lines, for clients that can't speak TLS or HTTP
+pub(crate) async fn setup_text_service(
+    opt: &crate::Opt,
+    port: u16,
+    state: Arc<AppState>,
+) -> anyhow::Result<()> {
+    let addresses: Vec<SocketAddr> = [opt.ipv4.map(IpAddr::V4), opt.ipv6.map(IpAddr::V6)]
+        .into_iter()
+        .filter_map(|ip| ip.map(|ip| SocketAddr::new(ip, port)))
+        .collect();
+
+    for addr in addresses {
+        let listener = TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("Failed to listen for plain TCP on {addr}"))?;
+        info!("Listening for plain TCP on {addr}");
+        let state = state.clone();
+        tokio::task::spawn(async move {
+            loop {
+                let (stream, remote) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        warn!("Failed to accept plain TCP connection: {err}");
+                        continue;
+                    }
+                };
+
+                let state = state.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = serve_connection(&state, stream).await {
+                        warn!("Plain TCP trace for {remote} failed: {err:#}");
+                    }
+                });
+            }
+        });
+    }
+    Ok(())
+}
+
+async fn serve_connection(state: &AppState, mut stream: TcpStream) -> anyhow::Result<()> {
+    let connection = Arc::new(Connection::new(&stream)?);
+    let remote = connection.remote;
+    let tracer = state.get_tracer(remote);
+
+    let trace_handle =
+        match TraceHandle::start_trace(tracer, connection, TraceOptions::default()).await {
+            Ok(trace_handle) => trace_handle,
+            Err(err) => return write_error(&mut stream, err).await,
+        };
+    let _round = trace_handle.start_round(TraceOptions::default()).await;
+
+    stream
+        .write_all(format!("inband traceroute to {remote}\r\n").as_bytes())
+        .await
+        .context("Failed to write to client")?;
+
+    for annotation in Annotation::detect(remote, &HeaderMap::new(), &state.nat64_prefixes) {
+        stream
+            .write_all(format!("note: {annotation}\r\n").as_bytes())
+            .await
+            .context("Failed to write to client")?;
+    }
+
+    let mut hop_stream = match trace_handle.hop_stream().await {
+        Ok(hop_stream) => Box::pin(hop_stream),
+        Err(err) => return write_error(&mut stream, err).await,
+    };
+    while let Some(hop) = hop_stream.next().await {
+        let hop = match hop {
+            Ok(hop) => hop,
+            Err(err) => return write_error(&mut stream, err).await,
+        };
+        stream
+            .write_all(format!("{hop}\r\n").as_bytes())
+            .await
+            .context("Failed to write to client")?;
+    }
+
+    stream.shutdown().await?;
+    Ok(())
+}
+
+// Tell the client why its trace ended, then fail with `err`
+async fn write_error(stream: &mut TcpStream, err: anyhow::Error) -> anyhow::Result<()> {
+    // The client may be gone already, which isn't worth reporting over `err`
+    let _ = stream
+        .write_all(format!("error: {err:#}\r\n").as_bytes())
+        .await;
+    Err(err)
+}
@@ -1,6 +1,6 @@
 use core::panic;
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     net::{self, IpAddr, Ipv4Addr, SocketAddr, SocketAddrV6},
     sync::{Arc, Weak},
     time::Duration,
@@ -8,26 +8,36 @@ use std::{
 
 use anyhow::Context;
 use async_stream::stream;
-use etherparse::{ip_number, Ipv4Header, Ipv6FlowLabel, Ipv6Header, PacketBuilder, TcpHeader};
+use etherparse::{
+    ip_number, Ipv4Dscp, Ipv4Ecn, Ipv4Header, Ipv6FlowLabel, Ipv6Header, PacketBuilder, TcpHeader,
+};
 use futures::stream::{Stream, StreamExt};
-use inband_traceroute_common::{IPAddr, TraceEvent, TraceEventType};
+use inband_traceroute_common::{
+    ConnectionKey, ConnectionSequence, IPAddr, TraceEntry, TraceEvent, TraceEventType,
+    CLASSIC_UDP_BASE_PORT,
+};
 use log::{debug, info, warn};
 use maxminddb::Reader;
 use nix::time::{clock_gettime, ClockId};
 use rand::{rngs::OsRng, Rng};
+use serde::{de, Deserialize, Deserializer, Serialize};
 use socket2::{Domain, SockAddr};
 use tokio::{
     sync::{
         mpsc::{UnboundedReceiver, UnboundedSender},
-        Mutex, RwLock,
+        Mutex, MutexGuard, RwLock,
     },
-    time::timeout,
+    time::{timeout, timeout_at, Instant},
 };
 
 use crate::{
+    classic::{ClassicHop, ClassicMethod, ClassicReply},
+    conn::{Connection, ConnectionStats},
     dns::ReverseDnsProvider,
-    ebpf::TraceMap,
-    hop::{Hop, HopType},
+    ebpf::{ClassicTraceMap, EbpfMaps, HandshakeMap, SequenceMap, SynMap, TraceMap},
+    fingerprint::ClientSyn,
+    hop::{Hop, HopType, LoadedHop, PathMtu, ProbeHeaders, TosTraversal, TtlField},
+    outbound::OutboundConnection,
     raw,
 };
 
@@ -35,16 +45,88 @@ type TraceId = u32;
 
 const STEP_TIMEOUT: Duration = Duration::from_secs(2);
 
+// Candidate path MTUs, largest first: Ethernet, PPPoE, common tunnel overheads, and the IPv6 and
+// IPv4 minimums
+const PMTU_PROBE_SIZES: [u16; 11] = [
+    1500, 1492, 1480, 1476, 1460, 1450, 1420, 1400, 1380, 1280, 576,
+];
+
+const PMTU_PROBE_TTL: u8 = 64;
+
+// MTU plateaus of RFC 1191 section 7, for routers that report a next-hop MTU of zero
+const MTU_PLATEAUS: [u32; 11] = [
+    65535, 32000, 17914, 8166, 4352, 2002, 1492, 1006, 508, 296, 68,
+];
+
+/// ECN codepoint to send probes with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum EcnCodepoint {
+    NotEct,
+    Ect1,
+    Ect0,
+    Ce,
+}
+
+impl EcnCodepoint {
+    fn bits(self) -> u8 {
+        match self {
+            EcnCodepoint::NotEct => 0b00,
+            EcnCodepoint::Ect1 => 0b01,
+            EcnCodepoint::Ect0 => 0b10,
+            EcnCodepoint::Ce => 0b11,
+        }
+    }
+}
+
+/// Per-trace options, taken from the query string
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default)]
+pub(crate) struct TraceOptions {
+    /// Run a path MTU discovery phase after the hop-by-hop trace
+    pub(crate) pmtu: bool,
+    /// Send probes with this ECN codepoint instead of the connection's
+    pub(crate) ecn: Option<EcnCodepoint>,
+    /// Send probes with this DSCP instead of the connection's
+    #[serde(deserialize_with = "deserialize_dscp")]
+    pub(crate) dscp: Option<u8>,
+    /// Bytes of padding to stream on the response while re-probing the path under load
+    pub(crate) load: Option<u64>,
+    /// Run classic ICMP echo and UDP traceroutes to the client alongside the inband trace
+    pub(crate) classic: bool,
+}
+
+/// Deserialize a DSCP, rejecting values that don't fit in its 6 bits
+pub(crate) fn deserialize_dscp<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<u8>, D::Error> {
+    let dscp = Option::<u8>::deserialize(deserializer)?;
+    if dscp.is_some_and(|dscp| dscp >= 64) {
+        return Err(de::Error::custom("DSCP must be below 64"));
+    }
+    Ok(dscp)
+}
+
 #[derive(Debug)]
 pub struct Tracer {
     listen_addr: SocketAddr,
     max_hops: u8,
     socket: raw::AsyncWriteOnlyIPRawSocket,
     trace_map: Arc<Mutex<TraceMap>>,
+    classic_trace_map: Arc<Mutex<ClassicTraceMap>>,
+    syn_map: Arc<SynMap>,
+    sequence_map: Arc<Mutex<SequenceMap>>,
+    handshake_map: Arc<HandshakeMap>,
     ipdb: &'static Reader<Vec<u8>>,
     pub(crate) dns_client: Arc<ReverseDnsProvider>,
 
     traces: RwLock<HashMap<TraceId, Weak<TraceHandle>>>,
+    /// Handles by remote address and local port. The eBPF map has one entry per connection, so
+    /// all requests on a connection share its handle.
+    handles: Mutex<HashMap<(SocketAddr, u16), Weak<TraceHandle>>>,
+    /// Classic trace idents of the live handles. Classic replies are matched by remote address
+    /// and ident, so two traces to the same address must not share one.
+    classic_idents: Mutex<HashSet<u16>>,
 }
 
 // Equivilent to the bpf_ktime_get_ns function from inside of BPF
@@ -60,7 +142,7 @@ impl Tracer {
     pub fn new(
         listen_addr: SocketAddr,
         max_hops: u8,
-        trace_map: Arc<Mutex<TraceMap>>,
+        maps: EbpfMaps,
         ipdb: &'static Reader<Vec<u8>>,
         dns_client: Arc<ReverseDnsProvider>,
     ) -> anyhow::Result<Self> {
@@ -76,25 +158,143 @@ impl Tracer {
             listen_addr,
             max_hops,
             socket,
-            trace_map,
+            trace_map: maps.traces,
+            classic_trace_map: maps.classic_traces,
+            syn_map: maps.syns,
+            sequence_map: maps.sequences,
+            handshake_map: maps.handshakes,
             ipdb,
             dns_client,
             traces: RwLock::new(HashMap::new()),
+            handles: Mutex::new(HashMap::new()),
+            classic_idents: Mutex::new(HashSet::new()),
         })
     }
 
+    /// The SYN that opened the client's connection, as XDP saw it arrive
+    pub(crate) fn client_syn(&self, connection: &Connection) -> Option<ClientSyn> {
+        let key = ConnectionKey {
+            remote: std_socket_addr_to_ebpf(connection.remote),
+            local_port: connection.local.port(),
+        };
+        match self.syn_map.get(&key, 0) {
+            Ok(syn) => Some(ClientSyn::new(&syn)),
+            Err(err) => {
+                debug!("No SYN recorded for {}: {err}", connection.remote);
+                None
+            }
+        }
+    }
+
+    /// Sequence numbers of the connection, from the SYNs the eBPF programs saw and the bytes the
+    /// socket had acknowledged both ways since. Left for the eBPF programs to fill in if either is
+    /// missing.
+    fn initial_sequence(&self, key: &ConnectionKey, connection: &Connection) -> ConnectionSequence {
+        let handshake = match self.handshake_map.get(key, 0) {
+            Ok(handshake) if handshake.local_seen != 0 && handshake.remote_seen != 0 => handshake,
+            _ => {
+                debug!("No handshake recorded for {}", connection.remote);
+                return ConnectionSequence::default();
+            }
+        };
+        let (bytes_acked, bytes_received) = match connection.stats() {
+            Ok(ConnectionStats {
+                bytes_acked: Some(bytes_acked),
+                bytes_received: Some(bytes_received),
+                ..
+            }) => (bytes_acked, bytes_received),
+            _ => return ConnectionSequence::default(),
+        };
+
+        // The kernel counts our SYN as acknowledged if we sent it first, but not in reply to the
+        // peer's. The peer's SYN never counts as received.
+        let syn_acked = if handshake.opened != 0 { 0 } else { 1 };
+        ConnectionSequence {
+            acked: 1,
+            snd_una: handshake
+                .local
+                .wrapping_add(syn_acked)
+                .wrapping_add(bytes_acked as u32),
+            ack_sent: 1,
+            rcv_nxt: handshake
+                .remote
+                .wrapping_add(1)
+                .wrapping_add(bytes_received as u32),
+        }
+    }
+
+    /// A random classic trace ident no live handle has, released when the handle drops
+    async fn allocate_classic_ident(&self) -> anyhow::Result<u16> {
+        let mut idents = self.classic_idents.lock().await;
+        anyhow::ensure!(
+            idents.len() < usize::from(u16::MAX),
+            "No free classic trace ident"
+        );
+
+        // Not zero, which is no valid UDP source port
+        let mut ident = OsRng.gen_range(1..=u16::MAX);
+        while !idents.insert(ident) {
+            ident = OsRng.gen_range(1..=u16::MAX);
+        }
+        Ok(ident)
+    }
+
+    pub(crate) fn listen_addr(&self) -> SocketAddr {
+        self.listen_addr
+    }
+
+    pub(crate) fn max_hops(&self) -> u8 {
+        self.max_hops
+    }
+
+    /// Open a connection from our address to `target` to trace it like a client connection
+    pub(crate) async fn connect_outbound(
+        &self,
+        target: SocketAddr,
+        server_name: Option<&str>,
+    ) -> anyhow::Result<OutboundConnection> {
+        OutboundConnection::connect(Some(self.listen_addr.ip()), target, server_name).await
+    }
+
+    // The IPv6 header has no identification field, and the flow label must match the connection,
+    // so the TTL is encoded in the payload length instead
+    fn probe_payload_len(&self, ttl: u8) -> usize {
+        match self.listen_addr {
+            SocketAddr::V4(_) => 1,
+            SocketAddr::V6(_) => ttl.into(),
+        }
+    }
+
+    fn probe_ttl_field(&self) -> TtlField {
+        match self.listen_addr {
+            SocketAddr::V4(_) => TtlField::Ipv4Identification,
+            SocketAddr::V6(_) => TtlField::Ipv6PayloadLength,
+        }
+    }
+
+    // Size of the IP and TCP headers of a probe
+    fn probe_header_len(&self) -> usize {
+        match self.listen_addr {
+            SocketAddr::V4(_) => Ipv4Header::MIN_LEN + TcpHeader::MIN_LEN,
+            SocketAddr::V6(_) => Ipv6Header::LEN + TcpHeader::MIN_LEN,
+        }
+    }
+
     // We send an outbound TCP Keep Alive Packet
     async fn send_outbound_packet(
         &self,
+        local_addr: SocketAddr,
         addr: SocketAddr,
         ttl: u8,
         seq: u32,
         seq_ack: u32,
+        payload_len: usize,
+        headers: &ProbeHeaders,
     ) -> anyhow::Result<()> {
-        let payload: &[u8; 1] = &[0];
+        let payload = vec![0; payload_len];
         let send_to_addr: SockAddr;
 
-        let ip_header = match (addr.ip(), self.listen_addr.ip()) {
+        let ip_header = match (addr.ip(), local_addr.ip()) {
             (IpAddr::V4(remote), IpAddr::V4(local)) => {
                 send_to_addr = addr.into();
                 etherparse::IpHeaders::Ipv4(
@@ -109,6 +309,8 @@ impl Tracer {
                         .unwrap();
                         header.identification = ttl.into();
                         header.dont_fragment = true;
+                        header.dscp = Ipv4Dscp::try_new(headers.tos >> 2).unwrap();
+                        header.ecn = Ipv4Ecn::try_new(headers.tos & 0b11).unwrap();
                         header
                     },
                     Default::default(),
@@ -125,7 +327,9 @@ impl Tracer {
                         destination: remote.octets(),
                         payload_length: 0, // will be overwritten
                         next_header: ip_number::TCP,
-                        flow_label: Ipv6FlowLabel::try_new(ttl.into()).unwrap(),
+                        traffic_class: headers.tos,
+                        flow_label: Ipv6FlowLabel::try_new(headers.flow_label.unwrap_or(0))
+                            .unwrap(),
                         ..Default::default()
                     },
                     Default::default(),
@@ -137,7 +341,7 @@ impl Tracer {
         };
 
         let builder = PacketBuilder::ip(ip_header).tcp_header({
-            let mut tcp_header = TcpHeader::new(self.listen_addr.port(), addr.port(), seq, 0xffff);
+            let mut tcp_header = TcpHeader::new(local_addr.port(), addr.port(), seq, 0xffff);
             tcp_header.psh = true;
             tcp_header.ack = true;
             tcp_header.acknowledgment_number = seq_ack;
@@ -146,7 +350,74 @@ impl Tracer {
 
         let mut result = Vec::<u8>::with_capacity(builder.size(payload.len()));
 
-        builder.write(&mut result, payload).unwrap();
+        builder.write(&mut result, &payload).unwrap();
+
+        self.socket
+            .send_to(result.as_slice(), &send_to_addr)
+            .await?;
+
+        Ok(())
+    }
+
+    // A classic traceroute probe, carrying the trace's `ident` in the ICMP echo identifier or the
+    // UDP source port, and the TTL in the ICMP echo sequence number or the UDP destination port
+    async fn send_classic_probe(
+        &self,
+        addr: IpAddr,
+        method: ClassicMethod,
+        ident: u16,
+        ttl: u8,
+    ) -> anyhow::Result<()> {
+        let send_to_addr: SockAddr;
+
+        let ip_header = match (addr, self.listen_addr.ip()) {
+            (IpAddr::V4(remote), IpAddr::V4(local)) => {
+                send_to_addr = SocketAddr::new(addr, 0).into();
+                let protocol = match method {
+                    ClassicMethod::IcmpEcho => ip_number::ICMP,
+                    ClassicMethod::Udp => ip_number::UDP,
+                };
+                etherparse::IpHeaders::Ipv4(
+                    Ipv4Header::new(0, ttl, protocol, local.octets(), remote.octets()).unwrap(),
+                    Default::default(),
+                )
+            }
+            (IpAddr::V6(remote), IpAddr::V6(local)) => {
+                send_to_addr = SocketAddrV6::new(remote, 0, 0, 0).into();
+                etherparse::IpHeaders::Ipv6(
+                    Ipv6Header {
+                        hop_limit: ttl,
+                        source: local.octets(),
+                        destination: remote.octets(),
+                        next_header: match method {
+                            ClassicMethod::IcmpEcho => ip_number::IPV6_ICMP,
+                            ClassicMethod::Udp => ip_number::UDP,
+                        },
+                        ..Default::default()
+                    },
+                    Default::default(),
+                )
+            }
+            _ => {
+                panic!("IP address family mismatch");
+            }
+        };
+
+        let builder = PacketBuilder::ip(ip_header);
+        let mut result = Vec::<u8>::new();
+
+        match (method, addr) {
+            (ClassicMethod::IcmpEcho, IpAddr::V4(_)) => builder
+                .icmpv4_echo_request(ident, ttl.into())
+                .write(&mut result, &[]),
+            (ClassicMethod::IcmpEcho, IpAddr::V6(_)) => builder
+                .icmpv6_echo_request(ident, ttl.into())
+                .write(&mut result, &[]),
+            (ClassicMethod::Udp, _) => builder
+                .udp(ident, CLASSIC_UDP_BASE_PORT + u16::from(ttl))
+                .write(&mut result, &[]),
+        }
+        .unwrap();
 
         self.socket
             .send_to(result.as_slice(), &send_to_addr)
@@ -161,7 +432,12 @@ impl Tracer {
 
         if let Some(trace) = traces.get(&trace_id) {
             if let Some(trace) = trace.upgrade() {
-                trace.sender.send(event)?;
+                match event.event_type {
+                    TraceEventType::ClassicTimeExceeded
+                    | TraceEventType::ClassicUnreachable
+                    | TraceEventType::ClassicEchoReply => trace.classic_sender.send(event)?,
+                    _ => trace.sender.send(event)?,
+                }
             } else {
                 warn!("Trace {trace_id} is no longer valid");
             }
@@ -204,93 +480,221 @@ pub struct TraceHandle {
     tracer: Arc<Tracer>, // Must be a strong reference to keep the tracer alive
     trace_id: u32,
     remote: SocketAddr,
-    key: inband_traceroute_common::SocketAddr,
+    connection: Arc<Connection>,
+    key: ConnectionKey,
+    // Tells classic probes of this trace apart from those of other traces to the same address
+    classic_ident: u16,
+    // Can be changed between rounds by interactive clients
+    options: std::sync::Mutex<TraceOptions>,
     sender: UnboundedSender<TraceEvent>,
     receiver: Mutex<UnboundedReceiver<TraceEvent>>,
+    // Replies to classic traceroute probes, kept apart so they can run alongside the inband trace
+    classic_sender: UnboundedSender<TraceEvent>,
+    classic_receiver: Mutex<UnboundedReceiver<TraceEvent>>,
+    // Held by the request whose round is running, so others on the connection queue behind it
+    round: Mutex<()>,
+}
+
+/// Sequence numbers for a probe on the traced connection
+#[derive(Debug, Clone, Copy)]
+struct TcpSequence {
+    /// Oldest byte we sent that the client hasn't acknowledged yet
+    snd_una: u32,
+    /// Next byte expected from the client
+    rcv_nxt: u32,
+}
+
+enum PmtuReply {
+    Acked,
+    TooBig { mtu: u32, addr: IpAddr },
+    Timeout,
 }
 
 impl TraceHandle {
-    /// Create a new `TraceHandle` and register it
-    pub async fn start_trace(tracer: Arc<Tracer>, remote: SocketAddr) -> anyhow::Result<Arc<Self>> {
-        let trace_id: u32 = OsRng.gen();
+    /// The connection's `TraceHandle`, created and registered if it has none yet. `options` only
+    /// apply to a new handle, rounds set their own with `start_round`.
+    pub async fn start_trace(
+        tracer: Arc<Tracer>,
+        connection: Arc<Connection>,
+        options: TraceOptions,
+    ) -> anyhow::Result<Arc<Self>> {
+        let remote = connection.remote;
+        let local_port = connection.local.port();
 
-        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel::<TraceEvent>();
+        // Held until the new handle is registered, so concurrent requests get the same one
+        let mut handles = tracer.handles.lock().await;
+        if let Some(handle) = handles.get(&(remote, local_port)).and_then(Weak::upgrade) {
+            // A handle of an earlier connection from the same port may still be around
+            if Arc::ptr_eq(&handle.connection, &connection) {
+                debug!("Sharing trace id {} for remote {remote}", handle.trace_id);
+                return Ok(handle);
+            }
+        }
 
-        let key = std_socket_addr_to_ebpf(remote);
-        let mut res = Arc::new(Self {
-            tracer: tracer.clone(),
-            trace_id,
-            remote,
-            key,
-            sender,
-            receiver: Mutex::new(receiver),
-        });
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel::<TraceEvent>();
+        let (classic_sender, classic_receiver) =
+            tokio::sync::mpsc::unbounded_channel::<TraceEvent>();
 
-        {
+        let classic_ident = tracer.allocate_classic_ident().await?;
+        let res = {
             let mut traces = tracer.traces.write().await;
 
             // Just in case of collisions, we will keep generating new trace ids until we find a free one
-            while traces.get(&trace_id).is_some() {
-                Arc::get_mut(&mut res).unwrap().trace_id = OsRng.gen();
+            let mut trace_id: u32 = OsRng.gen();
+            while traces.contains_key(&trace_id) {
+                trace_id = OsRng.gen();
             }
 
+            let res = Arc::new(Self {
+                tracer: tracer.clone(),
+                trace_id,
+                remote,
+                connection,
+                key: ConnectionKey {
+                    remote: std_socket_addr_to_ebpf(remote),
+                    local_port,
+                },
+                classic_ident,
+                options: std::sync::Mutex::new(options),
+                sender,
+                receiver: Mutex::new(receiver),
+                classic_sender,
+                classic_receiver: Mutex::new(classic_receiver),
+                round: Mutex::new(()),
+            });
             traces.insert(trace_id, Arc::downgrade(&res));
-        }
+            res
+        };
+        let trace_id = res.trace_id;
 
         {
             let mut trace_map = tracer.trace_map.lock().await;
 
             debug!("Registering trace id {trace_id} for remote {remote}");
 
+            // Before the trace entry, which makes the eBPF programs update it
+            let sequence = tracer.initial_sequence(&res.key, &res.connection);
+            tracer
+                .sequence_map
+                .lock()
+                .await
+                .insert(res.key, sequence, 0)
+                .context("failed to seed sequence numbers")?;
             trace_map
-                .insert(key, trace_id, 0)
+                .insert(res.key, TraceEntry::new(trace_id), 0)
                 .context("failed to register trace")?;
         }
 
+        handles.insert((remote, local_port), Arc::downgrade(&res));
         Ok(res)
     }
 
-    async fn wait_for_initial_ack(&self) -> anyhow::Result<(u32, u32)> {
-        let mut receiver = self.receiver.lock().await;
+    /// Wait for the running round on the connection to finish, then run one with `options` until
+    /// the guard is dropped. Probes of two rounds at once would take each other's replies.
+    pub(crate) async fn start_round(&self, options: TraceOptions) -> MutexGuard<'_, ()> {
+        let round = self.round.lock().await;
+        self.set_options(options);
+        round
+    }
 
-        match receiver.recv().await {
-            Some(event) => {
-                if event.event_type == TraceEventType::TcpAck {
-                    Ok((event.ack_seq, event.seq))
-                } else {
-                    panic!("Received unexpected event type: {:?}", event.event_type);
-                }
-            }
-            None => Err(anyhow::anyhow!(
-                "Receiver channel closed before ack was received"
-            )),
+    /// Header fields for the next probe, copied from what the egress classifier last saw on the
+    /// connection
+    async fn probe_headers(&self) -> ProbeHeaders {
+        let headers = {
+            let trace_map = self.tracer.trace_map.lock().await;
+            trace_map
+                .get(&self.key, 0)
+                .map(|entry| entry.headers)
+                .unwrap_or_else(|err| {
+                    debug!("Failed to look up trace {}: {err:#?}", self.trace_id);
+                    Default::default()
+                })
+        };
+
+        let options = self.options();
+        let dscp = options.dscp.unwrap_or(headers.tos >> 2);
+        let ecn = options.ecn.map_or(headers.tos & 0b11, EcnCodepoint::bits);
+
+        ProbeHeaders {
+            tos: (dscp << 2) | ecn,
+            flow_label: match self.remote {
+                SocketAddr::V4(_) => None,
+                SocketAddr::V6(_) => Some(headers.flow_label),
+            },
+            observed: headers.observed != 0,
+            ttl_field: self.tracer.probe_ttl_field(),
         }
     }
 
-    async fn hop_stream_internal<'a>(&'a self) -> anyhow::Result<impl Stream<Item = Hop> + 'a> {
-        let (mut ack_seq, mut seq) = timeout(Duration::from_secs(5), self.wait_for_initial_ack())
+    /// Current sequence numbers of the traced connection, seeded when the trace started and then
+    /// updated by XDP and the egress classifier as they see them
+    async fn tcp_sequence(&self) -> anyhow::Result<TcpSequence> {
+        let sequence = self
+            .tracer
+            .sequence_map
+            .lock()
             .await
-            .context("Timed out waiting for initial ACK")?
-            .context("Failed to get initial ACK")?;
+            .get(&self.key, 0)
+            .context("No sequence numbers recorded for the connection")?;
+        anyhow::ensure!(
+            sequence.acked != 0 && sequence.ack_sent != 0,
+            "Sequence numbers of the connection haven't been seen in both directions yet"
+        );
+
+        Ok(TcpSequence {
+            snd_una: sequence.snd_una,
+            rcv_nxt: sequence.rcv_nxt,
+        })
+    }
+
+    /// The kernel's TCP statistics for the traced connection
+    pub(crate) fn connection_stats(&self) -> anyhow::Result<ConnectionStats> {
+        self.connection
+            .stats()
+            .context("Failed to read TCP_INFO from the socket")
+    }
+
+    async fn hop_stream_internal<'a>(
+        &'a self,
+    ) -> anyhow::Result<impl Stream<Item = anyhow::Result<Hop>> + 'a> {
+        // Fail early if the connection's sequence numbers aren't known
+        self.tcp_sequence().await?;
 
         let stream = stream! {
-                let origin = Hop::new(0, HopType::Origin, Some(self.tracer.listen_addr.ip()), None, self.tracer.ipdb);
-                yield origin;
+                let origin = Hop::new(0, HopType::Origin, Some(self.tracer.listen_addr.ip()), None, None, self.tracer.ipdb);
+                yield Ok(origin);
 
                 let mut receiver = self.receiver.lock().await;
-
+                // Discard late replies to an earlier round's probes
+                while receiver.try_recv().is_ok() {}
 
               'outer:   for ttl in 1..=self.tracer.max_hops {
                     debug!( "Trace with TTL {ttl}");
 
-                    let sent_seq = ack_seq - 1;
+                    let sequence = match self.tcp_sequence().await {
+                        Ok(sequence) => sequence,
+                        Err(err) => {
+                            yield Err(err);
+                            break 'outer;
+                        }
+                    };
+                    let probe = self.probe_headers().await;
+                    let payload_len = self.tracer.probe_payload_len(ttl);
+                    // Already acknowledged data, so the client discards it and sends a duplicate ACK
+                    let sent_seq = sequence.snd_una.wrapping_sub(payload_len as u32);
                     // TODO: save timeing information for RTT
-                    self.tracer.send_outbound_packet(
+                    if let Err(err) = self.tracer.send_outbound_packet(
+                        self.connection.local,
                         self.remote,
                         ttl,
                         sent_seq,
-                        seq,
-                    ).await.expect("Should never fail to send packets");
+                        sequence.rcv_nxt,
+                        payload_len,
+                        &probe,
+                    ).await {
+                        yield Err(err.context(format!("Failed to send probe with TTL {ttl}")));
+                        break 'outer;
+                    }
 
                     let sent_time = bpf_ktime_get_ns();
 
@@ -301,13 +705,14 @@ impl TraceHandle {
                         debug!("Received event for TTL {ttl}: {res:?}");
 
                         if res.is_err() {
-                            yield Hop::new(
+                            yield Ok(Hop::new(
                                 ttl,
                                 HopType::Timeout,
                                 None,
                                 None,
+                                Some(probe),
                                 self.tracer.ipdb
-                            );
+                            ));
                             break;
                         }
 
@@ -318,37 +723,46 @@ impl TraceHandle {
                         let event = event.unwrap();
                         match event.event_type {
                             TraceEventType::IcmpTimeExceeded => {
-                                yield Hop::new(
+                                let mut hop = Hop::new(
                                     ttl,
                                     HopType::IcmpTimeExceeded,
                                     Some(ebpf_to_std_ipaddr(event.addr)),
                                     Some(event.arrival - sent_time),
+                                    Some(probe),
                                     self.tracer.ipdb
                                 );
+                                hop.tos = Some(TosTraversal::new(probe.tos, event.quoted_tos));
+                                yield Ok(hop);
                             }
                             TraceEventType::TcpAck => {
-                                if event.ack_seq - 1 == sent_seq {
-                                   yield Hop::new(
+                                if event.ack_seq == sent_seq.wrapping_add(payload_len as u32) {
+                                   yield Ok(Hop::new(
                                         ttl,
                                         HopType::TcpAck,
                                         Some(self.remote.ip()),
                                         Some(event.arrival - sent_time),
+                                        Some(probe),
                                         self.tracer.ipdb
-                                    );
+                                    ));
                                     break 'outer;
-                                } else {
-                                    ack_seq = event.ack_seq;
-                                    seq = event.seq;
                                 }
+                                // Otherwise it acknowledges other data on the connection
                             }
+                            // Only sent in response to the PMTU phase's full size probes
+                            TraceEventType::IcmpPacketTooBig => {}
+                            // Routed to the classic receiver
+                            TraceEventType::ClassicTimeExceeded
+                            | TraceEventType::ClassicUnreachable
+                            | TraceEventType::ClassicEchoReply => {}
                             TraceEventType::TcpRst => {
-                                yield  Hop::new(
+                                yield Ok(Hop::new(
                                     ttl,
                                     HopType::TcpRst,
                                     Some(self.remote.ip()),
                                     Some(event.arrival - sent_time),
+                                    Some(probe),
                                     self.tracer.ipdb,
-                                );
+                                ));
                                 break 'outer;
                             }
                         }
@@ -359,19 +773,373 @@ impl TraceHandle {
         Ok(stream)
     }
 
-    pub async fn hop_stream<'a>(&'a self) -> anyhow::Result<impl Stream<Item = Hop> + 'a> {
+    pub fn options(&self) -> TraceOptions {
+        *self.options.lock().unwrap()
+    }
+
+    // Options for the following probes, set when a round starts
+    fn set_options(&self, options: TraceOptions) {
+        *self.options.lock().unwrap() = options;
+    }
+
+    /// Probe a single TTL again, outside of a hop stream
+    pub async fn probe_ttl(&self, ttl: u8) -> anyhow::Result<Hop> {
+        let mut receiver = self.receiver.lock().await;
+        // Discard late replies to earlier probes
+        while receiver.try_recv().is_ok() {}
+
+        let sequence = self.tcp_sequence().await?;
+        let probe = self.probe_headers().await;
+        let payload_len = self.tracer.probe_payload_len(ttl);
+        let sent_seq = sequence.snd_una.wrapping_sub(payload_len as u32);
+
+        self.tracer
+            .send_outbound_packet(
+                self.connection.local,
+                self.remote,
+                ttl,
+                sent_seq,
+                sequence.rcv_nxt,
+                payload_len,
+                &probe,
+            )
+            .await?;
+
+        let sent_time = bpf_ktime_get_ns();
+        let deadline = Instant::now() + STEP_TIMEOUT;
+
+        while let Ok(event) = timeout_at(deadline, receiver.recv()).await {
+            let event = event.context("Receiver channel closed while re-probing")?;
+            match event.event_type {
+                TraceEventType::IcmpTimeExceeded if event.ttl == ttl => {
+                    let mut hop = Hop::new(
+                        ttl,
+                        HopType::IcmpTimeExceeded,
+                        Some(ebpf_to_std_ipaddr(event.addr)),
+                        event.arrival.checked_sub(sent_time),
+                        Some(probe),
+                        self.tracer.ipdb,
+                    );
+                    hop.tos = Some(TosTraversal::new(probe.tos, event.quoted_tos));
+                    return Ok(hop);
+                }
+                TraceEventType::TcpAck
+                    if event.ack_seq == sent_seq.wrapping_add(payload_len as u32) =>
+                {
+                    return Ok(Hop::new(
+                        ttl,
+                        HopType::TcpAck,
+                        Some(self.remote.ip()),
+                        event.arrival.checked_sub(sent_time),
+                        Some(probe),
+                        self.tracer.ipdb,
+                    ));
+                }
+                TraceEventType::TcpRst => {
+                    return Ok(Hop::new(
+                        ttl,
+                        HopType::TcpRst,
+                        Some(self.remote.ip()),
+                        event.arrival.checked_sub(sent_time),
+                        Some(probe),
+                        self.tracer.ipdb,
+                    ));
+                }
+                _ => {}
+            }
+        }
+
+        Ok(Hop::new(
+            ttl,
+            HopType::Timeout,
+            None,
+            None,
+            Some(probe),
+            self.tracer.ipdb,
+        ))
+    }
+
+    /// Send DF probes of decreasing size on the connection until one is acknowledged by the client,
+    /// noting which hop reported a smaller MTU. Run after the hop stream has finished, with its
+    /// hops, so that the constraining hop can be located on the path.
+    pub async fn path_mtu(&self, hops: &[Hop]) -> anyhow::Result<PathMtu> {
+        let min_size = match self.remote {
+            SocketAddr::V4(_) => 576,
+            SocketAddr::V6(_) => 1280,
+        };
+        let header_len = self.tracer.probe_header_len();
+
+        let mut receiver = self.receiver.lock().await;
+        let mut result = PathMtu::default();
+        let mut limit = u32::MAX;
+
+        for size in PMTU_PROBE_SIZES {
+            if !pmtu_probe_fits(size, min_size, limit) {
+                continue;
+            }
+
+            let sequence = self.tcp_sequence().await?;
+            let probe = self.probe_headers().await;
+            let payload_len = usize::from(size) - header_len;
+            let sent_seq = sequence.snd_una.wrapping_sub(payload_len as u32);
+
+            // Fails with EMSGSIZE when larger than our own interface MTU
+            if let Err(err) = self
+                .tracer
+                .send_outbound_packet(
+                    self.connection.local,
+                    self.remote,
+                    PMTU_PROBE_TTL,
+                    sent_seq,
+                    sequence.rcv_nxt,
+                    payload_len,
+                    &probe,
+                )
+                .await
+            {
+                debug!("Failed to send {size} byte PMTU probe: {err:#}");
+                continue;
+            }
+
+            match self
+                .wait_for_pmtu_reply(&mut receiver, sent_seq, payload_len)
+                .await?
+            {
+                PmtuReply::Acked => {
+                    result.mtu = Some(size);
+                    break;
+                }
+                PmtuReply::TooBig { mtu, addr } => {
+                    debug!("{addr} reported MTU {mtu} for {size} byte probe");
+                    limit = pmtu_limit(mtu, size);
+                    result.reported_mtu = Some(mtu);
+                    result.constrained_by = Some(addr);
+                    result.constrained_ttl = hops
+                        .iter()
+                        .find(|hop| hop.addr == Some(addr))
+                        .map(|hop| hop.ttl);
+                }
+                PmtuReply::Timeout => result.black_holed.push(size),
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Re-probe the hops that answered the trace while bulk data is flowing on the connection,
+    /// pairing each RTT under load with the idle one from the trace
+    pub async fn rtt_under_load(&self, hops: &[Hop]) -> anyhow::Result<Vec<LoadedHop>> {
+        let mut receiver = self.receiver.lock().await;
+        let mut loaded = Vec::new();
+
+        // The client ACKs bulk data constantly, so only ICMP replies can be matched to probes
+        for hop in hops
+            .iter()
+            .filter(|hop| matches!(hop.hop_type, HopType::IcmpTimeExceeded))
+        {
+            let ttl = hop.ttl;
+            let sequence = self.tcp_sequence().await?;
+            let probe = self.probe_headers().await;
+            let payload_len = self.tracer.probe_payload_len(ttl);
+
+            self.tracer
+                .send_outbound_packet(
+                    self.connection.local,
+                    self.remote,
+                    ttl,
+                    sequence.snd_una.wrapping_sub(payload_len as u32),
+                    sequence.rcv_nxt,
+                    payload_len,
+                    &probe,
+                )
+                .await?;
+
+            let sent_time = bpf_ktime_get_ns();
+            let deadline = Instant::now() + STEP_TIMEOUT;
+            let mut loaded_rtt = None;
+
+            while let Ok(event) = timeout_at(deadline, receiver.recv()).await {
+                let event = event.context("Receiver channel closed while probing under load")?;
+                if event.event_type == TraceEventType::IcmpTimeExceeded && event.ttl == ttl {
+                    // Arrived before the probe was sent, so it answers an earlier one
+                    let Some(rtt) = event.arrival.checked_sub(sent_time) else {
+                        continue;
+                    };
+                    loaded_rtt = Some(rtt);
+                    break;
+                }
+            }
+
+            loaded.push(LoadedHop {
+                ttl,
+                addr: hop.addr,
+                idle_rtt: hop.rtt,
+                loaded_rtt,
+            });
+        }
+
+        Ok(loaded)
+    }
+
+    /// Run a classic traceroute of each method to the client's address, one probe at a time like
+    /// the inband trace
+    pub async fn classic_traces(&self) -> anyhow::Result<Vec<(ClassicMethod, Vec<ClassicHop>)>> {
+        {
+            let mut classic_trace_map = self.tracer.classic_trace_map.lock().await;
+            let key = self.classic_key();
+            if let Ok(trace_id) = classic_trace_map.get(&key, 0) {
+                anyhow::ensure!(
+                    trace_id == self.trace_id,
+                    "Trace {trace_id} is already tracing {} classically with the same identifier",
+                    self.remote.ip()
+                );
+            }
+            classic_trace_map
+                .insert(key, self.trace_id, 0)
+                .context("failed to register classic trace")?;
+        }
+
+        let mut receiver = self.classic_receiver.lock().await;
+        let mut traces = Vec::new();
+        for method in ClassicMethod::ALL {
+            traces.push((method, self.classic_trace(method, &mut receiver).await?));
+        }
+
+        Ok(traces)
+    }
+
+    fn classic_key(&self) -> inband_traceroute_common::SocketAddr {
+        inband_traceroute_common::SocketAddr {
+            addr: self.key.remote.addr,
+            port: self.classic_ident,
+        }
+    }
+
+    async fn classic_trace(
+        &self,
+        method: ClassicMethod,
+        receiver: &mut UnboundedReceiver<TraceEvent>,
+    ) -> anyhow::Result<Vec<ClassicHop>> {
+        let remote = self.remote.ip();
+        let mut hops = Vec::new();
+
+        for ttl in 1..=self.tracer.max_hops {
+            self.tracer
+                .send_classic_probe(remote, method, self.classic_ident, ttl)
+                .await?;
+            let sent_time = bpf_ktime_get_ns();
+
+            let mut hop = ClassicHop {
+                ttl,
+                reply: None,
+                addr: None,
+                rtt: None,
+            };
+
+            let deadline = Instant::now() + STEP_TIMEOUT;
+            while let Ok(event) = timeout_at(deadline, receiver.recv()).await {
+                let event = event.context("Receiver channel closed during classic trace")?;
+
+                // Late replies to earlier probes or the other method
+                let udp = i32::from(event.probe_protocol) == libc::IPPROTO_UDP;
+                if event.ttl != ttl || udp != (method == ClassicMethod::Udp) {
+                    continue;
+                }
+                let Some(rtt) = event.arrival.checked_sub(sent_time) else {
+                    continue;
+                };
+
+                let addr = ebpf_to_std_ipaddr(event.addr);
+                hop.reply = Some(match event.event_type {
+                    TraceEventType::ClassicTimeExceeded => ClassicReply::TimeExceeded,
+                    TraceEventType::ClassicUnreachable if addr == remote => {
+                        ClassicReply::Destination
+                    }
+                    TraceEventType::ClassicUnreachable => ClassicReply::Unreachable,
+                    TraceEventType::ClassicEchoReply => ClassicReply::Destination,
+                    _ => continue,
+                });
+                hop.addr = Some(addr);
+                hop.rtt = Some(rtt);
+                break;
+            }
+
+            let reached = hop.reply == Some(ClassicReply::Destination);
+            hops.push(hop);
+            if reached {
+                break;
+            }
+        }
+
+        Ok(hops)
+    }
+
+    // Wait for the reply to the probe sent with `sent_seq`
+    async fn wait_for_pmtu_reply(
+        &self,
+        receiver: &mut UnboundedReceiver<TraceEvent>,
+        sent_seq: u32,
+        payload_len: usize,
+    ) -> anyhow::Result<PmtuReply> {
+        let expected_ack = sent_seq.wrapping_add(payload_len as u32);
+        let deadline = Instant::now() + STEP_TIMEOUT;
+
+        loop {
+            let Ok(event) = timeout_at(deadline, receiver.recv()).await else {
+                return Ok(PmtuReply::Timeout);
+            };
+            let event = event.context("Receiver channel closed during PMTU discovery")?;
+
+            match event.event_type {
+                TraceEventType::TcpAck if event.ack_seq == expected_ack => {
+                    return Ok(PmtuReply::Acked);
+                }
+                // Acknowledges other data on the connection
+                TraceEventType::TcpAck => {}
+                TraceEventType::TcpRst => {
+                    anyhow::bail!("Connection reset during PMTU discovery");
+                }
+                TraceEventType::IcmpPacketTooBig if event.seq == sent_seq => {
+                    return Ok(PmtuReply::TooBig {
+                        mtu: event.mtu,
+                        addr: ebpf_to_std_ipaddr(event.addr),
+                    });
+                }
+                // Late replies to a larger probe
+                TraceEventType::IcmpPacketTooBig => {}
+                // Late replies to the hop-by-hop probes
+                TraceEventType::IcmpTimeExceeded => {}
+                // Routed to the classic receiver
+                TraceEventType::ClassicTimeExceeded
+                | TraceEventType::ClassicUnreachable
+                | TraceEventType::ClassicEchoReply => {}
+            }
+        }
+    }
+
+    /// The hops of a round, ending with an error if probing fails midway
+    pub async fn hop_stream<'a>(
+        &'a self,
+    ) -> anyhow::Result<impl Stream<Item = anyhow::Result<Hop>> + 'a> {
         let mut internal = Box::pin(self.hop_stream_internal().await?);
         let mut trace: Vec<Option<Hop>> = vec![None; self.tracer.max_hops as usize];
 
         let stream = stream! {
             while let Some(hop) = internal.next().await {
+                let hop = match hop {
+                    Ok(hop) => hop,
+                    Err(err) => {
+                        yield Err(err);
+                        continue;
+                    }
+                };
                 let ttl = hop.ttl as usize;
                 if trace[ttl].is_some() {
                     warn!("Duplicate hop for TTL {ttl}: {hop:?}");
                     continue;
                 }
                 trace[ttl] = Some(hop.clone());
-                yield hop;
+                yield Ok(hop);
             }
             info!("Trace completed: {trace:?}");
         };
@@ -380,12 +1148,32 @@ impl TraceHandle {
     }
 }
 
+// Whether a `size` byte probe is worth sending once the path is known to be limited to `limit`
+fn pmtu_probe_fits(size: u16, min_size: u16, limit: u32) -> bool {
+    size >= min_size && u32::from(size) <= limit
+}
+
+// The path MTU implied by a Packet Too Big for a `size` byte probe. Routers that don't fill in the
+// next-hop MTU (or report one the probe would have fit) get the next plateau below the probe.
+fn pmtu_limit(mtu: u32, size: u16) -> u32 {
+    let size = u32::from(size);
+    if mtu != 0 && mtu < size {
+        return mtu;
+    }
+    MTU_PLATEAUS
+        .into_iter()
+        .find(|plateau| *plateau < size)
+        .unwrap_or(0)
+}
+
 impl Drop for TraceHandle {
     fn drop(&mut self) {
         debug!("Dropping trace handle for trace id {}", self.trace_id);
         let trace_id = self.trace_id;
         let remote = self.remote;
+        let local_port = self.key.local_port;
         let key = self.key;
+        let classic_key = self.classic_key();
         let tracer = self.tracer.clone();
 
         tokio::spawn(async move {
@@ -393,14 +1181,122 @@ impl Drop for TraceHandle {
                 let mut trace_map = tracer.trace_map.lock().await;
                 debug!("Unregistering trace id {trace_id} for remote {remote}");
 
-                trace_map.remove(&key).unwrap_or_else(|e| {
-                    debug!("Failed to unregister trace id {trace_id}: {e:#?}");
-                });
+                // The key may already belong to a newer trace, e.g. of a new connection from the
+                // same port
+                match trace_map.get(&key, 0) {
+                    Ok(entry) if entry.trace_id == trace_id => {
+                        trace_map.remove(&key).unwrap_or_else(|e| {
+                            debug!("Failed to unregister trace id {trace_id}: {e:#?}");
+                        });
+                        tracer
+                            .sequence_map
+                            .lock()
+                            .await
+                            .remove(&key)
+                            .unwrap_or_else(|e| {
+                                debug!("Failed to remove sequence numbers of {trace_id}: {e:#?}");
+                            });
+                    }
+                    _ => {}
+                }
+            }
+            {
+                // Only registered if classic traces were run
+                let mut classic_trace_map = tracer.classic_trace_map.lock().await;
+                if classic_trace_map.get(&classic_key, 0).ok() == Some(trace_id) {
+                    classic_trace_map.remove(&classic_key).unwrap_or_else(|e| {
+                        debug!("Failed to unregister classic trace id {trace_id}: {e:#?}");
+                    });
+                }
+            }
+            {
+                // Only once the classic trace is unregistered, so a new one can't get its replies
+                let mut classic_idents = tracer.classic_idents.lock().await;
+                classic_idents.remove(&classic_key.port);
             }
             {
                 let mut traces = tracer.traces.write().await;
                 traces.remove(&trace_id);
             }
+            {
+                let mut handles = tracer.handles.lock().await;
+                if handles
+                    .get(&(remote, local_port))
+                    .is_some_and(|handle| handle.strong_count() == 0)
+                {
+                    handles.remove(&(remote, local_port));
+                }
+            }
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The sizes path_mtu would probe, given the limits reported for each probe in turn
+    fn probed_sizes(min_size: u16, mut reported: impl FnMut(u16) -> Option<u32>) -> Vec<u16> {
+        let mut limit = u32::MAX;
+        let mut probed = Vec::new();
+        for size in PMTU_PROBE_SIZES {
+            if !pmtu_probe_fits(size, min_size, limit) {
+                continue;
+            }
+            probed.push(size);
+            match reported(size) {
+                Some(mtu) => limit = pmtu_limit(mtu, size),
+                None => break,
+            }
+        }
+        probed
+    }
+
+    #[test]
+    fn reported_mtu_is_the_limit() {
+        assert_eq!(pmtu_limit(1400, 1500), 1400);
+        assert_eq!(pmtu_limit(1280, 1420), 1280);
+    }
+
+    #[test]
+    fn zero_mtu_falls_back_to_plateau() {
+        assert_eq!(pmtu_limit(0, 1500), 1492);
+        assert_eq!(pmtu_limit(0, 1492), 1006);
+        assert_eq!(pmtu_limit(0, 1280), 1006);
+        assert_eq!(pmtu_limit(0, 576), 508);
+        assert_eq!(pmtu_limit(0, 68), 0);
+    }
+
+    #[test]
+    fn mtu_not_below_probe_falls_back_to_plateau() {
+        assert_eq!(pmtu_limit(1500, 1500), 1492);
+        assert_eq!(pmtu_limit(9000, 1500), 1492);
+    }
+
+    #[test]
+    fn probes_skip_sizes_above_limit() {
+        // Acknowledged up to 1400 bytes
+        assert_eq!(
+            probed_sizes(576, |size| (size > 1400).then_some(1400)),
+            [1500, 1400]
+        );
+        // A router reporting zero twice, then 576 bytes get through
+        assert_eq!(
+            probed_sizes(576, |size| (size > 576).then_some(0)),
+            [1500, 1492, 576]
+        );
+        // Nothing fits above the minimum
+        assert_eq!(probed_sizes(1280, |_| Some(0)), [1500, 1492]);
+    }
+
+    #[test]
+    fn probes_start_at_minimum() {
+        assert_eq!(
+            probed_sizes(1280, |size| (size > 1280).then_some(1281)),
+            [1500, 1280]
+        );
+        assert!(PMTU_PROBE_SIZES
+            .iter()
+            .all(|size| !pmtu_probe_fits(*size, 1281, 1280)));
+    }
+}
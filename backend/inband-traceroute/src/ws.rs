@@ -0,0 +1,259 @@
+use std::{num::NonZeroU64, sync::Arc, time::Duration};
+
+use anyhow::Context;
+use axum::extract::ws::{Message, WebSocket};
+use log::{debug, warn};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::{
+    sync::mpsc::{self, UnboundedSender},
+    task::JoinHandle,
+};
+
+use crate::{
+    annotation::Annotation,
+    conn::Connection,
+    server::{self, AppState, TraceEvent, TraceStart, PADDING_QUEUE},
+    tracer::{deserialize_dscp, EcnCodepoint, TraceHandle, TraceOptions, Tracer},
+};
+
+const DEFAULT_INTERVAL_SECS: NonZeroU64 = NonZeroU64::new(5).unwrap();
+
+/// Commands sent by the client as JSON text messages, e.g. `{"command": "reprobe", "ttl": 3}`
+#[derive(Debug, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+enum Command {
+    /// Start a new round with these options, stopping the running one
+    Start(TraceOptions),
+    /// Stop the running round or continuous mode, and any reprobes waiting for it
+    Stop,
+    /// Probe a single TTL again
+    Reprobe { ttl: u8 },
+    /// Change the ECN and DSCP of the following rounds
+    ProbeStyle {
+        ecn: Option<EcnCodepoint>,
+        #[serde(default, deserialize_with = "deserialize_dscp")]
+        dscp: Option<u8>,
+    },
+    /// Run rounds back to back until stopped
+    Continuous {
+        #[serde(default = "default_interval_secs")]
+        interval_secs: NonZeroU64,
+    },
+}
+
+fn default_interval_secs() -> NonZeroU64 {
+    DEFAULT_INTERVAL_SECS
+}
+
+type EventSender = UnboundedSender<anyhow::Result<TraceEvent>>;
+
+/// Interactive trace of the WebSocket's own connection. All rounds share one `TraceHandle`, with
+/// any other requests on the connection.
+struct Session {
+    tracer: Arc<Tracer>,
+    connection: Arc<Connection>,
+    node_name: String,
+    // Sent after each round's `Start`
+    annotations: Vec<Annotation>,
+    // Shared with the running rounds, which apply them as each one starts
+    options: Arc<std::sync::Mutex<TraceOptions>>,
+    handle: Option<Arc<TraceHandle>>,
+    round: Option<JoinHandle<()>>,
+    reprobes: Vec<JoinHandle<()>>,
+    tx: EventSender,
+    padding_tx: mpsc::Sender<anyhow::Result<TraceEvent>>,
+}
+
+/// Serve an upgraded WebSocket until the client goes away
+pub(crate) async fn serve(
+    mut socket: WebSocket,
+    state: Arc<AppState>,
+    connection: Arc<Connection>,
+    options: TraceOptions,
+    annotations: Vec<Annotation>,
+) {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let (padding_tx, mut padding_rx) = mpsc::channel(PADDING_QUEUE);
+    let mut session = Session {
+        tracer: state.get_tracer(connection.remote),
+        connection,
+        node_name: state.node_name.clone(),
+        annotations,
+        options: Arc::new(std::sync::Mutex::new(options)),
+        handle: None,
+        round: None,
+        reprobes: Vec::new(),
+        tx,
+        padding_tx,
+    };
+
+    loop {
+        let message = tokio::select! {
+            message = socket.recv() => {
+                match message {
+                    Some(Ok(Message::Text(text))) => match session.command(&text).await {
+                        Ok(()) => continue,
+                        Err(err) => event_message(Err(err)),
+                    },
+                    Some(Ok(Message::Close(_)) | Err(_)) | None => break,
+                    Some(Ok(_)) => continue,
+                }
+            }
+            Some(event) = rx.recv() => event_message(event),
+            Some(padding) = padding_rx.recv() => event_message(padding),
+        };
+
+        if let Err(err) = socket.send(message).await {
+            debug!("Failed to send to WebSocket: {err}");
+            break;
+        }
+    }
+
+    session.stop();
+}
+
+fn event_message(event: anyhow::Result<TraceEvent>) -> Message {
+    match event {
+        Ok(TraceEvent::Padding(len)) => Message::Binary(vec![0; len].into()),
+        Ok(event) => Message::text(serde_json::to_string(&event).unwrap()),
+        Err(err) => {
+            warn!("Error: {err:#}");
+            Message::text(json!({ "error": format!("{err:#}") }).to_string())
+        }
+    }
+}
+
+impl Session {
+    async fn command(&mut self, text: &str) -> anyhow::Result<()> {
+        let command: Command = serde_json::from_str(text).context("Invalid command")?;
+        debug!(
+            "WebSocket command from {}: {command:?}",
+            self.connection.remote
+        );
+
+        match command {
+            Command::Start(options) => {
+                *self.options.lock().unwrap() = options;
+                self.start_rounds(None).await?;
+            }
+            Command::Stop => self.stop(),
+            Command::Reprobe { ttl } => {
+                let max_hops = self.tracer.max_hops();
+                anyhow::ensure!(
+                    (1..=max_hops).contains(&ttl),
+                    "TTL must be between 1 and {max_hops}"
+                );
+                let handle = self.handle().await?;
+                let options = *self.options.lock().unwrap();
+                let tx = self.tx.clone();
+                self.reprobes.retain(|reprobe| !reprobe.is_finished());
+                self.reprobes.push(tokio::spawn(async move {
+                    let _round = handle.start_round(options).await;
+                    let _ = tx.send(handle.probe_ttl(ttl).await.map(TraceEvent::Hop));
+                }));
+            }
+            Command::ProbeStyle { ecn, dscp } => {
+                // The handle is shared with other requests on the connection, so the options only
+                // take effect once this session's next round starts
+                let mut options = self.options.lock().unwrap();
+                options.ecn = ecn;
+                options.dscp = dscp;
+            }
+            Command::Continuous { interval_secs } => {
+                self.start_rounds(Some(Duration::from_secs(interval_secs.get())))
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn handle(&mut self) -> anyhow::Result<Arc<TraceHandle>> {
+        if let Some(handle) = &self.handle {
+            return Ok(handle.clone());
+        }
+
+        let options = *self.options.lock().unwrap();
+        let handle =
+            TraceHandle::start_trace(self.tracer.clone(), self.connection.clone(), options).await?;
+        self.handle = Some(handle.clone());
+        Ok(handle)
+    }
+
+    /// Run a round, or keep running them with `interval` in between
+    async fn start_rounds(&mut self, interval: Option<Duration>) -> anyhow::Result<()> {
+        self.stop();
+        let handle = self.handle().await?;
+        let tracer = self.tracer.clone();
+        let connection = self.connection.clone();
+        let node_name = self.node_name.clone();
+        let annotations = self.annotations.clone();
+        let tx = self.tx.clone();
+        let padding_tx = self.padding_tx.clone();
+        let options = self.options.clone();
+
+        self.round = Some(tokio::spawn(async move {
+            loop {
+                {
+                    let options = *options.lock().unwrap();
+                    let _round = handle.start_round(options).await;
+
+                    // Like the first events of an SSE trace, so each round reads the same
+                    let start = TraceStart::new(
+                        server::new_trace_id(),
+                        node_name.clone(),
+                        &connection,
+                        tracer.client_syn(&connection),
+                        options,
+                    );
+                    let _ = tx.send(Ok(TraceEvent::Start(start)));
+                    for annotation in &annotations {
+                        let _ = tx.send(Ok(TraceEvent::Annotation(annotation.clone())));
+                    }
+
+                    if let Err(err) = AppState::trace_stream_inner(
+                        tracer.clone(),
+                        handle.clone(),
+                        &tx,
+                        padding_tx.clone(),
+                    )
+                    .await
+                    {
+                        let _ = tx.send(Err(err));
+                    }
+                }
+                let _ = tx.send(Ok(TraceEvent::Done));
+
+                let Some(interval) = interval else {
+                    break;
+                };
+                tokio::time::sleep(interval).await;
+            }
+        }));
+        Ok(())
+    }
+
+    fn stop(&mut self) {
+        if let Some(round) = self.round.take() {
+            round.abort();
+        }
+        for reprobe in self.reprobes.drain(..) {
+            reprobe.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn continuous_interval_is_not_zero() {
+        let command = |text: &str| serde_json::from_str::<Command>(text);
+        assert!(command(r#"{"command": "continuous", "interval_secs": 0}"#).is_err());
+        assert!(matches!(
+            command(r#"{"command": "continuous"}"#),
+            Ok(Command::Continuous { interval_secs }) if interval_secs == DEFAULT_INTERVAL_SECS
+        ));
+    }
+}
@@ -23,7 +23,84 @@ export interface ReverseDnsMessage {
   name: { Ok?: string; Err?: string };
 }
 
-export type TraceEvent = { Hop: TraceMessage } | { ReverseDns: ReverseDnsMessage } | 'Done';
+export interface TlsInfo {
+  version: string | null;
+  cipher_suite: string | null;
+  alpn: string | null;
+  sni: string | null;
+}
+
+export interface ClientHelloInfo {
+  ja4: string;
+  sni: string | null;
+  alpn: string[];
+}
+
+export interface ClientSynMessage {
+  ttl: number;
+  initial_ttl: number;
+  reverse_hops: number;
+  window: number;
+  mss: number | null;
+  wscale: number | null;
+  options: string;
+  df: boolean;
+  os: string | null;
+}
+
+export interface TraceStartMessage {
+  trace_id: string;
+  node_name: string;
+  node_addr: string;
+  time: number;
+  client: string;
+  ip_version: 4 | 6;
+  tls: TlsInfo | null;
+  client_hello: ClientHelloInfo | null;
+  syn: ClientSynMessage | null;
+  options: {
+    pmtu: boolean;
+    ecn: string | null;
+    dscp: number | null;
+    load: number | null;
+    classic: boolean;
+  };
+}
+
+// Times are in nanoseconds, rates in bytes per second
+export interface ConnectionStatsMessage {
+  rtt: number;
+  rtt_var: number;
+  min_rtt: number | null;
+  path_rtt: number | null;
+  rto: number;
+  retransmits: number;
+  lost: number;
+  unacked: number;
+  snd_cwnd: number;
+  snd_ssthresh: number;
+  snd_mss: number;
+  rcv_mss: number;
+  pmtu: number;
+  delivery_rate: number | null;
+  pacing_rate: number | null;
+  bytes_acked: number | null;
+  bytes_received: number | null;
+  bytes_retrans: number | null;
+}
+
+// Why the trace may not describe the path to the user
+export type AnnotationMessage =
+  | { kind: 'proxy'; header: string; value: string; clients: string[] }
+  | { kind: 'nat64'; prefix: string; ipv4: string };
+
+export type TraceEvent =
+  | { Start: TraceStartMessage }
+  | { Annotation: AnnotationMessage }
+  | { Hop: TraceMessage }
+  | { ReverseDns: ReverseDnsMessage }
+  | { ConnectionStats: ConnectionStatsMessage }
+  | 'Done';
 
 export interface Node {
   dns_name: string;
@@ -11,6 +11,7 @@ export class TraceConnection extends EventTarget {
   private traceData: { [ttl: number]: any } = {};
   private reverseDns: { [ttl: number]: any } = {};
   private _status: 'not-started' | 'in-progress' | 'done' = 'not-started';
+  private _traceId: string | null = null;
 
   constructor(
     private node: Node,
@@ -24,6 +25,10 @@ export class TraceConnection extends EventTarget {
     return this._status;
   }
 
+  get traceId() {
+    return this._traceId;
+  }
+
   connect() {
     this._status = 'in-progress';
     const protoKey = this.protocol === 'IPv4' ? 'ipv4' : 'ipv6';
@@ -32,8 +37,13 @@ export class TraceConnection extends EventTarget {
     this.eventSource.onmessage = (event) => {
       const evt = JSON.parse(event.data);
       if (evt == 'Done') {
+        // Otherwise EventSource reconnects when the server ends the stream
+        this.eventSource?.close();
+        this.eventSource = null;
         this._status = 'done';
         this.publish();
+      } else if (evt && 'Start' in evt) {
+        this._traceId = evt.Start.trace_id;
       } else if (evt && 'Hop' in evt) {
         const hop = evt.Hop;
         this.traceData[hop.ttl] = hop;
@@ -49,6 +59,10 @@ export class TraceConnection extends EventTarget {
       }
     };
     this.eventSource.onerror = (err) => {
+      // EventSource retries with Last-Event-ID, and the server replays what was missed
+      if (this.eventSource?.readyState === EventSource.CONNECTING) {
+        return;
+      }
       this.eventSource?.close();
       this.eventSource = null;
       this._status = 'done';